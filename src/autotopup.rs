@@ -0,0 +1,686 @@
+//! Automatic top-ups for orders that are running low on bandwidth.
+//!
+//! A rule tops an order up with a fixed package once its usage reaches a
+//! threshold, at most `max_topups` times and optionally within a USD budget.
+//! A background monitor polls the order status and re-checks an order as
+//! soon as a webhook event for it arrives. A top-up only creates a payment
+//! invoice, so each invoice is sent to the client as a log notification and
+//! recorded in the event inbox.
+//!
+//! After a top-up the rule stays disarmed until the invoice is seen to be
+//! paid: the order's bandwidth or remaining bytes grew, or usage dropped
+//! below the threshold again. An unpaid invoice never leads to a second one.
+
+use crate::catalog;
+use crate::inbox::{EventInbox, OrderEvent};
+use crate::models::OrderStatus;
+use crate::notify::Notifier;
+use crate::order_id::OrderId;
+use crate::util::unix_now;
+use crate::ProxyBaseClient;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_THRESHOLD_PCT: f64 = 85.0;
+pub const DEFAULT_MAX_TOPUPS: u32 = 3;
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Inbox event recorded for every invoice created by a rule.
+pub const EVENT_INVOICE: &str = "auto_topup_invoice";
+
+const TICK: Duration = Duration::from_secs(1);
+const LOGGER: &str = "proxybase.autotopup";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleSpec {
+    /// Usage percentage at which to top up.
+    pub threshold_pct: f64,
+    pub package_id: String,
+    pub pay_currency: Option<String>,
+    pub max_topups: u32,
+    /// Upper bound for the sum of all top-up prices.
+    pub budget_usd: Option<f64>,
+}
+
+/// Values used for rule fields the agent leaves out. `max_topups` and
+/// `budget_usd` are also ceilings the agent cannot raise.
+#[derive(Debug, Clone)]
+pub struct RuleDefaults {
+    pub threshold_pct: f64,
+    pub max_topups: u32,
+    pub budget_usd: Option<f64>,
+}
+
+impl Default for RuleDefaults {
+    fn default() -> Self {
+        Self {
+            threshold_pct: DEFAULT_THRESHOLD_PCT,
+            max_topups: DEFAULT_MAX_TOPUPS,
+            budget_usd: None,
+        }
+    }
+}
+
+impl RuleDefaults {
+    /// The agent's `max_topups` and `budget_usd`, within the operator's
+    /// limits. Omitted values take the limits themselves.
+    pub fn limits(&self, max_topups: Option<u64>, budget_usd: Option<f64>) -> Result<(u32, Option<f64>), String> {
+        let max_topups = match max_topups {
+            Some(n) if n > self.max_topups as u64 => {
+                return Err(format!("max_topups cannot exceed {}, the limit set by the server", self.max_topups))
+            }
+            Some(n) => n as u32,
+            None => self.max_topups,
+        };
+        let budget_usd = match (budget_usd, self.budget_usd) {
+            (Some(b), Some(limit)) if b > limit => {
+                return Err(format!("budget_usd cannot exceed ${:.2}, the limit set by the server", limit))
+            }
+            (Some(b), _) => Some(b),
+            (None, limit) => limit,
+        };
+        Ok((max_topups, budget_usd))
+    }
+}
+
+impl RuleSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold_pct <= 0.0 || self.threshold_pct > 100.0 {
+            return Err("threshold_pct must be greater than 0 and at most 100".to_string());
+        }
+        if self.max_topups == 0 {
+            return Err("max_topups must be at least 1".to_string());
+        }
+        if self.budget_usd.is_some_and(|b| b <= 0.0) {
+            return Err("budget_usd must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopupRecord {
+    /// Unix timestamp (seconds) of the top-up.
+    pub at: u64,
+    /// `set`, `poll` or `webhook:<event>`.
+    pub trigger: String,
+    pub usage_percentage: Option<f64>,
+    pub price_usd: Option<f64>,
+    pub invoice: Value,
+}
+
+/// Outcome of evaluating a rule against the order's current usage.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Wait,
+    /// The last top-up was paid or usage is below the threshold again; the
+    /// rule may fire next time.
+    Rearm,
+    Topup,
+    /// A top-up is due but a limit prevents it.
+    Blocked(String),
+}
+
+/// The order's byte allowance as last reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Allowance {
+    pub bandwidth_bytes: Option<u64>,
+    pub remaining_bytes: Option<u64>,
+}
+
+impl Allowance {
+    /// Whether bytes were added since `earlier`, i.e. a top-up was paid.
+    fn grew_since(&self, earlier: &Allowance) -> bool {
+        let grew = |now: Option<u64>, then: Option<u64>| now.zip(then).is_some_and(|(now, then)| now > then);
+        grew(self.bandwidth_bytes, earlier.bandwidth_bytes) || grew(self.remaining_bytes, earlier.remaining_bytes)
+    }
+}
+
+struct RuleState {
+    armed: bool,
+    /// Allowance when the last top-up was made, to tell when it is paid.
+    allowance_at_topup: Allowance,
+    topups: Vec<TopupRecord>,
+    blocked: Option<String>,
+    last_status: Option<String>,
+    last_usage: Option<f64>,
+    last_checked_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl RuleState {
+    /// Total price of the top-ups made so far.
+    fn spent_usd(&self) -> f64 {
+        self.topups.iter().filter_map(|t| t.price_usd).sum()
+    }
+}
+
+pub struct TopupRule {
+    pub order_id: OrderId,
+    api_key: String,
+    pub spec: RuleSpec,
+    created_at: u64,
+    state: Mutex<RuleState>,
+    /// Held while the rule is evaluated, so the monitor and a tool call
+    /// never top up the same order at once.
+    evaluating: tokio::sync::Mutex<()>,
+}
+
+impl TopupRule {
+    pub fn new(order_id: OrderId, api_key: &str, spec: RuleSpec) -> Self {
+        Self {
+            order_id,
+            api_key: api_key.to_string(),
+            spec,
+            created_at: unix_now(),
+            state: Mutex::new(RuleState {
+                armed: true,
+                allowance_at_topup: Allowance::default(),
+                topups: Vec::new(),
+                blocked: None,
+                last_status: None,
+                last_usage: None,
+                last_checked_at: None,
+                last_error: None,
+            }),
+            evaluating: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn is_due(&self, usage: Option<f64>, exhausted: bool) -> bool {
+        exhausted || usage.is_some_and(|u| u >= self.spec.threshold_pct)
+    }
+
+    /// Decide what to do at `usage` percent with the order's current
+    /// `allowance`; `price_usd` is the price of the next top-up, if known.
+    pub fn decide(&self, usage: Option<f64>, exhausted: bool, allowance: &Allowance, price_usd: Option<f64>) -> Decision {
+        let state = self.state.lock().unwrap();
+        // A top-up small next to the order may leave usage above the
+        // threshold even once paid
+        if !state.armed && allowance.grew_since(&state.allowance_at_topup) {
+            return Decision::Rearm;
+        }
+        if !self.is_due(usage, exhausted) {
+            return if state.armed { Decision::Wait } else { Decision::Rearm };
+        }
+        if !state.armed {
+            return Decision::Wait;
+        }
+        if state.topups.len() >= self.spec.max_topups as usize {
+            return Decision::Blocked(format!("max_topups ({}) reached", self.spec.max_topups));
+        }
+        if let Some(budget) = self.spec.budget_usd {
+            let spent = state.spent_usd();
+            let Some(price) = price_usd else {
+                return Decision::Blocked(format!(
+                    "price of package '{}' is unknown, so the budget cannot be enforced",
+                    self.spec.package_id
+                ));
+            };
+            if spent + price > budget + 1e-9 {
+                return Decision::Blocked(format!(
+                    "budget of ${:.2} would be exceeded (spent ${:.2}, next top-up ${:.2})",
+                    budget, spent, price
+                ));
+            }
+        }
+        Decision::Topup
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "order_id": self.order_id.as_str(),
+            "threshold_pct": self.spec.threshold_pct,
+            "package_id": self.spec.package_id,
+            "pay_currency": self.spec.pay_currency,
+            "max_topups": self.spec.max_topups,
+            "budget_usd": self.spec.budget_usd,
+            "created_at": self.created_at,
+            "armed": state.armed,
+            "blocked_reason": state.blocked,
+            "topups_done": state.topups.len(),
+            "spent_usd": state.spent_usd(),
+            "last_status": state.last_status,
+            "last_usage_percentage": state.last_usage,
+            "last_checked_at": state.last_checked_at,
+            "last_error": state.last_error,
+            "topups": state.topups
+        })
+    }
+}
+
+pub struct AutoTopup {
+    rules: Mutex<Vec<Arc<TopupRule>>>,
+    /// Deleted rules, whose top-ups still count when the order gets a new
+    /// rule.
+    removed: Mutex<Vec<Arc<TopupRule>>>,
+    inbox: Arc<EventInbox>,
+    notifier: Notifier,
+    monitor_started: AtomicBool,
+}
+
+impl AutoTopup {
+    pub fn new(inbox: Arc<EventInbox>, notifier: Notifier) -> Self {
+        Self {
+            rules: Mutex::new(Vec::new()),
+            removed: Mutex::new(Vec::new()),
+            inbox,
+            notifier,
+            monitor_started: AtomicBool::new(false),
+        }
+    }
+
+    /// Add a rule, replacing any existing rule for the same order. The
+    /// top-ups made under earlier rules for the order carry over, so
+    /// replacing a rule never resets `max_topups` or the budget.
+    pub fn set(&self, rule: TopupRule) -> Arc<TopupRule> {
+        let mut rules = self.rules.lock().unwrap();
+        let mut removed = self.removed.lock().unwrap();
+        let previous = rules
+            .iter()
+            .chain(removed.iter())
+            .find(|r| r.order_id == rule.order_id)
+            .cloned();
+        if let Some(previous) = previous {
+            let old = previous.state.lock().unwrap();
+            let mut state = rule.state.lock().unwrap();
+            state.topups = old.topups.clone();
+            state.armed = old.armed;
+            state.allowance_at_topup = old.allowance_at_topup;
+        }
+
+        let rule = Arc::new(rule);
+        rules.retain(|r| r.order_id != rule.order_id);
+        removed.retain(|r| r.order_id != rule.order_id);
+        rules.push(rule.clone());
+        rule
+    }
+
+    pub fn remove(&self, order_id: &OrderId) -> Option<Arc<TopupRule>> {
+        let mut rules = self.rules.lock().unwrap();
+        let pos = rules.iter().position(|r| &r.order_id == order_id)?;
+        let rule = rules.remove(pos);
+        self.removed.lock().unwrap().push(rule.clone());
+        Some(rule)
+    }
+
+    pub fn list(&self) -> Vec<Arc<TopupRule>> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    fn find(&self, order_id: &str) -> Option<Arc<TopupRule>> {
+        self.list().into_iter().find(|r| r.order_id.as_str() == order_id)
+    }
+
+    /// Check the order's usage and top it up if the rule says so.
+    pub async fn evaluate(&self, client: &ProxyBaseClient, rule: &TopupRule, trigger: &str) {
+        let _guard = rule.evaluating.lock().await;
+        let status = client
+            .check_order_status(&rule.api_key, &rule.order_id)
+            .await
+            .and_then(|v| OrderStatus::from_value(&v));
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                log::warn!("Auto top-up could not check order {}: {}", rule.order_id, e);
+                rule.state.lock().unwrap().last_error = Some(e);
+                return;
+            }
+        };
+
+        let usage = status.usage();
+        let allowance = Allowance {
+            bandwidth_bytes: status.bandwidth_bytes,
+            remaining_bytes: status.remaining_bytes,
+        };
+        {
+            let mut state = rule.state.lock().unwrap();
+            state.last_status = Some(status.status.clone());
+            state.last_usage = usage;
+            state.last_checked_at = Some(unix_now());
+        }
+
+        let price = if rule.is_due(usage, status.is_exhausted()) {
+            self.package_price(client, rule).await
+        } else {
+            None
+        };
+
+        match rule.decide(usage, status.is_exhausted(), &allowance, price) {
+            Decision::Wait => {}
+            Decision::Rearm => {
+                log::info!("Order {} got more bandwidth or is below its top-up threshold again", rule.order_id);
+                let mut state = rule.state.lock().unwrap();
+                state.armed = true;
+                state.blocked = None;
+            }
+            Decision::Blocked(reason) => {
+                let changed = {
+                    let mut state = rule.state.lock().unwrap();
+                    let changed = state.blocked.as_deref() != Some(reason.as_str());
+                    state.blocked = Some(reason.clone());
+                    changed
+                };
+                if changed {
+                    log::warn!("Auto top-up of {} blocked: {}", rule.order_id, reason);
+                    self.notifier.log("warning", LOGGER, json!({
+                        "message": format!("Auto top-up of order {} is blocked: {}", rule.order_id, reason),
+                        "order_id": rule.order_id.as_str(),
+                        "usage_percentage": usage
+                    }));
+                }
+            }
+            Decision::Topup => self.topup(client, rule, trigger, usage, allowance, price).await,
+        }
+    }
+
+    async fn topup(
+        &self,
+        client: &ProxyBaseClient,
+        rule: &TopupRule,
+        trigger: &str,
+        usage: Option<f64>,
+        allowance: Allowance,
+        price: Option<f64>,
+    ) {
+        let result = client
+            .topup_order(
+                &rule.api_key,
+                &rule.order_id,
+                &rule.spec.package_id,
+                rule.spec.pay_currency.as_deref(),
+            )
+            .await;
+        let invoice = match result {
+            Ok(invoice) => invoice,
+            Err(e) => {
+                log::warn!("Auto top-up of {} failed: {}", rule.order_id, e);
+                rule.state.lock().unwrap().last_error = Some(e);
+                return;
+            }
+        };
+
+        let record = TopupRecord {
+            at: unix_now(),
+            trigger: trigger.to_string(),
+            usage_percentage: usage,
+            price_usd: invoice.get("price_usd").and_then(|v| v.as_f64()).or(price),
+            invoice,
+        };
+        let n = {
+            let mut state = rule.state.lock().unwrap();
+            state.armed = false;
+            state.allowance_at_topup = allowance;
+            state.blocked = None;
+            state.last_error = None;
+            state.topups.push(record.clone());
+            state.topups.len()
+        };
+        log::info!("Auto top-up {} of order {} created an invoice", n, rule.order_id);
+
+        self.notifier.log("notice", LOGGER, json!({
+            "message": format!(
+                "Order {} reached {} usage; created top-up invoice {} of {} for package {}. Pay it to add the bandwidth.",
+                rule.order_id,
+                usage.map(|u| format!("{:.1}%", u)).unwrap_or_else(|| "its".to_string()),
+                n,
+                rule.spec.max_topups,
+                rule.spec.package_id
+            ),
+            "order_id": rule.order_id.as_str(),
+            "invoice": record.invoice
+        }));
+
+        let event = OrderEvent::from_payload(json!({
+            "event_id": format!("autotopup-{}-{}-{}", rule.order_id, rule.created_at, n),
+            "order_id": rule.order_id.as_str(),
+            "event": EVENT_INVOICE,
+            "package_id": rule.spec.package_id,
+            "trigger": record.trigger,
+            "usage_percentage": usage,
+            "invoice": record.invoice
+        }));
+        if let Ok(event) = event {
+            self.inbox.record(event);
+        }
+    }
+
+    async fn package_price(&self, client: &ProxyBaseClient, rule: &TopupRule) -> Option<f64> {
+        let catalog_val = client.list_packages(&rule.api_key, false).await.ok()?;
+        catalog::find_package(&catalog_val, &rule.spec.package_id).and_then(catalog::package_price)
+    }
+
+    /// Start the background monitor once. Every rule is checked each
+    /// `interval`, and a rule's order is checked right away when a webhook
+    /// event for it lands in the inbox.
+    pub fn ensure_monitor(self: &Arc<Self>, client: ProxyBaseClient, interval: Duration) {
+        if self.monitor_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut last_poll = Instant::now();
+            let mut cursor = manager.upgrade().map(|m| m.inbox.last_seq()).unwrap_or(0);
+            loop {
+                tokio::time::sleep(TICK).await;
+                let Some(manager) = manager.upgrade() else {
+                    return;
+                };
+
+                let events = manager.inbox.query(None, Some(cursor), usize::MAX);
+                if let Some(last) = events.last() {
+                    cursor = last.seq;
+                }
+                for event in events.iter().filter(|e| e.event != EVENT_INVOICE) {
+                    let rule = event.order_id.as_deref().and_then(|id| manager.find(id));
+                    if let Some(rule) = rule {
+                        manager.evaluate(&client, &rule, &format!("webhook:{}", event.event)).await;
+                    }
+                }
+
+                if last_poll.elapsed() >= interval {
+                    last_poll = Instant::now();
+                    for rule in manager.list() {
+                        manager.evaluate(&client, &rule, "poll").await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http1::test_support::spawn_backend;
+
+    fn spec() -> RuleSpec {
+        RuleSpec {
+            threshold_pct: 85.0,
+            package_id: "us_residential_1gb".to_string(),
+            pay_currency: Some("usdttrc20".to_string()),
+            max_topups: 2,
+            budget_usd: None,
+        }
+    }
+
+    fn rule(spec: RuleSpec) -> TopupRule {
+        TopupRule::new(OrderId::parse("kQx7p3Wn").unwrap(), "pk_test", spec)
+    }
+
+    impl TopupRule {
+        fn decide_at(&self, usage: Option<f64>, exhausted: bool, price_usd: Option<f64>) -> Decision {
+            self.decide(usage, exhausted, &Allowance::default(), price_usd)
+        }
+    }
+
+    fn fire(rule: &TopupRule, price: f64) {
+        let mut state = rule.state.lock().unwrap();
+        state.armed = false;
+        state.topups.push(TopupRecord {
+            at: 0,
+            trigger: "poll".to_string(),
+            usage_percentage: Some(90.0),
+            price_usd: Some(price),
+            invoice: json!({}),
+        });
+    }
+
+    #[test]
+    fn test_spec_validation() {
+        assert!(spec().validate().is_ok());
+        assert!(RuleSpec { threshold_pct: 0.0, ..spec() }.validate().is_err());
+        assert!(RuleSpec { threshold_pct: 101.0, ..spec() }.validate().is_err());
+        assert!(RuleSpec { max_topups: 0, ..spec() }.validate().is_err());
+        assert!(RuleSpec { budget_usd: Some(-1.0), ..spec() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_fires_once_until_usage_drops() {
+        let rule = rule(spec());
+        assert_eq!(rule.decide_at(Some(50.0), false, None), Decision::Wait);
+        assert_eq!(rule.decide_at(Some(85.0), false, None), Decision::Topup);
+        assert_eq!(rule.decide_at(None, true, None), Decision::Topup);
+
+        fire(&rule, 10.0);
+        // Invoice not paid yet: usage is still high
+        assert_eq!(rule.decide_at(Some(95.0), false, None), Decision::Wait);
+        assert_eq!(rule.decide_at(Some(40.0), false, None), Decision::Rearm);
+        rule.state.lock().unwrap().armed = true;
+        assert_eq!(rule.decide_at(Some(90.0), false, None), Decision::Topup);
+
+        fire(&rule, 10.0);
+        rule.state.lock().unwrap().armed = true;
+        let Decision::Blocked(reason) = rule.decide_at(Some(90.0), false, None) else {
+            panic!("expected max_topups to block");
+        };
+        assert!(reason.contains("max_topups"), "{}", reason);
+    }
+
+    #[test]
+    fn test_paid_small_topup_rearms_above_threshold() {
+        let rule = rule(spec());
+        let before = Allowance { bandwidth_bytes: Some(10_000), remaining_bytes: Some(1_000) };
+        fire(&rule, 1.0);
+        rule.state.lock().unwrap().allowance_at_topup = before;
+
+        // Unpaid: nothing changes, and usage stays over the threshold
+        assert_eq!(rule.decide(Some(90.0), false, &before, None), Decision::Wait);
+        let used_more = Allowance { remaining_bytes: Some(800), ..before };
+        assert_eq!(rule.decide(Some(92.0), false, &used_more, None), Decision::Wait);
+
+        // Paid: 100 bytes more on 10,000 leaves usage at 91%
+        let paid = Allowance { bandwidth_bytes: Some(10_100), remaining_bytes: Some(900) };
+        assert_eq!(rule.decide(Some(91.0), false, &paid, None), Decision::Rearm);
+        // Only the remaining bytes are reported
+        let paid = Allowance { bandwidth_bytes: None, remaining_bytes: Some(1_050) };
+        assert_eq!(rule.decide(Some(89.0), false, &paid, None), Decision::Rearm);
+    }
+
+    #[test]
+    fn test_limits_cap_agent_values() {
+        let defaults = RuleDefaults { budget_usd: Some(50.0), ..RuleDefaults::default() };
+        assert_eq!(defaults.limits(None, None).unwrap(), (DEFAULT_MAX_TOPUPS, Some(50.0)));
+        assert_eq!(defaults.limits(Some(1), Some(20.0)).unwrap(), (1, Some(20.0)));
+        assert!(defaults.limits(None, Some(1e9)).unwrap_err().contains("$50.00"));
+        assert!(defaults.limits(Some(4), None).unwrap_err().contains("max_topups cannot exceed 3"));
+        assert_eq!(RuleDefaults::default().limits(None, Some(1e9)).unwrap().1, Some(1e9));
+    }
+
+    #[test]
+    fn test_budget() {
+        let rule = rule(RuleSpec { budget_usd: Some(25.0), ..spec() });
+        assert!(matches!(rule.decide_at(Some(90.0), false, None), Decision::Blocked(r) if r.contains("unknown")));
+        assert_eq!(rule.decide_at(Some(90.0), false, Some(10.0)), Decision::Topup);
+
+        fire(&rule, 20.0);
+        rule.state.lock().unwrap().armed = true;
+        assert_eq!(rule.state.lock().unwrap().spent_usd(), 20.0);
+        assert!(matches!(rule.decide_at(Some(90.0), false, Some(10.0)), Decision::Blocked(r) if r.contains("budget")));
+        assert_eq!(rule.decide_at(Some(90.0), false, Some(5.0)), Decision::Topup);
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_creates_and_surfaces_invoice() {
+        let topups = Arc::new(Mutex::new(Vec::new()));
+        let seen = topups.clone();
+        let base = spawn_backend(move |method, target, body| match (method, target) {
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "status": "proxy_active",
+                "usage_percentage": 91.5
+            })),
+            ("POST", "/v1/orders/kQx7p3Wn/topup") => {
+                seen.lock().unwrap().push(body);
+                (200, json!({"order_id": "kQx7p3Wn", "payment_id": "p1", "price_usd": 10.0}))
+            }
+            _ => (404, json!({"error": "not found"})),
+        })
+        .await;
+
+        let client = ProxyBaseClient::new(&base);
+        let inbox = Arc::new(EventInbox::in_memory());
+        let manager = AutoTopup::new(inbox.clone(), Notifier::default());
+        let rule = manager.set(rule(RuleSpec { budget_usd: Some(100.0), ..spec() }));
+
+        // The package is not in the (missing) catalog, but the invoice
+        // reports the price
+        manager.evaluate(&client, &rule, "set").await;
+        assert!(matches!(rule.snapshot()["blocked_reason"].as_str(), Some(r) if r.contains("unknown")));
+        assert!(topups.lock().unwrap().is_empty());
+
+        let rule = manager.set(self::rule(spec()));
+        manager.evaluate(&client, &rule, "set").await;
+        manager.evaluate(&client, &rule, "poll").await;
+
+        let requests = topups.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1, "an unpaid invoice must not be repeated");
+        assert_eq!(requests[0]["package_id"], "us_residential_1gb");
+        assert_eq!(requests[0]["pay_currency"], "usdttrc20");
+
+        let snapshot = rule.snapshot();
+        assert_eq!(snapshot["topups_done"], 1);
+        assert_eq!(snapshot["spent_usd"], 10.0);
+        assert_eq!(snapshot["armed"], false);
+
+        let events = inbox.query(Some("kQx7p3Wn"), None, 10);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, EVENT_INVOICE);
+        assert_eq!(events[0].payload["invoice"]["payment_id"], "p1");
+    }
+
+    #[tokio::test]
+    async fn test_replacing_a_rule_keeps_its_spend() {
+        let topups = Arc::new(Mutex::new(0));
+        let count = topups.clone();
+        let base = spawn_backend(move |method, target, _| match (method, target) {
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({"order_id": "kQx7p3Wn", "status": "proxy_active", "usage_percentage": 95.0})),
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb", "price_usd": 10.0}]})),
+            ("POST", "/v1/orders/kQx7p3Wn/topup") => {
+                *count.lock().unwrap() += 1;
+                (200, json!({"order_id": "kQx7p3Wn", "price_usd": 10.0}))
+            }
+            _ => (404, json!({})),
+        })
+        .await;
+        let client = ProxyBaseClient::new(&base);
+        let manager = AutoTopup::new(Arc::new(EventInbox::in_memory()), Notifier::default());
+        let budget = || rule(RuleSpec { budget_usd: Some(15.0), max_topups: 3, ..spec() });
+
+        let first = manager.set(budget());
+        manager.evaluate(&client, &first, "set").await;
+        assert_eq!(*topups.lock().unwrap(), 1);
+
+        // A new rule, even after deleting the old one, starts from what was spent
+        manager.remove(&first.order_id);
+        let second = manager.set(budget());
+        second.state.lock().unwrap().armed = true;
+        manager.evaluate(&client, &second, "set").await;
+        assert_eq!(*topups.lock().unwrap(), 1);
+        assert_eq!(second.snapshot()["spent_usd"], 10.0);
+        assert!(second.snapshot()["blocked_reason"].as_str().unwrap().contains("budget"));
+    }
+}
@@ -0,0 +1,198 @@
+//! TTL cache for the package catalog and currency list.
+//!
+//! Entries live in memory and, when a cache directory is configured, are
+//! mirrored to disk so a restarted server can revalidate with `If-None-Match`
+//! instead of downloading the catalog again.
+
+use crate::util::unix_now;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub body: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Unix timestamp (seconds) of the last successful fetch or revalidation.
+    pub fetched_at: u64,
+}
+
+impl CacheEntry {
+    pub fn new(body: Value, etag: Option<String>) -> Self {
+        Self {
+            body,
+            etag,
+            fetched_at: unix_now(),
+        }
+    }
+}
+
+pub struct CatalogCache {
+    ttl: Duration,
+    dir: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl CatalogCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            dir: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Persist entries as JSON files under `dir` in addition to memory.
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Look up an entry, loading it from disk if it is not in memory yet.
+    /// Stale entries are returned too; use [`CatalogCache::is_fresh`] to decide
+    /// whether they can be served without revalidation.
+    pub fn get(&self, key: &str) -> Option<CacheEntry> {
+        if let Some(entry) = self.entries.lock().unwrap().get(key) {
+            return Some(entry.clone());
+        }
+
+        let entry = self.load(key)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), entry.clone());
+        Some(entry)
+    }
+
+    pub fn is_fresh(&self, entry: &CacheEntry) -> bool {
+        unix_now().saturating_sub(entry.fetched_at) < self.ttl.as_secs()
+    }
+
+    pub fn put(&self, key: &str, entry: CacheEntry) {
+        self.store(key, &entry);
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+    }
+
+    /// Mark an entry as revalidated (the backend answered 304 Not Modified).
+    pub fn touch(&self, key: &str) {
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            let Some(entry) = entries.get_mut(key) else {
+                return;
+            };
+            entry.fetched_at = unix_now();
+            entry.clone()
+        };
+        self.store(key, &entry);
+    }
+
+    fn path_for(&self, key: &str) -> Option<PathBuf> {
+        let file = key.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        self.dir.as_ref().map(|d| d.join(format!("{}.json", file)))
+    }
+
+    fn load(&self, key: &str) -> Option<CacheEntry> {
+        let path = self.path_for(key)?;
+        let data = std::fs::read(&path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Ignoring corrupt cache file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn store(&self, key: &str, entry: &CacheEntry) {
+        let Some(path) = self.path_for(key) else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| std::fs::write(&path, serde_json::to_vec(entry).unwrap_or_default()));
+        if let Err(e) = result {
+            log::warn!("Failed to write cache file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Cache key for a backend endpoint as seen by a given API key. The key
+/// itself is only stored as a fingerprint so it never ends up in file names;
+/// a cryptographic hash keeps one account from being made to share
+/// another's entries.
+pub fn cache_key(endpoint: &str, api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    let fingerprint: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", endpoint, fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fresh_and_stale_entries() {
+        let cache = CatalogCache::new(Duration::from_secs(60));
+        cache.put("packages-1", CacheEntry::new(json!({"packages": []}), None));
+        let entry = cache.get("packages-1").unwrap();
+        assert!(cache.is_fresh(&entry));
+
+        let stale = CacheEntry {
+            fetched_at: unix_now() - 120,
+            ..entry
+        };
+        assert!(!cache.is_fresh(&stale));
+        assert!(cache.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_touch_revalidates_entry() {
+        let cache = CatalogCache::new(Duration::from_secs(60));
+        cache.put(
+            "currencies-1",
+            CacheEntry {
+                body: json!({"currencies": ["btc"]}),
+                etag: Some("\"v1\"".to_string()),
+                fetched_at: 0,
+            },
+        );
+        assert!(!cache.is_fresh(&cache.get("currencies-1").unwrap()));
+        cache.touch("currencies-1");
+        assert!(cache.is_fresh(&cache.get("currencies-1").unwrap()));
+    }
+
+    #[test]
+    fn test_disk_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("proxybase-cache-test-{}", std::process::id()));
+        let key = cache_key("packages", "pk_test");
+
+        CatalogCache::new(DEFAULT_TTL)
+            .with_dir(&dir)
+            .put(&key, CacheEntry::new(json!({"packages": [1]}), Some("\"abc\"".to_string())));
+
+        let reloaded = CatalogCache::new(DEFAULT_TTL).with_dir(&dir).get(&key).unwrap();
+        assert_eq!(reloaded.body, json!({"packages": [1]}));
+        assert_eq!(reloaded.etag.as_deref(), Some("\"abc\""));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cache_key_hides_api_key() {
+        let key = cache_key("packages", "pk_secret");
+        assert!(key.starts_with("packages-"));
+        assert_eq!(key.len(), "packages-".len() + 32);
+        assert!(!key.contains("pk_secret"));
+        assert_ne!(key, cache_key("packages", "pk_other"));
+    }
+}
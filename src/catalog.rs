@@ -0,0 +1,222 @@
+//! Helpers for working with the package catalog returned by `list_packages`.
+//!
+//! The backend response is kept as raw JSON elsewhere, so these functions are
+//! deliberately lenient about its shape: both `{"packages": [...]}` and a bare
+//! array are accepted, and attribute names fall back to common aliases.
+
+use serde_json::Value;
+
+/// Maximum number of "did you mean" suggestions in an error message.
+const MAX_SUGGESTIONS: usize = 3;
+
+pub fn packages(catalog: &Value) -> Option<&Vec<Value>> {
+    catalog
+        .get("packages")
+        .and_then(|v| v.as_array())
+        .or_else(|| catalog.as_array())
+}
+
+pub fn package_id(pkg: &Value) -> Option<&str> {
+    first_str(pkg, &["id", "package_id"])
+}
+
+pub fn package_country(pkg: &Value) -> Option<&str> {
+    first_str(pkg, &["country", "country_code"])
+}
+
+pub fn package_type(pkg: &Value) -> Option<&str> {
+    first_str(pkg, &["proxy_type", "type"])
+}
+
+pub fn package_price(pkg: &Value) -> Option<f64> {
+    ["price_usd", "price"]
+        .iter()
+        .find_map(|k| pkg.get(*k).and_then(|v| v.as_f64()))
+}
+
+/// Bandwidth included in a package, from a byte count or a size in GB.
+pub fn package_bytes(pkg: &Value) -> Option<u64> {
+    const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+    ["bandwidth_bytes", "bytes"]
+        .iter()
+        .find_map(|k| pkg.get(*k).and_then(|v| v.as_u64()))
+        .or_else(|| {
+            ["bandwidth_gb", "gb"]
+                .iter()
+                .find_map(|k| pkg.get(*k).and_then(|v| v.as_f64()))
+                .map(|gb| (gb * GIB).round() as u64)
+        })
+}
+
+pub fn find_package<'a>(catalog: &'a Value, id: &str) -> Option<&'a Value> {
+    packages(catalog)?
+        .iter()
+        .find(|p| package_id(p).is_some_and(|pid| pid.eq_ignore_ascii_case(id)))
+}
+
+/// Validate `id` against the catalog. Returns `Ok(None)` when the catalog
+/// does not contain a recognisable package list, so an unexpected backend
+/// response never blocks an order.
+pub fn check_package<'a>(catalog: &'a Value, id: &str) -> Result<Option<&'a Value>, String> {
+    if packages(catalog).is_none() {
+        return Ok(None);
+    }
+    if let Some(pkg) = find_package(catalog, id) {
+        return Ok(Some(pkg));
+    }
+
+    let suggestions = suggest(catalog, id);
+    if suggestions.is_empty() {
+        Err(format!(
+            "Unknown package_id: '{}'. Use list_packages to see available packages.",
+            id
+        ))
+    } else {
+        Err(format!(
+            "Unknown package_id: '{}'. Did you mean: {}?",
+            id,
+            suggestions.join(", ")
+        ))
+    }
+}
+
+/// Package ids that look like a typo or abbreviation of `id`, best first.
+pub fn suggest(catalog: &Value, id: &str) -> Vec<String> {
+    let needle = id.to_lowercase();
+    let mut scored: Vec<(bool, usize, &str)> = packages(catalog)
+        .into_iter()
+        .flatten()
+        .filter_map(package_id)
+        .filter_map(|candidate| {
+            let lower = candidate.to_lowercase();
+            let prefix = tokens_are_prefixes(&needle, &lower);
+            let distance = levenshtein(&needle, &lower);
+            let close = distance <= (needle.len().max(lower.len()) / 3).max(2);
+            (prefix || close).then_some((!prefix, distance, candidate))
+        })
+        .collect();
+
+    scored.sort();
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, c)| c.to_string())
+        .collect()
+}
+
+/// Human-readable warnings when a top-up package targets a different country
+/// or proxy type than the package the order was created with.
+pub fn topup_mismatch(original: &Value, topup: &Value) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let pairs = [
+        ("country", package_country(original), package_country(topup)),
+        ("proxy type", package_type(original), package_type(topup)),
+    ];
+
+    for (what, orig, new) in pairs {
+        if let (Some(orig), Some(new)) = (orig, new) {
+            if !orig.eq_ignore_ascii_case(new) {
+                warnings.push(format!(
+                    "Top-up package {} is '{}' but the order was created with '{}'. \
+                     The bandwidth is added to the existing proxy, which keeps its original {}.",
+                    what, new, orig, what
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+fn first_str<'a>(v: &'a Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().find_map(|k| v.get(*k).and_then(|x| x.as_str()))
+}
+
+/// True when every `_`-separated token of `needle` is a prefix of the token
+/// at the same position in `candidate` (e.g. `us_resi_1gb` → `us_residential_1gb`).
+fn tokens_are_prefixes(needle: &str, candidate: &str) -> bool {
+    let a: Vec<&str> = needle.split('_').collect();
+    let b: Vec<&str> = candidate.split('_').collect();
+    a.len() == b.len() && a.iter().zip(&b).all(|(x, y)| !x.is_empty() && y.starts_with(x))
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn catalog() -> Value {
+        json!({
+            "packages": [
+                {"id": "us_residential_1gb", "country": "US", "proxy_type": "residential"},
+                {"id": "us_residential_5gb", "country": "US", "proxy_type": "residential"},
+                {"id": "de_datacenter_1gb", "country": "DE", "proxy_type": "datacenter"}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_check_package_found() {
+        let cat = catalog();
+        let pkg = check_package(&cat, "US_Residential_1GB").unwrap().unwrap();
+        assert_eq!(package_id(pkg), Some("us_residential_1gb"));
+    }
+
+    #[test]
+    fn test_suggests_abbreviation() {
+        let err = check_package(&catalog(), "us_resi_1gb").unwrap_err();
+        assert!(err.contains("Did you mean: us_residential_1gb"), "{}", err);
+    }
+
+    #[test]
+    fn test_suggests_typo() {
+        assert_eq!(suggest(&catalog(), "de_datacentre_1gb"), vec!["de_datacenter_1gb"]);
+    }
+
+    #[test]
+    fn test_unknown_without_suggestions() {
+        let err = check_package(&catalog(), "jp_mobile_100gb").unwrap_err();
+        assert!(err.contains("Use list_packages"));
+    }
+
+    #[test]
+    fn test_package_size_and_price() {
+        let pkg = json!({"id": "us_residential_1gb", "bandwidth_gb": 1, "price_usd": 10.0});
+        assert_eq!(package_bytes(&pkg), Some(1073741824));
+        assert_eq!(package_price(&pkg), Some(10.0));
+
+        let pkg = json!({"id": "x", "bandwidth_bytes": 500, "price": 2});
+        assert_eq!(package_bytes(&pkg), Some(500));
+        assert_eq!(package_price(&pkg), Some(2.0));
+        assert_eq!(package_bytes(&json!({"id": "x"})), None);
+    }
+
+    #[test]
+    fn test_unrecognised_catalog_is_not_an_error() {
+        assert!(check_package(&json!({"unexpected": true}), "anything").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_topup_mismatch() {
+        let cat = catalog();
+        let us = find_package(&cat, "us_residential_1gb").unwrap();
+        let us5 = find_package(&cat, "us_residential_5gb").unwrap();
+        let de = find_package(&cat, "de_datacenter_1gb").unwrap();
+        assert!(topup_mismatch(us, us5).is_empty());
+        assert_eq!(topup_mismatch(us, de).len(), 2);
+    }
+}
@@ -0,0 +1,452 @@
+//! Command-line arguments.
+//!
+//! With no arguments the binary runs the MCP server on stdio, which is what
+//! MCP clients expect. Subcommands expose features that are useful outside
+//! an agent session.
+
+use crate::config::Overrides;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage:
+  proxybase-mcp                      Run the MCP server on stdio (default)
+  proxybase-mcp serve                Same as above
+  proxybase-mcp gateway <order_id> [--listen ADDR] [--api-key KEY]
+                                     Run a local unauthenticated SOCKS5/HTTP
+                                     proxy chained through the order's proxy
+  proxybase-mcp register             Register a new agent and print its API key
+  proxybase-mcp packages [--refresh] List bandwidth packages
+  proxybase-mcp orders list          Orders recorded locally, with live status
+  proxybase-mcp orders status <order_id>
+                                     Status, usage and proxy credentials
+  proxybase-mcp orders rotate <order_id> [--verify]
+                                     Rotate the proxy to a new exit IP
+  proxybase-mcp orders topup <order_id> <package_id> [--pay-currency CUR]
+                                     Create a top-up invoice
+  proxybase-mcp install --client NAME [--config-path PATH] [--api-key KEY]
+                                     Add this server to an MCP client's config
+                                     (claude-desktop, cursor)
+  proxybase-mcp uninstall --client NAME [--config-path PATH]
+                                     Remove it again
+  proxybase-mcp doctor [--proxies] [--max-clock-skew SECS]
+                                     Check connectivity, TLS, API key, clock
+                                     and file permissions
+  proxybase-mcp config show          Print the effective configuration
+  proxybase-mcp help                 Show this message
+
+Global options (before or after the command):
+  --config PATH    Config file (default: $PROXYBASE_CONFIG, else
+                   ~/.config/proxybase-mcp/config.toml if present)
+  --profile NAME   Profile from the config file (default: $PROXYBASE_PROFILE)
+  --api-url URL    Backend URL (default: $PROXYBASE_API_URL)
+  --read-only      Only expose the package, currency and order lookups
+
+Options:
+  --listen ADDR    Loopback address for the gateway (default: 127.0.0.1:1080)
+  --api-key KEY    API key (default: $PROXYBASE_API_KEY)
+  --json           Print the raw JSON result instead of a table
+";
+
+pub const DEFAULT_GATEWAY_LISTEN: &str = "127.0.0.1:1080";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    Gateway {
+        order_id: String,
+        listen: SocketAddr,
+        api_key: Option<String>,
+    },
+    Register {
+        json: bool,
+    },
+    Packages {
+        api_key: Option<String>,
+        refresh: bool,
+        json: bool,
+    },
+    Orders {
+        action: OrdersAction,
+        api_key: Option<String>,
+        json: bool,
+    },
+    Doctor {
+        api_key: Option<String>,
+        test_proxies: bool,
+        max_clock_skew: Option<u64>,
+        json: bool,
+    },
+    ConfigShow {
+        json: bool,
+    },
+    Install {
+        client: String,
+        config_path: Option<PathBuf>,
+        api_key: Option<String>,
+    },
+    Uninstall {
+        client: String,
+        config_path: Option<PathBuf>,
+    },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OrdersAction {
+    List,
+    Status {
+        order_id: String,
+    },
+    Rotate {
+        order_id: String,
+        verify: bool,
+    },
+    Topup {
+        order_id: String,
+        package_id: String,
+        pay_currency: Option<String>,
+    },
+}
+
+impl Command {
+    /// Parse arguments, excluding the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let Some(command) = args.next() else {
+            return Ok(Command::Serve);
+        };
+
+        match command.as_str() {
+            "serve" => no_more_args(args, Command::Serve),
+            "help" | "--help" | "-h" => Ok(Command::Help),
+            "gateway" => {
+                let mut order_id = None;
+                let mut listen = DEFAULT_GATEWAY_LISTEN.parse().unwrap();
+                let mut api_key = None;
+
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--listen" => {
+                            let value = option_value(&mut args, &arg)?;
+                            listen = value
+                                .parse()
+                                .map_err(|e| format!("Invalid --listen '{}': {}", value, e))?;
+                        }
+                        "--api-key" => api_key = Some(option_value(&mut args, &arg)?),
+                        flag if flag.starts_with("--") => {
+                            return Err(format!("Unknown option: {}", flag))
+                        }
+                        _ if order_id.is_none() => order_id = Some(arg),
+                        _ => return Err(format!("Unexpected argument: {}", arg)),
+                    }
+                }
+
+                Ok(Command::Gateway {
+                    order_id: order_id.ok_or("gateway: missing <order_id>")?,
+                    listen,
+                    api_key,
+                })
+            }
+            "register" => {
+                let mut parsed = Parsed::parse(args, &[])?;
+                parsed.expect_positionals(0)?;
+                Ok(Command::Register { json: parsed.json })
+            }
+            "packages" => {
+                let mut parsed = Parsed::parse(args, &["--refresh"])?;
+                parsed.expect_positionals(0)?;
+                Ok(Command::Packages {
+                    refresh: parsed.switch("--refresh"),
+                    api_key: parsed.api_key,
+                    json: parsed.json,
+                })
+            }
+            "orders" => {
+                let action = args.next().ok_or("orders: missing action (list, status, rotate, topup)")?;
+                let mut parsed = match action.as_str() {
+                    "rotate" => Parsed::parse(args, &["--verify"])?,
+                    "topup" => Parsed::parse(args, &["--pay-currency"])?,
+                    _ => Parsed::parse(args, &[])?,
+                };
+                let action = match action.as_str() {
+                    "list" => {
+                        parsed.expect_positionals(0)?;
+                        OrdersAction::List
+                    }
+                    "status" => {
+                        let [order_id] = parsed.expect_positionals(1)?.try_into().unwrap();
+                        OrdersAction::Status { order_id }
+                    }
+                    "rotate" => {
+                        let [order_id] = parsed.expect_positionals(1)?.try_into().unwrap();
+                        OrdersAction::Rotate { order_id, verify: parsed.switch("--verify") }
+                    }
+                    "topup" => {
+                        let [order_id, package_id] = parsed.expect_positionals(2)?.try_into().unwrap();
+                        OrdersAction::Topup {
+                            order_id,
+                            package_id,
+                            pay_currency: parsed.options.remove("--pay-currency"),
+                        }
+                    }
+                    other => return Err(format!("Unknown orders action: {}", other)),
+                };
+                Ok(Command::Orders {
+                    action,
+                    api_key: parsed.api_key,
+                    json: parsed.json,
+                })
+            }
+            "doctor" => {
+                let mut parsed = Parsed::parse(args, &["--proxies", "--max-clock-skew"])?;
+                parsed.expect_positionals(0)?;
+                let max_clock_skew = match parsed.options.remove("--max-clock-skew") {
+                    Some(value) => Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid --max-clock-skew '{}': expected seconds", value))?,
+                    ),
+                    None => None,
+                };
+                Ok(Command::Doctor {
+                    test_proxies: parsed.switch("--proxies"),
+                    api_key: parsed.api_key,
+                    max_clock_skew,
+                    json: parsed.json,
+                })
+            }
+            "config" => match args.next().as_deref() {
+                Some("show") => {
+                    let mut parsed = Parsed::parse(args, &[])?;
+                    parsed.expect_positionals(0)?;
+                    Ok(Command::ConfigShow { json: parsed.json })
+                }
+                Some(other) => Err(format!("Unknown config action: {}", other)),
+                None => Err("config: missing action (show)".to_string()),
+            },
+            "install" | "uninstall" => {
+                let mut parsed = Parsed::parse(args, &["--client", "--config-path"])?;
+                parsed.expect_positionals(0)?;
+                let client = parsed
+                    .options
+                    .remove("--client")
+                    .ok_or_else(|| format!("{}: missing --client (claude-desktop, cursor)", command))?;
+                let config_path = parsed.options.remove("--config-path").map(PathBuf::from);
+                Ok(match command.as_str() {
+                    "install" => Command::Install { client, config_path, api_key: parsed.api_key },
+                    _ => Command::Uninstall { client, config_path },
+                })
+            }
+            other => Err(format!("Unknown command: {}", other)),
+        }
+    }
+}
+
+/// Arguments of the API subcommands: positionals plus `--api-key`, `--json`
+/// and the command's own options. Options in `extra` that are switches take
+/// no value.
+struct Parsed {
+    positionals: Vec<String>,
+    api_key: Option<String>,
+    json: bool,
+    options: HashMap<String, String>,
+}
+
+const SWITCHES: &[&str] = &["--refresh", "--verify", "--proxies"];
+
+impl Parsed {
+    fn parse(mut args: impl Iterator<Item = String>, extra: &[&str]) -> Result<Self, String> {
+        let mut parsed = Parsed {
+            positionals: Vec::new(),
+            api_key: None,
+            json: false,
+            options: HashMap::new(),
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--api-key" => parsed.api_key = Some(option_value(&mut args, &arg)?),
+                "--json" => parsed.json = true,
+                flag if extra.contains(&flag) && SWITCHES.contains(&flag) => {
+                    parsed.options.insert(arg, String::new());
+                }
+                flag if extra.contains(&flag) => {
+                    let value = option_value(&mut args, &arg)?;
+                    parsed.options.insert(arg, value);
+                }
+                flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+                _ => parsed.positionals.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    /// Take exactly `n` positional arguments.
+    fn expect_positionals(&mut self, n: usize) -> Result<Vec<String>, String> {
+        match self.positionals.len() {
+            len if len < n => Err(format!("Missing argument: expected {} but got {}", n, len)),
+            len if len > n => Err(format!("Unexpected argument: {}", self.positionals[n])),
+            _ => Ok(std::mem::take(&mut self.positionals)),
+        }
+    }
+}
+
+/// Take the global options out of `args`, wherever they appear, and return
+/// them with the remaining arguments.
+pub fn split_global<I: IntoIterator<Item = String>>(args: I) -> Result<(Overrides, Vec<String>), String> {
+    let mut overrides = Overrides::default();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => overrides.config_path = Some(option_value(&mut args, &arg)?.into()),
+            "--profile" => overrides.profile = Some(option_value(&mut args, &arg)?),
+            "--api-url" => overrides.api_url = Some(option_value(&mut args, &arg)?),
+            "--read-only" => overrides.read_only = true,
+            _ => rest.push(arg),
+        }
+    }
+    Ok((overrides, rest))
+}
+
+fn option_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+fn no_more_args(mut args: impl Iterator<Item = String>, command: Command) -> Result<Command, String> {
+    match args.next() {
+        Some(arg) => Err(format!("Unexpected argument: {}", arg)),
+        None => Ok(command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_default_is_serve() {
+        assert_eq!(parse(&[]).unwrap(), Command::Serve);
+        assert_eq!(parse(&["serve"]).unwrap(), Command::Serve);
+        assert!(parse(&["serve", "extra"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_gateway() {
+        assert_eq!(
+            parse(&["gateway", "kQx7p3Wn"]).unwrap(),
+            Command::Gateway {
+                order_id: "kQx7p3Wn".to_string(),
+                listen: DEFAULT_GATEWAY_LISTEN.parse().unwrap(),
+                api_key: None,
+            }
+        );
+        assert_eq!(
+            parse(&["gateway", "--listen", "127.0.0.1:9050", "kQx7p3Wn", "--api-key", "pk_x"]).unwrap(),
+            Command::Gateway {
+                order_id: "kQx7p3Wn".to_string(),
+                listen: "127.0.0.1:9050".parse().unwrap(),
+                api_key: Some("pk_x".to_string()),
+            }
+        );
+
+        assert!(parse(&["gateway"]).unwrap_err().contains("order_id"));
+        assert!(parse(&["gateway", "a", "--listen"]).unwrap_err().contains("Missing value"));
+        assert!(parse(&["gateway", "a", "--listen", "nope"]).is_err());
+        assert!(parse(&["gateway", "a", "--bogus"]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_api_commands() {
+        assert_eq!(parse(&["register", "--json"]).unwrap(), Command::Register { json: true });
+        assert_eq!(
+            parse(&["packages", "--refresh", "--api-key", "pk_x"]).unwrap(),
+            Command::Packages { api_key: Some("pk_x".to_string()), refresh: true, json: false }
+        );
+        assert_eq!(
+            parse(&["orders", "list"]).unwrap(),
+            Command::Orders { action: OrdersAction::List, api_key: None, json: false }
+        );
+        assert_eq!(
+            parse(&["orders", "rotate", "kQx7p3Wn", "--verify", "--json"]).unwrap(),
+            Command::Orders {
+                action: OrdersAction::Rotate { order_id: "kQx7p3Wn".to_string(), verify: true },
+                api_key: None,
+                json: true,
+            }
+        );
+        assert_eq!(
+            parse(&["orders", "topup", "kQx7p3Wn", "us_residential_1gb", "--pay-currency", "btc"]).unwrap(),
+            Command::Orders {
+                action: OrdersAction::Topup {
+                    order_id: "kQx7p3Wn".to_string(),
+                    package_id: "us_residential_1gb".to_string(),
+                    pay_currency: Some("btc".to_string()),
+                },
+                api_key: None,
+                json: false,
+            }
+        );
+
+        assert!(parse(&["orders"]).unwrap_err().contains("missing action"));
+        assert!(parse(&["orders", "status"]).unwrap_err().contains("Missing argument"));
+        assert!(parse(&["orders", "status", "a", "b"]).unwrap_err().contains("Unexpected argument: b"));
+        assert!(parse(&["orders", "status", "a", "--verify"]).unwrap_err().contains("Unknown option"));
+        assert!(parse(&["orders", "topup", "a", "p", "--pay-currency"]).unwrap_err().contains("Missing value"));
+        assert!(parse(&["orders", "cancel", "a"]).is_err());
+        assert!(parse(&["packages", "extra"]).is_err());
+    }
+
+    #[test]
+    fn test_split_global() {
+        let args = ["--config", "/etc/pb.toml", "packages", "--profile", "staging", "--json", "--read-only"];
+        let (overrides, rest) = split_global(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(overrides.config_path, Some(PathBuf::from("/etc/pb.toml")));
+        assert_eq!(overrides.profile.as_deref(), Some("staging"));
+        assert!(overrides.read_only);
+        assert_eq!(rest, ["packages", "--json"]);
+        assert_eq!(Command::parse(rest).unwrap(), Command::Packages { api_key: None, refresh: false, json: true });
+
+        assert!(split_global(["--profile".to_string()]).is_err());
+        assert_eq!(parse(&["config", "show"]).unwrap(), Command::ConfigShow { json: false });
+        assert!(parse(&["config"]).is_err());
+
+        for option in ["--config PATH", "--profile NAME", "--api-url URL", "--read-only"] {
+            assert_eq!(USAGE.matches(&format!("  {} ", option)).count(), 1, "{}", option);
+        }
+    }
+
+    #[test]
+    fn test_parse_install() {
+        assert_eq!(
+            parse(&["install", "--client", "cursor", "--config-path", "/tmp/mcp.json"]).unwrap(),
+            Command::Install {
+                client: "cursor".to_string(),
+                config_path: Some(PathBuf::from("/tmp/mcp.json")),
+                api_key: None,
+            }
+        );
+        assert_eq!(
+            parse(&["uninstall", "--client", "claude-desktop"]).unwrap(),
+            Command::Uninstall { client: "claude-desktop".to_string(), config_path: None }
+        );
+        assert!(parse(&["install"]).unwrap_err().contains("missing --client"));
+
+        assert_eq!(
+            parse(&["doctor", "--proxies", "--max-clock-skew", "30"]).unwrap(),
+            Command::Doctor { api_key: None, test_proxies: true, max_clock_skew: Some(30), json: false }
+        );
+        assert!(parse(&["doctor", "--max-clock-skew", "soon"]).is_err());
+    }
+}
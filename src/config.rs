@@ -0,0 +1,804 @@
+//! Server configuration.
+//!
+//! Settings are layered: built-in defaults, then `config.toml` (and the
+//! selected profile in it), then `PROXYBASE_*` environment variables, then
+//! command-line options. Each layer only overrides what it sets.
+//!
+//! There is no transport setting: the server speaks MCP over stdio only,
+//! and the listeners it can run (webhook receiver, local gateways) are
+//! configured on their own.
+
+use crate::autotopup::RuleDefaults;
+use crate::cache;
+use crate::callback::CallbackPolicy;
+use crate::logging::{LogConfig, LogFormat};
+use crate::probe;
+use crate::redact;
+use crate::schedule::RotationConfig;
+use crate::usage;
+use crate::webhook::WebhookConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_API_URL: &str = "https://api.proxybase.xyz";
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// The only tools available in read-only mode: they look up the catalog
+/// and orders and nothing else.
+pub const READ_ONLY_TOOLS: &[&str] = &["list_packages", "list_currencies", "check_order_status"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub api_url: String,
+    /// API key for CLI commands; MCP tools take it as an argument.
+    pub api_key: Option<String>,
+    /// Timeout for each request to the backend.
+    pub request_timeout: Duration,
+    pub cache_ttl: Duration,
+    pub cache_dir: Option<PathBuf>,
+    /// Directory for durable local state (event inbox). `None` keeps
+    /// everything in memory.
+    pub data_dir: Option<PathBuf>,
+    /// `pay_currency` for new invoices when the agent does not pass one.
+    pub default_pay_currency: Option<String>,
+    pub callback: CallbackPolicy,
+    pub webhook: WebhookConfig,
+    /// IP echo service used by `test_proxy` to learn the exit address.
+    pub echo_url: String,
+    /// MaxMind DB file for checking exit IP countries after rotation.
+    pub geoip_db: Option<PathBuf>,
+    pub rotation: RotationConfig,
+    /// Defaults for `set_topup_rule`, including the spending budget.
+    pub topup: RuleDefaults,
+    /// Which tools this server exposes.
+    pub tools: ToolPolicy,
+    pub log: LogConfig,
+    /// Mask proxy passwords in tool results, for deployments that store
+    /// transcripts.
+    pub mask_proxy_passwords: bool,
+    /// How often orders seen by the server are re-polled for usage
+    /// history; zero disables background sampling.
+    pub usage_sample_interval: Duration,
+    /// The config file and profile that were loaded, if any.
+    pub file: Option<PathBuf>,
+    pub profile: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            api_key: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            cache_ttl: cache::DEFAULT_TTL,
+            cache_dir: None,
+            data_dir: None,
+            default_pay_currency: None,
+            callback: CallbackPolicy::default(),
+            webhook: WebhookConfig::default(),
+            echo_url: probe::DEFAULT_ECHO_URL.to_string(),
+            geoip_db: None,
+            rotation: RotationConfig::default(),
+            topup: RuleDefaults::default(),
+            tools: ToolPolicy::default(),
+            log: LogConfig::default(),
+            mask_proxy_passwords: false,
+            usage_sample_interval: usage::DEFAULT_SAMPLE_INTERVAL,
+            file: None,
+            profile: None,
+        }
+    }
+}
+
+/// Settings given on the command line, the highest-precedence layer.
+#[derive(Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub api_url: Option<String>,
+    pub read_only: bool,
+}
+
+/// Which tools are exposed: an optional allowlist, a denylist and read-only
+/// mode, all of which must let a tool through.
+#[derive(Debug, Clone, Default)]
+pub struct ToolPolicy {
+    /// Only these tools, when set.
+    pub enabled: Option<Vec<String>>,
+    pub disabled: Vec<String>,
+    pub read_only: bool,
+}
+
+impl ToolPolicy {
+    pub fn allows(&self, tool: &str) -> bool {
+        self.check(tool).is_ok()
+    }
+
+    /// Why `tool` is not available, if it is not.
+    pub fn check(&self, tool: &str) -> Result<(), String> {
+        let reason = if self.read_only && !READ_ONLY_TOOLS.contains(&tool) {
+            "read-only mode"
+        } else if self.enabled.as_ref().is_some_and(|e| !e.iter().any(|t| t == tool)) {
+            "not in tools.enabled"
+        } else if self.disabled.iter().any(|t| t == tool) {
+            "listed in tools.disabled"
+        } else {
+            return Ok(());
+        };
+        Err(format!("Tool '{}' is disabled on this server ({})", tool, reason))
+    }
+
+    /// Reject names that are not tools, so a typo cannot silently leave a
+    /// tool enabled.
+    pub fn validate(&self, known: &[&str]) -> Result<(), String> {
+        let listed = self.enabled.iter().flatten().chain(&self.disabled);
+        match listed.into_iter().find(|t| !known.contains(&t.as_str())) {
+            Some(unknown) => Err(format!("Unknown tool '{}' in the tool settings", unknown)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Config {
+    /// Load the layered configuration. The config file is `--config`,
+    /// else `$PROXYBASE_CONFIG`, else `config.toml` in the default config
+    /// directory, which may be absent.
+    pub fn load(overrides: &Overrides) -> Result<Self, String> {
+        let env: Env = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Self::load_with_env(overrides, &env)
+    }
+
+    /// [`Config::load`] with the environment given as a map.
+    fn load_with_env(overrides: &Overrides, env: &Env) -> Result<Self, String> {
+        let mut config = Self::default();
+
+        let explicit = overrides
+            .config_path
+            .clone()
+            .or_else(|| env_string(env, "PROXYBASE_CONFIG").map(PathBuf::from));
+        let profile = overrides.profile.clone().or_else(|| env_string(env, "PROXYBASE_PROFILE"));
+        if let Some(path) = explicit.clone().or_else(|| default_config_path(env)) {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => {
+                    let file = FileConfig::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+                    file.apply_to(&mut config, profile.as_deref())
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+                    config.file = Some(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && explicit.is_none() => {}
+                Err(e) => return Err(format!("Failed to read config file {}: {}", path.display(), e)),
+            }
+        }
+        if let (Some(profile), None) = (&profile, &config.file) {
+            return Err(format!("Profile '{}' requested but no config file was found", profile));
+        }
+
+        config.apply_env(env)?;
+        set(&mut config.api_url, overrides.api_url.clone());
+        config.tools.read_only |= overrides.read_only;
+        if config.data_dir.is_none() {
+            config.data_dir = default_data_dir(env);
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, env: &Env) -> Result<(), String> {
+        set(&mut self.api_url, env_string(env, "PROXYBASE_API_URL"));
+        set_some(&mut self.api_key, env_string(env, "PROXYBASE_API_KEY"));
+        set(&mut self.request_timeout, env_duration(env, "PROXYBASE_REQUEST_TIMEOUT")?);
+        set(&mut self.cache_ttl, env_duration(env, "PROXYBASE_CACHE_TTL")?);
+        set_some(&mut self.cache_dir, env_string(env, "PROXYBASE_CACHE_DIR").map(PathBuf::from));
+        set_some(&mut self.data_dir, env_string(env, "PROXYBASE_DATA_DIR").map(PathBuf::from));
+        set_some(&mut self.default_pay_currency, env_string(env, "PROXYBASE_DEFAULT_PAY_CURRENCY"));
+        set(&mut self.callback.allow_http, env_string(env, "PROXYBASE_CALLBACK_ALLOW_HTTP").map(|v| parse_flag(&v)));
+        set(&mut self.callback.allow_private, env_string(env, "PROXYBASE_CALLBACK_ALLOW_PRIVATE").map(|v| parse_flag(&v)));
+        set(&mut self.callback.allowed_domains, env_string(env, "PROXYBASE_CALLBACK_ALLOWED_DOMAINS").map(|v| parse_list(&v)));
+        set_some(
+            &mut self.webhook.listen,
+            env_string(env, "PROXYBASE_WEBHOOK_LISTEN")
+                .map(|v| v.trim().parse().map_err(|e| format!("PROXYBASE_WEBHOOK_LISTEN: invalid address '{}': {}", v, e)))
+                .transpose()?,
+        );
+        set_some(&mut self.webhook.public_url, env_string(env, "PROXYBASE_WEBHOOK_PUBLIC_URL"));
+        set_some(&mut self.webhook.secret, env_string(env, "PROXYBASE_WEBHOOK_SECRET"));
+        set(&mut self.echo_url, env_string(env, "PROXYBASE_ECHO_URL"));
+        set_some(&mut self.geoip_db, env_string(env, "PROXYBASE_GEOIP_DB").map(PathBuf::from));
+        set(&mut self.rotation.schedules, env_string(env, "PROXYBASE_ROTATION_SCHEDULE").map(|v| parse_schedules(&v)));
+        set(&mut self.rotation.max_per_hour, env_number(env, "PROXYBASE_ROTATION_MAX_PER_HOUR")?);
+        set(&mut self.rotation.jitter_pct, env_number(env, "PROXYBASE_ROTATION_JITTER")?);
+        set(&mut self.usage_sample_interval, env_duration(env, "PROXYBASE_USAGE_SAMPLE_INTERVAL")?);
+        set(&mut self.mask_proxy_passwords, env_string(env, "PROXYBASE_MASK_PROXY_PASSWORDS").map(|v| parse_flag(&v)));
+        set_some(&mut self.tools.enabled, env_string(env, "PROXYBASE_TOOLS").map(|v| parse_list(&v)));
+        set(&mut self.tools.disabled, env_string(env, "PROXYBASE_DISABLED_TOOLS").map(|v| parse_list(&v)));
+        set(&mut self.tools.read_only, env_string(env, "PROXYBASE_READ_ONLY").map(|v| parse_flag(&v)));
+        set(
+            &mut self.log.format,
+            env_string(env, "PROXYBASE_LOG_FORMAT")
+                .map(|v| LogFormat::parse(&v).map_err(|e| format!("PROXYBASE_LOG_FORMAT: {}", e)))
+                .transpose()?,
+        );
+        set_some(&mut self.log.file, env_string(env, "PROXYBASE_LOG_FILE").map(PathBuf::from));
+        Ok(())
+    }
+
+    /// The effective configuration as TOML, with the API key and webhook
+    /// secret redacted.
+    pub fn show(&self) -> String {
+        let file = self.redacted();
+        let mut out = String::new();
+        match &self.file {
+            Some(path) => out.push_str(&format!("# Config file: {}\n", path.display())),
+            None => out.push_str("# Config file: none\n"),
+        }
+        if let Some(profile) = &self.profile {
+            out.push_str(&format!("# Profile: {}\n", profile));
+        }
+        out.push('\n');
+        out.push_str(&toml::to_string(&file).unwrap_or_default());
+        out
+    }
+
+    /// [`Config::show`] as JSON, with the file and profile alongside.
+    pub fn show_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "file": self.file,
+            "profile": self.profile,
+            "config": self.redacted()
+        })
+    }
+
+    fn redacted(&self) -> FileConfig {
+        let mut file = FileConfig::from(self);
+        file.api_key = file.api_key.as_deref().map(redact::key);
+        if let Some(webhook) = &mut file.webhook {
+            webhook.secret = webhook.secret.as_ref().map(|_| redact::MASK.to_string());
+        }
+        file
+    }
+}
+
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+fn set_some<T>(target: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *target = value;
+    }
+}
+
+// ---------------------------------------------------------------------------
+// config.toml
+// ---------------------------------------------------------------------------
+
+/// The contents of `config.toml`. Every setting is optional; a profile is a
+/// table under `[profiles.<name>]` with the same settings, applied on top.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    /// Profile to use when neither `--profile` nor `PROXYBASE_PROFILE` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_timeout: Option<Seconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_ttl: Option<Seconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_pay_currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    echo_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geoip_db: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage_sample_interval: Option<Seconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask_proxy_passwords: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    callback: Option<FileCallback>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<FileWebhook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotation: Option<FileRotation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topup: Option<FileTopup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<FileTools>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<FileLog>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profiles: BTreeMap<String, FileConfig>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileCallback {
+    allow_http: Option<bool>,
+    allow_private: Option<bool>,
+    allowed_domains: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileWebhook {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen: Option<std::net::SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRotation {
+    max_per_hour: Option<u32>,
+    jitter_pct: Option<u32>,
+    /// Order id to interval, as in `PROXYBASE_ROTATION_SCHEDULE`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    schedules: BTreeMap<String, Seconds>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTopup {
+    threshold_pct: Option<f64>,
+    max_topups: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget_usd: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTools {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enabled: Option<Vec<String>>,
+    disabled: Option<Vec<String>>,
+    read_only: Option<bool>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLog {
+    format: Option<LogFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<PathBuf>,
+}
+
+/// A duration: whole seconds, or a string such as `"90"`, `"10m"` or `"2h"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Seconds {
+    Number(u64),
+    Text(String),
+}
+
+impl Seconds {
+    fn duration(&self) -> Result<Duration, String> {
+        match self {
+            Seconds::Number(n) => Ok(Duration::from_secs(*n)),
+            Seconds::Text(s) => parse_duration(s).ok_or_else(|| format!("invalid duration '{}'", s)),
+        }
+    }
+}
+
+impl FileConfig {
+    fn parse(text: &str) -> Result<Self, String> {
+        let file: FileConfig = toml::from_str(text).map_err(|e| e.to_string().trim_end().to_string())?;
+        if let Some(name) = file.profiles.iter().find(|(_, p)| p.profile.is_some() || !p.profiles.is_empty()).map(|(n, _)| n) {
+            return Err(format!("profile '{}' cannot select or define profiles", name));
+        }
+        Ok(file)
+    }
+
+    /// Apply the top-level settings, then those of `profile` (or of the
+    /// file's own `profile` setting).
+    fn apply_to(&self, config: &mut Config, profile: Option<&str>) -> Result<(), String> {
+        self.apply(config)?;
+        if let Some(name) = profile.or(self.profile.as_deref()) {
+            let overlay = self.profiles.get(name).ok_or_else(|| {
+                let known: Vec<&str> = self.profiles.keys().map(|k| k.as_str()).collect();
+                format!("unknown profile '{}' (defined: {})", name, if known.is_empty() { "none".to_string() } else { known.join(", ") })
+            })?;
+            overlay.apply(config).map_err(|e| format!("profile '{}': {}", name, e))?;
+            config.profile = Some(name.to_string());
+        }
+        Ok(())
+    }
+
+    fn apply(&self, config: &mut Config) -> Result<(), String> {
+        let duration = |value: &Option<Seconds>| value.as_ref().map(|s| s.duration()).transpose();
+
+        set(&mut config.api_url, self.api_url.clone());
+        set_some(&mut config.api_key, self.api_key.clone());
+        set(&mut config.request_timeout, duration(&self.request_timeout)?);
+        set(&mut config.cache_ttl, duration(&self.cache_ttl)?);
+        set_some(&mut config.cache_dir, self.cache_dir.clone());
+        set_some(&mut config.data_dir, self.data_dir.clone());
+        set_some(&mut config.default_pay_currency, self.default_pay_currency.clone());
+        set(&mut config.echo_url, self.echo_url.clone());
+        set_some(&mut config.geoip_db, self.geoip_db.clone());
+        set(&mut config.usage_sample_interval, duration(&self.usage_sample_interval)?);
+        set(&mut config.mask_proxy_passwords, self.mask_proxy_passwords);
+        if let Some(callback) = &self.callback {
+            set(&mut config.callback.allow_http, callback.allow_http);
+            set(&mut config.callback.allow_private, callback.allow_private);
+            set(&mut config.callback.allowed_domains, callback.allowed_domains.clone());
+        }
+        if let Some(webhook) = &self.webhook {
+            set_some(&mut config.webhook.listen, webhook.listen);
+            set_some(&mut config.webhook.public_url, webhook.public_url.clone());
+            set_some(&mut config.webhook.secret, webhook.secret.clone());
+        }
+        if let Some(rotation) = &self.rotation {
+            set(&mut config.rotation.max_per_hour, rotation.max_per_hour);
+            set(&mut config.rotation.jitter_pct, rotation.jitter_pct);
+            if !rotation.schedules.is_empty() {
+                config.rotation.schedules = rotation
+                    .schedules
+                    .iter()
+                    .map(|(id, interval)| Ok((id.clone(), interval.duration()?)))
+                    .collect::<Result<_, String>>()?;
+            }
+        }
+        if let Some(topup) = &self.topup {
+            set(&mut config.topup.threshold_pct, topup.threshold_pct);
+            set(&mut config.topup.max_topups, topup.max_topups);
+            set_some(&mut config.topup.budget_usd, topup.budget_usd);
+        }
+        if let Some(tools) = &self.tools {
+            set_some(&mut config.tools.enabled, tools.enabled.clone());
+            set(&mut config.tools.disabled, tools.disabled.clone());
+            set(&mut config.tools.read_only, tools.read_only);
+        }
+        if let Some(log) = &self.log {
+            set(&mut config.log.format, log.format);
+            set_some(&mut config.log.file, log.file.clone());
+        }
+        Ok(())
+    }
+}
+
+impl From<&Config> for FileConfig {
+    fn from(config: &Config) -> Self {
+        let secs = |d: Duration| Some(Seconds::Number(d.as_secs()));
+        Self {
+            profile: None,
+            api_url: Some(config.api_url.clone()),
+            api_key: config.api_key.clone(),
+            request_timeout: secs(config.request_timeout),
+            cache_ttl: secs(config.cache_ttl),
+            cache_dir: config.cache_dir.clone(),
+            data_dir: config.data_dir.clone(),
+            default_pay_currency: config.default_pay_currency.clone(),
+            echo_url: Some(config.echo_url.clone()),
+            geoip_db: config.geoip_db.clone(),
+            usage_sample_interval: secs(config.usage_sample_interval),
+            mask_proxy_passwords: Some(config.mask_proxy_passwords),
+            callback: Some(FileCallback {
+                allow_http: Some(config.callback.allow_http),
+                allow_private: Some(config.callback.allow_private),
+                allowed_domains: Some(config.callback.allowed_domains.clone()),
+            }),
+            webhook: Some(FileWebhook {
+                listen: config.webhook.listen,
+                public_url: config.webhook.public_url.clone(),
+                secret: config.webhook.secret.clone(),
+            })
+            .filter(|w| w.listen.is_some() || w.public_url.is_some() || w.secret.is_some()),
+            rotation: Some(FileRotation {
+                max_per_hour: Some(config.rotation.max_per_hour),
+                jitter_pct: Some(config.rotation.jitter_pct),
+                schedules: config
+                    .rotation
+                    .schedules
+                    .iter()
+                    .map(|(id, d)| (id.clone(), Seconds::Number(d.as_secs())))
+                    .collect(),
+            }),
+            topup: Some(FileTopup {
+                threshold_pct: Some(config.topup.threshold_pct),
+                max_topups: Some(config.topup.max_topups),
+                budget_usd: config.topup.budget_usd,
+            }),
+            tools: Some(FileTools {
+                enabled: config.tools.enabled.clone(),
+                disabled: Some(config.tools.disabled.clone()),
+                read_only: Some(config.tools.read_only),
+            }),
+            log: Some(FileLog {
+                format: Some(config.log.format),
+                file: config.log.file.clone(),
+            }),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Whether the config file at `path` holds an API key or webhook secret, at
+/// the top level or in a profile.
+pub fn file_holds_secrets(path: &Path) -> bool {
+    let holds = |file: &FileConfig| file.api_key.is_some() || file.webhook.as_ref().is_some_and(|w| w.secret.is_some());
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| FileConfig::parse(&text).ok())
+        .is_some_and(|file| holds(&file) || file.profiles.values().any(holds))
+}
+
+/// `$XDG_CONFIG_HOME/proxybase-mcp/config.toml`, falling back to
+/// `~/.config` (or `%APPDATA%` on Windows).
+fn default_config_path(env: &Env) -> Option<PathBuf> {
+    let base = env_string(env, "XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env_string(env, "APPDATA").map(PathBuf::from))
+        .or_else(|| env_string(env, "HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("proxybase-mcp").join(CONFIG_FILE))
+}
+
+/// `$XDG_DATA_HOME/proxybase-mcp`, falling back to `~/.local/share` (or
+/// `%APPDATA%` on Windows).
+fn default_data_dir(env: &Env) -> Option<PathBuf> {
+    let base = env_string(env, "XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env_string(env, "APPDATA").map(PathBuf::from))
+        .or_else(|| env_string(env, "HOME").map(|h| PathBuf::from(h).join(".local").join("share")))?;
+    Some(base.join("proxybase-mcp"))
+}
+
+/// Environment variables by name.
+type Env = BTreeMap<String, String>;
+
+fn env_string(env: &Env, name: &str) -> Option<String> {
+    env.get(name).filter(|v| !v.trim().is_empty()).cloned()
+}
+
+/// A duration variable, spelled as in the config file.
+fn env_duration(env: &Env, name: &str) -> Result<Option<Duration>, String> {
+    env_string(env, name)
+        .map(|v| Seconds::Text(v).duration().map_err(|e| format!("{}: {}", name, e)))
+        .transpose()
+}
+
+/// A whole-number variable; a typo is an error rather than the default.
+fn env_number(env: &Env, name: &str) -> Result<Option<u32>, String> {
+    env_string(env, name)
+        .map(|v| v.trim().parse().map_err(|_| format!("{}: invalid number '{}'", name, v)))
+        .transpose()
+}
+
+fn parse_flag(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// `order_id=interval` pairs separated by commas, e.g. `kQx7p3Wn=10m`.
+/// Invalid entries are skipped with a warning.
+fn parse_schedules(value: &str) -> Vec<(String, Duration)> {
+    parse_list(value)
+        .into_iter()
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(id, interval)| Some((id.trim().to_string(), parse_duration(interval)?)));
+            if parsed.is_none() {
+                log::warn!("Ignoring rotation schedule '{}': expected order_id=interval", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Seconds, or a number with an `s`, `m` or `h` suffix.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 's') => (&value[..i], 1),
+        (i, 'm') => (&value[..i], 60),
+        (i, 'h') => (&value[..i], 3600),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(unit).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flag() {
+        for v in ["1", "true", "YES", " on "] {
+            assert!(parse_flag(v), "{}", v);
+        }
+        for v in ["0", "false", "no", ""] {
+            assert!(!parse_flag(v), "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            parse_list(" example.com, ,hooks.example.org "),
+            vec!["example.com", "hooks.example.org"]
+        );
+    }
+
+    const SAMPLE: &str = r#"
+profile = "staging"
+api_url = "https://api.proxybase.xyz"
+api_key = "pk_live_0123456789abcdef"
+request_timeout = "1m"
+default_pay_currency = "usdttrc20"
+
+[webhook]
+listen = "127.0.0.1:8787"
+secret = "whsec_abc"
+
+[rotation.schedules]
+kQx7p3Wn = "10m"
+
+[topup]
+budget_usd = 50.0
+
+[log]
+format = "json"
+
+[profiles.staging]
+api_url = "https://staging.proxybase.xyz"
+
+[profiles.monitor]
+default_pay_currency = "btc"
+"#;
+
+    #[test]
+    fn test_file_layers_and_profiles() {
+        let file = FileConfig::parse(SAMPLE).unwrap();
+        let mut config = Config::default();
+        file.apply_to(&mut config, None).unwrap();
+        // The file's own profile applies on top of the top-level settings
+        assert_eq!(config.api_url, "https://staging.proxybase.xyz");
+        assert_eq!(config.profile.as_deref(), Some("staging"));
+        assert_eq!(config.request_timeout, Duration::from_secs(60));
+        assert_eq!(config.rotation.schedules, vec![("kQx7p3Wn".to_string(), Duration::from_secs(600))]);
+        assert_eq!(config.topup.budget_usd, Some(50.0));
+        assert_eq!(config.topup.max_topups, crate::autotopup::DEFAULT_MAX_TOPUPS);
+        assert_eq!(config.log.format, LogFormat::Json);
+
+        let mut config = Config::default();
+        file.apply_to(&mut config, Some("monitor")).unwrap();
+        assert_eq!(config.api_url, "https://api.proxybase.xyz");
+        assert_eq!(config.default_pay_currency.as_deref(), Some("btc"));
+
+        let err = file.apply_to(&mut Config::default(), Some("prod")).unwrap_err();
+        assert!(err.contains("monitor, staging"), "{}", err);
+        assert!(FileConfig::parse("api_ur = 'x'").unwrap_err().contains("unknown field"));
+        assert!(FileConfig::parse("cache_ttl = 'soon'").unwrap().apply(&mut Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_load_precedence_and_show() {
+        let path = std::env::temp_dir().join(format!("proxybase-config-test-{}.toml", std::process::id()));
+        std::fs::write(&path, SAMPLE).unwrap();
+        let mut env: Env = [
+            ("PROXYBASE_CONFIG", path.to_str().unwrap()),
+            ("PROXYBASE_PROFILE", "monitor"),
+            ("PROXYBASE_API_URL", "https://env.proxybase.xyz"),
+            ("PROXYBASE_DEFAULT_PAY_CURRENCY", "ltc"),
+            ("PROXYBASE_REQUEST_TIMEOUT", "2m"),
+            ("XDG_DATA_HOME", "/xdg/data"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        // CLI over env over file over defaults
+        let overrides = Overrides {
+            api_url: Some("http://127.0.0.1:8080".to_string()),
+            read_only: true,
+            ..Overrides::default()
+        };
+        let config = Config::load_with_env(&overrides, &env).unwrap();
+        assert_eq!(config.api_url, "http://127.0.0.1:8080");
+        assert_eq!(config.default_pay_currency.as_deref(), Some("ltc"));
+        assert_eq!(config.request_timeout, Duration::from_secs(120));
+        assert_eq!(config.topup.budget_usd, Some(50.0));
+        assert_eq!(config.cache_ttl, cache::DEFAULT_TTL);
+        assert_eq!(config.file.as_deref(), Some(path.as_path()));
+        assert_eq!(config.profile.as_deref(), Some("monitor"));
+        assert_eq!(config.data_dir, Some(PathBuf::from("/xdg/data/proxybase-mcp")));
+        assert!(config.tools.read_only);
+        assert_eq!(Config::load_with_env(&Overrides::default(), &env).unwrap().api_url, "https://env.proxybase.xyz");
+
+        let shown = config.show();
+        assert!(shown.contains("# Profile: monitor"));
+        assert!(shown.contains("api_key = \"pk_…cdef\""), "{}", shown);
+        assert!(shown.contains("secret = \"<redacted>\""));
+        assert!(!shown.contains("whsec_abc"));
+        // What is shown can be loaded again
+        assert!(FileConfig::parse(shown.split_once("\n\n").unwrap().1).is_ok());
+
+        env.insert("PROXYBASE_CACHE_TTL".to_string(), "soon".to_string());
+        let err = Config::load_with_env(&Overrides::default(), &env).unwrap_err();
+        assert_eq!(err, "PROXYBASE_CACHE_TTL: invalid duration 'soon'");
+        env.insert("PROXYBASE_CACHE_TTL".to_string(), "9999999999999999h".to_string());
+        let err = Config::load_with_env(&Overrides::default(), &env).unwrap_err();
+        assert_eq!(err, "PROXYBASE_CACHE_TTL: invalid duration '9999999999999999h'");
+        env.remove("PROXYBASE_CACHE_TTL");
+        env.insert("PROXYBASE_ROTATION_MAX_PER_HOUR".to_string(), "6/h".to_string());
+        let err = Config::load_with_env(&Overrides::default(), &env).unwrap_err();
+        assert_eq!(err, "PROXYBASE_ROTATION_MAX_PER_HOUR: invalid number '6/h'");
+        env.remove("PROXYBASE_ROTATION_MAX_PER_HOUR");
+        env.insert("PROXYBASE_WEBHOOK_LISTEN".to_string(), "127.0.0.1".to_string());
+        let err = Config::load_with_env(&Overrides::default(), &env).unwrap_err();
+        assert!(err.starts_with("PROXYBASE_WEBHOOK_LISTEN: invalid address '127.0.0.1'"), "{}", err);
+        env.insert("PROXYBASE_WEBHOOK_LISTEN".to_string(), "127.0.0.1:8787".to_string());
+        let config = Config::load_with_env(&Overrides::default(), &env).unwrap();
+        assert_eq!(config.webhook.listen, Some("127.0.0.1:8787".parse().unwrap()));
+        env.remove("PROXYBASE_WEBHOOK_LISTEN");
+        env.insert("PROXYBASE_LOG_FORMAT".to_string(), "jsn".to_string());
+        let err = Config::load_with_env(&Overrides::default(), &env).unwrap_err();
+        assert_eq!(err, "PROXYBASE_LOG_FORMAT: invalid log format 'jsn' (expected text or json)");
+        env.remove("PROXYBASE_LOG_FORMAT");
+
+        let missing = Overrides { config_path: Some(path.with_extension("absent")), ..Overrides::default() };
+        assert!(Config::load_with_env(&missing, &env).unwrap_err().contains("Failed to read"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_tool_policy() {
+        let read_only = ToolPolicy { read_only: true, ..ToolPolicy::default() };
+        assert!(read_only.allows("check_order_status"));
+        assert!(read_only.check("create_order").unwrap_err().contains("read-only mode"));
+
+        let policy = FileConfig::parse("[tools]\nenabled = [\"list_packages\", \"create_order\"]\ndisabled = [\"create_order\"]")
+            .unwrap();
+        let mut config = Config::default();
+        policy.apply(&mut config).unwrap();
+        assert!(config.tools.allows("list_packages"));
+        assert!(config.tools.check("create_order").unwrap_err().contains("tools.disabled"));
+        assert!(config.tools.check("rotate_proxy").unwrap_err().contains("tools.enabled"));
+
+        assert!(config.tools.validate(&["list_packages", "create_order"]).is_ok());
+        assert!(config.tools.validate(&["list_packages"]).unwrap_err().contains("create_order"));
+    }
+
+    #[test]
+    fn test_parse_schedules() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("soon"), None);
+
+        assert_eq!(
+            parse_schedules("kQx7p3Wn=10m, bad, Zp4rT8Lm=300"),
+            vec![
+                ("kQx7p3Wn".to_string(), Duration::from_secs(600)),
+                ("Zp4rT8Lm".to_string(), Duration::from_secs(300)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Resolve friendly currency names ("USDT on Tron", "bitcoin") to the
+//! `pay_currency` codes the backend accepts.
+//!
+//! Resolution runs against the live `list_currencies` result, so it never
+//! invents a code: a name is split into an asset and an optional network,
+//! and the codes that start with the asset and mention the network win.
+
+/// Names of assets, mapped to the prefix of their codes.
+const ASSETS: &[(&str, &[&str])] = &[
+    ("btc", &["btc", "bitcoin", "xbt"]),
+    ("eth", &["eth", "ether", "ethereum"]),
+    ("usdt", &["usdt", "tether"]),
+    ("usdc", &["usdc", "usdcoin"]),
+    ("ltc", &["ltc", "litecoin"]),
+    ("doge", &["doge", "dogecoin"]),
+    ("sol", &["sol", "solana"]),
+    ("trx", &["trx", "tron"]),
+    ("bnb", &["bnb"]),
+    ("xmr", &["xmr", "monero"]),
+    ("ton", &["ton", "toncoin"]),
+    ("dai", &["dai"]),
+    ("matic", &["matic", "pol"]),
+];
+
+/// Names of networks, mapped to how codes spell them.
+const NETWORKS: &[(&[&str], &[&str])] = &[
+    (&["tron", "trc20", "trx"], &["trc20", "trx"]),
+    (&["ethereum", "erc20", "eth"], &["erc20", "eth"]),
+    (&["solana", "sol", "spl"], &["sol"]),
+    (&["bsc", "bep20", "bnb", "binance"], &["bsc", "bep20"]),
+    (&["polygon", "matic"], &["matic", "polygon"]),
+    (&["arbitrum", "arb"], &["arb"]),
+    (&["base"], &["base"]),
+    (&["ton"], &["ton"]),
+    (&["avalanche", "avax"], &["avax"]),
+    (&["optimism", "op"], &["op"]),
+    (&["lightning", "ln"], &["ln"]),
+];
+
+/// Words that carry no meaning in a currency name.
+const FILLER: &[&str] = &["on", "via", "over", "the", "network", "chain", "token", "coin", "smart", "usd"];
+
+/// Resolve `input` against `available` codes. Exact codes (in any case,
+/// with or without separators, e.g. `USDT-TRC20`) are returned as they are.
+pub fn resolve(input: &str, available: &[&str]) -> Result<String, String> {
+    let compact: String = input.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    if let Some(code) = available.iter().find(|c| c.eq_ignore_ascii_case(&compact)) {
+        return Ok(code.to_string());
+    }
+
+    let candidates = candidates(input, available);
+    match candidates.as_slice() {
+        [code] => Ok(code.to_string()),
+        [] => Err(format!(
+            "Invalid pay_currency: '{}'. Supported currencies: {}",
+            input,
+            available.join(", ")
+        )),
+        several => Err(format!(
+            "Ambiguous pay_currency: '{}' matches {}. Pass one of these codes, or name the network (e.g. 'USDT on Tron').",
+            input,
+            several.join(", ")
+        )),
+    }
+}
+
+fn candidates<'a>(input: &str, available: &[&'a str]) -> Vec<&'a str> {
+    let lower = input.to_lowercase();
+    // "USD Coin" is one name
+    let lower = lower.replace("usd coin", "usdcoin");
+    // Standards are often written apart, as in "ERC-20"
+    let mut words: Vec<String> = Vec::new();
+    for word in lower.split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| !w.is_empty()) {
+        match words.last_mut() {
+            Some(last) if word.chars().all(|c| c.is_ascii_digit()) => last.push_str(word),
+            _ => words.push(word.to_string()),
+        }
+    }
+    words.retain(|w| !FILLER.contains(&w.as_str()));
+
+    let asset_of = |word: &str| ASSETS.iter().find(|(_, names)| names.contains(&word)).map(|(code, _)| *code);
+    let network_of = |word: &str| NETWORKS.iter().find(|(names, _)| names.contains(&word)).map(|(_, spellings)| *spellings);
+
+    // The first word naming an asset is the asset; with a token such as
+    // USDT, chain names like "tron" that follow are the network
+    let Some(asset_pos) = words.iter().position(|w| asset_of(w).is_some()) else {
+        return Vec::new();
+    };
+    let asset = asset_of(&words[asset_pos]).unwrap();
+    let network = words
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != asset_pos)
+        .find_map(|(_, w)| network_of(w));
+
+    let matching: Vec<&str> = available
+        .iter()
+        .copied()
+        .filter(|code| {
+            let code = code.to_lowercase();
+            code.starts_with(asset)
+                && network.is_none_or(|spellings| spellings.iter().any(|s| code[asset.len()..].contains(s)))
+        })
+        .collect();
+
+    // A bare native coin ("bitcoin") means the coin itself, not tokens
+    // or layer-2 variants that share its prefix
+    if network.is_none() {
+        if let Some(native) = matching.iter().find(|c| c.eq_ignore_ascii_case(asset)) {
+            return vec![native];
+        }
+    }
+    matching
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIVE: &[&str] = &["btc", "btcln", "eth", "ltc", "usdttrc20", "usdterc20", "usdtsol", "usdcsol", "usdcerc20", "sol", "trx"];
+
+    #[test]
+    fn test_resolve_aliases() {
+        assert_eq!(resolve("USDT on Tron", LIVE).unwrap(), "usdttrc20");
+        assert_eq!(resolve("bitcoin", LIVE).unwrap(), "btc");
+        assert_eq!(resolve("USDC Solana", LIVE).unwrap(), "usdcsol");
+        assert_eq!(resolve("USD Coin on Ethereum", LIVE).unwrap(), "usdcerc20");
+        assert_eq!(resolve("tether erc-20", LIVE).unwrap(), "usdterc20");
+        assert_eq!(resolve("Bitcoin Lightning", LIVE).unwrap(), "btcln");
+        assert_eq!(resolve("solana", LIVE).unwrap(), "sol");
+        assert_eq!(resolve("Tron", LIVE).unwrap(), "trx");
+        // Codes pass through, whatever their spelling
+        assert_eq!(resolve("USDT-TRC20", LIVE).unwrap(), "usdttrc20");
+        assert_eq!(resolve("LTC", LIVE).unwrap(), "ltc");
+    }
+
+    #[test]
+    fn test_ambiguous_and_unknown() {
+        let err = resolve("USDT", LIVE).unwrap_err();
+        assert!(err.contains("Ambiguous"), "{}", err);
+        assert!(err.contains("usdttrc20, usdterc20, usdtsol"), "{}", err);
+
+        let err = resolve("dogecoin", LIVE).unwrap_err();
+        assert!(err.starts_with("Invalid pay_currency: 'dogecoin'"), "{}", err);
+        assert!(resolve("USDT on BSC", LIVE).is_err());
+        assert!(resolve("monopoly money", LIVE).is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! Setup diagnostics for the `doctor` command and the `diagnose` tool.
+//!
+//! Each check passes, warns, fails or is skipped, and says how to fix what
+//! it found. Checks never abort the run: a later check that depends on an
+//! earlier one is skipped instead.
+
+use crate::config::{self, Config};
+use crate::install;
+use crate::models::OrderStatus;
+use crate::order_id::OrderId;
+use crate::probe;
+use crate::util::{parse_http_date, unix_now};
+use crate::ProxyBaseClient;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Active proxies probed at most, newest orders first.
+const MAX_PROXIES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+pub struct Options {
+    pub api_key: Option<String>,
+    pub max_clock_skew: Duration,
+    pub test_proxies: bool,
+}
+
+/// Run every check. `orders` are the locally known orders whose proxies are
+/// probed with `test_proxies`.
+pub async fn run(client: &ProxyBaseClient, config: &Config, orders: &[String], options: &Options) -> Value {
+    let mut checks = Vec::new();
+
+    let url = match reqwest::Url::parse(&config.api_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => {
+            checks.push(Check::new("api_url", Status::Pass, url.as_str()));
+            Some(url)
+        }
+        _ => {
+            checks.push(
+                Check::new("api_url", Status::Fail, format!("'{}' is not an http(s) URL", config.api_url))
+                    .hint("Set PROXYBASE_API_URL to a full URL such as https://api.proxybase.xyz"),
+            );
+            None
+        }
+    };
+
+    match &url {
+        Some(url) => {
+            let reachable = check_reachable(url).await;
+            let connected = reachable.status == Status::Pass;
+            checks.push(reachable);
+            if connected {
+                let (tls, server_time) = check_tls(client, url).await;
+                checks.push(tls);
+                checks.push(check_clock(server_time, unix_now(), options.max_clock_skew));
+            } else {
+                checks.push(Check::new("tls", Status::Skip, "backend not reachable"));
+                checks.push(Check::new("clock", Status::Skip, "backend not reachable"));
+            }
+        }
+        None => {
+            for name in ["reachable", "tls", "clock"] {
+                checks.push(Check::new(name, Status::Skip, "no valid API URL"));
+            }
+        }
+    }
+
+    let key_valid = match (&options.api_key, backend_ok(&checks)) {
+        (None, _) => {
+            checks.push(
+                Check::new("api_key", Status::Skip, "no API key configured")
+                    .hint("Set PROXYBASE_API_KEY or pass --api-key; `proxybase-mcp register` creates one"),
+            );
+            false
+        }
+        (Some(_), false) => {
+            checks.push(Check::new("api_key", Status::Skip, "backend checks failed"));
+            false
+        }
+        (Some(api_key), true) => {
+            let check = check_api_key(client.list_packages(api_key, true).await);
+            let valid = check.status == Status::Pass;
+            checks.push(check);
+            valid
+        }
+    };
+
+    let client_configs: Vec<_> = [install::Client::ClaudeDesktop, install::Client::Cursor]
+        .iter()
+        .filter_map(|c| c.config_path())
+        .collect();
+    checks.push(check_permissions(config.data_dir.as_deref(), config.file.as_deref(), &client_configs));
+
+    if options.test_proxies {
+        match (&options.api_key, key_valid) {
+            (Some(api_key), true) => checks.extend(check_proxies(client, config, api_key, orders).await),
+            _ => checks.push(Check::new("proxies", Status::Skip, "needs a valid API key")),
+        }
+    }
+
+    report(checks)
+}
+
+/// Whether the backend can be asked about the key. A skewed clock is
+/// reported on its own and does not stop the key from being checked.
+fn backend_ok(checks: &[Check]) -> bool {
+    checks
+        .iter()
+        .filter(|c| matches!(c.name.as_str(), "api_url" | "reachable" | "tls"))
+        .all(|c| c.status != Status::Fail)
+}
+
+fn report(checks: Vec<Check>) -> Value {
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    json!({
+        "ok": count(Status::Fail) == 0,
+        "summary": format!(
+            "{} passed, {} warnings, {} failed, {} skipped",
+            count(Status::Pass),
+            count(Status::Warn),
+            count(Status::Fail),
+            count(Status::Skip)
+        ),
+        "checks": checks
+    })
+}
+
+async fn check_reachable(url: &reqwest::Url) -> Check {
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addr = format!("{}:{}", url.host_str().unwrap_or_default(), port);
+    match tokio::time::timeout(TIMEOUT, tokio::net::TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Check::new("reachable", Status::Pass, format!("connected to {}", addr)),
+        Ok(Err(e)) => Check::new("reachable", Status::Fail, format!("cannot connect to {}: {}", addr, e))
+            .hint("Check DNS, firewall and network access to the backend, and that PROXYBASE_API_URL is correct"),
+        Err(_) => Check::new("reachable", Status::Fail, format!("timed out connecting to {}", addr))
+            .hint("A firewall may be dropping the connection; outbound HTTPS must be allowed"),
+    }
+}
+
+/// Make one request to the backend. Any HTTP response proves TLS works;
+/// its `Date` header gives the server's time.
+async fn check_tls(client: &ProxyBaseClient, url: &reqwest::Url) -> (Check, Option<u64>) {
+    let result = client.http.get(url.clone()).timeout(TIMEOUT).send().await;
+    match result {
+        Ok(resp) => {
+            let server_time = resp
+                .headers()
+                .get(reqwest::header::DATE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_http_date);
+            let check = if url.scheme() == "https" {
+                Check::new("tls", Status::Pass, format!("HTTPS handshake ok (HTTP {})", resp.status().as_u16()))
+            } else {
+                Check::new("tls", Status::Warn, "the backend URL is plain http; API keys are sent unencrypted")
+                    .hint("Use an https:// PROXYBASE_API_URL outside local testing")
+            };
+            (check, server_time)
+        }
+        Err(e) => {
+            let message = error_chain(&e);
+            let check = Check::new("tls", Status::Fail, format!("request failed: {}", message));
+            let check = if is_tls_error(&message) {
+                check.hint("The certificate was not accepted. Check the system clock; TLS-intercepting proxies are not supported because only the bundled Mozilla root certificates are trusted")
+            } else {
+                check.hint("The backend accepted the connection but the request failed; retry, or check for a proxy or firewall in between")
+            };
+            (check, None)
+        }
+    }
+}
+
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(inner) = source {
+        message.push_str(": ");
+        message.push_str(&inner.to_string());
+        source = inner.source();
+    }
+    message
+}
+
+fn is_tls_error(message: &str) -> bool {
+    let message = message.to_ascii_lowercase();
+    ["certificate", "tls", "handshake", "unknownissuer"].iter().any(|m| message.contains(m))
+}
+
+fn check_clock(server_time: Option<u64>, local: u64, max_skew: Duration) -> Check {
+    let Some(server_time) = server_time else {
+        return Check::new("clock", Status::Skip, "the backend sent no Date header");
+    };
+    let skew = local.abs_diff(server_time);
+    let direction = if local >= server_time { "ahead of" } else { "behind" };
+    if skew <= max_skew.as_secs() {
+        Check::new("clock", Status::Pass, format!("{}s {} the backend (limit {}s)", skew, direction, max_skew.as_secs()))
+    } else {
+        Check::new("clock", Status::Fail, format!("local clock is {}s {} the backend (limit {}s)", skew, direction, max_skew.as_secs()))
+            .hint("Enable automatic time sync (NTP), e.g. `timedatectl set-ntp true`; invoice expiry and webhook times depend on it")
+    }
+}
+
+fn check_api_key(result: Result<Value, String>) -> Check {
+    match result {
+        Ok(catalog) => {
+            let packages = crate::catalog::packages(&catalog).map_or(0, |p| p.len());
+            Check::new("api_key", Status::Pass, format!("accepted by list_packages ({} packages)", packages))
+        }
+        Err(e) if e.contains("(401") || e.contains("(403") => Check::new("api_key", Status::Fail, format!("rejected: {}", e))
+            .hint("Check the key for typos (it starts with pk_), or register a new agent with `proxybase-mcp register`"),
+        Err(e) => Check::new("api_key", Status::Fail, format!("list_packages failed: {}", e))
+            .hint("The key could not be verified; retry later or check the backend status"),
+    }
+}
+
+/// The data directory, a config file holding secrets and any MCP client
+/// config holding the API key must not be open to other users.
+fn check_permissions(data_dir: Option<&Path>, config_file: Option<&Path>, client_configs: &[PathBuf]) -> Check {
+    let mut problems = Vec::new();
+    let mut fixes = Vec::new();
+    let mut checked = Vec::new();
+
+    if let Some(dir) = data_dir {
+        match mode(dir) {
+            Some(mode) => {
+                checked.push(dir.display().to_string());
+                if mode & 0o077 != 0 {
+                    problems.push(format!("{} is accessible by other users (mode {:o})", dir.display(), mode & 0o777));
+                    fixes.push(format!("chmod 700 {}", dir.display()));
+                }
+            }
+            None => checked.push(format!("{} (not created yet)", dir.display())),
+        }
+    }
+
+    let config_file = config_file.filter(|path| config::file_holds_secrets(path)).map(|path| (path, "the API key or webhook secret"));
+    let client_configs = client_configs.iter().filter(|path| holds_api_key(path)).map(|path| (path.as_path(), "the API key"));
+    for (path, secret) in config_file.into_iter().chain(client_configs) {
+        checked.push(path.display().to_string());
+        if let Some(mode) = mode(path).filter(|m| m & 0o077 != 0) {
+            problems.push(format!("{} holds {} and is readable by other users (mode {:o})", path.display(), secret, mode & 0o777));
+            fixes.push(format!("chmod 600 '{}'", path.display()));
+        }
+    }
+
+    if !cfg!(unix) {
+        return Check::new("permissions", Status::Skip, "only checked on Unix");
+    }
+    if problems.is_empty() {
+        let detail = match checked.is_empty() {
+            true => "no local credential stores".to_string(),
+            false => format!("checked {}", checked.join(", ")),
+        };
+        Check::new("permissions", Status::Pass, detail)
+    } else {
+        Check::new("permissions", Status::Fail, problems.join("; ")).hint(fixes.join(" && "))
+    }
+}
+
+fn holds_api_key(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .is_some_and(|config| !config["mcpServers"][install::SERVER_NAME]["env"]["PROXYBASE_API_KEY"].is_null())
+}
+
+#[cfg(unix)]
+fn mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).ok().map(|m| m.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Probe the proxies of locally known active orders.
+async fn check_proxies(client: &ProxyBaseClient, config: &Config, api_key: &str, orders: &[String]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for id in orders.iter().rev() {
+        if checks.len() == MAX_PROXIES {
+            break;
+        }
+        let Ok(order_id) = OrderId::parse(id) else { continue };
+        let Ok(status) = client.check_order_status(api_key, &order_id).await else { continue };
+        let Ok(order) = OrderStatus::from_value(&status) else { continue };
+        let Ok(proxy) = order.usable_proxy() else { continue };
+
+        let name = format!("proxy {}", order_id);
+        let probe = probe::probe(proxy, &config.echo_url, TIMEOUT).await;
+        checks.push(match (probe.ok, &probe.exit_ip) {
+            (true, Some(ip)) => Check::new(name, Status::Pass, format!("{} works, exit IP {}", probe.proxy, ip)),
+            _ => {
+                let stage = probe.failed_stage.unwrap_or("fetch");
+                let hint = match stage {
+                    "auth" => "The proxy rejected the order's credentials; run check_order_status for current ones",
+                    "connect" | "greeting" => "The proxy host is unreachable; outbound connections to its port may be blocked",
+                    _ => "Run test_proxy for the full per-stage report",
+                };
+                Check::new(name, Status::Fail, format!("failed at {}: {}", stage, probe.error.unwrap_or_default())).hint(hint)
+            }
+        });
+    }
+    if checks.is_empty() {
+        checks.push(Check::new("proxies", Status::Skip, "no active orders recorded locally"));
+    }
+    checks
+}
+
+/// Plain-text report for the terminal.
+pub fn render(report: &Value) -> String {
+    let mut out = String::new();
+    for check in report["checks"].as_array().map(|c| c.as_slice()).unwrap_or_default() {
+        let status = check["status"].as_str().unwrap_or_default().to_ascii_uppercase();
+        out.push_str(&format!(
+            "[{:<4}] {:<12} {}\n",
+            status,
+            check["name"].as_str().unwrap_or_default(),
+            check["detail"].as_str().unwrap_or_default()
+        ));
+        if let Some(hint) = check["hint"].as_str() {
+            out.push_str(&format!("{:20}→ {}\n", "", hint));
+        }
+    }
+    out.push_str(&format!("\n{}\n", report["summary"].as_str().unwrap_or_default()));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_check() {
+        let limit = Duration::from_secs(60);
+        assert_eq!(check_clock(Some(1_000), 1_030, limit).status, Status::Pass);
+        let check = check_clock(Some(1_000), 880, limit);
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("120s behind"), "{}", check.detail);
+        assert!(check.hint.is_some());
+        assert_eq!(check_clock(None, 1_000, limit).status, Status::Skip);
+    }
+
+    #[test]
+    fn test_clock_skew_does_not_fail_the_backend() {
+        let mut checks = vec![
+            Check::new("api_url", Status::Pass, "http://127.0.0.1"),
+            Check::new("reachable", Status::Pass, "connected"),
+            Check::new("tls", Status::Warn, "plain http"),
+            check_clock(Some(1_000), 5_000, DEFAULT_MAX_CLOCK_SKEW),
+        ];
+        assert_eq!(checks[3].status, Status::Fail);
+        assert!(backend_ok(&checks));
+
+        checks[1] = Check::new("reachable", Status::Fail, "cannot connect");
+        assert!(!backend_ok(&checks));
+    }
+
+    #[test]
+    fn test_api_key_check() {
+        assert_eq!(check_api_key(Ok(json!({"packages": [{"id": "a"}]}))).detail, "accepted by list_packages (1 packages)");
+        let check = check_api_key(Err("API error (401 Unauthorized): {}".to_string()));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.hint.unwrap().contains("register"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_check() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("proxybase-doctor-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let client_config = dir.join("mcp.json");
+        std::fs::write(&client_config, r#"{"mcpServers": {"proxybase": {"env": {"PROXYBASE_API_KEY": "pk_x"}}}}"#).unwrap();
+        let set_mode = |path: &Path, mode| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+
+        set_mode(&dir, 0o755);
+        set_mode(&client_config, 0o644);
+        let config_file = dir.join("config.toml");
+        std::fs::write(&config_file, "[profiles.prod]\napi_key = \"pk_live_0123456789abcdef\"\n").unwrap();
+        set_mode(&config_file, 0o644);
+        let check = check_permissions(Some(&dir), Some(&config_file), std::slice::from_ref(&client_config));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("config.toml holds the API key or webhook secret"), "{}", check.detail);
+        assert!(check.detail.contains("mcp.json holds the API key"), "{}", check.detail);
+        assert!(check.hint.unwrap().starts_with("chmod 700"));
+
+        set_mode(&dir, 0o700);
+        set_mode(&client_config, 0o600);
+        set_mode(&config_file, 0o600);
+        assert_eq!(check_permissions(Some(&dir), Some(&config_file), &[client_config]).status, Status::Pass);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,280 @@
+//! HTTP requests through an order's proxy on behalf of the agent.
+//!
+//! Each fetch runs through a private, short-lived [`Gateway`] so the bytes
+//! that actually crossed the proxy (TLS and protocol overhead included,
+//! which is what the order is billed for) can be reported per call.
+
+use crate::gateway::{Gateway, Route};
+use crate::models::ProxyCredentials;
+use serde_json::{json, Map, Value};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_BODY_BYTES: usize = 256 * 1024;
+/// Upper bound for `max_body_bytes`, so a single call cannot flood the
+/// agent's context.
+pub const MAX_BODY_BYTES_LIMIT: usize = 4 * 1024 * 1024;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Upper bound for `timeout_secs`, so a call cannot hang a tool for long.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone)]
+pub struct FetchRequest {
+    pub url: reqwest::Url,
+    pub method: reqwest::Method,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+    pub max_body_bytes: usize,
+    pub timeout: Duration,
+}
+
+impl FetchRequest {
+    /// Build a request from tool arguments.
+    pub fn from_args(args: &Value) -> Result<Self, String> {
+        let raw_url = args
+            .get("url")
+            .and_then(|v| v.as_str())
+            .ok_or("Missing required argument: url")?;
+        let url = reqwest::Url::parse(raw_url).map_err(|e| format!("Invalid url '{}': {}", raw_url, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Invalid url '{}': only http:// and https:// are supported", raw_url));
+        }
+
+        let method_name = args.get("method").and_then(|v| v.as_str()).unwrap_or("GET");
+        let method = reqwest::Method::from_bytes(method_name.to_ascii_uppercase().as_bytes())
+            .map_err(|_| format!("Invalid method: '{}'", method_name))?;
+
+        let headers = match args.get("headers") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Object(map)) => map
+                .iter()
+                .map(|(name, value)| match value.as_str() {
+                    Some(v) => Ok((name.clone(), v.to_string())),
+                    None => Err(format!("Header '{}' must be a string", name)),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("headers must be an object of name → value".to_string()),
+        };
+
+        let max_body_bytes = args
+            .get("max_body_bytes")
+            .and_then(|v| v.as_u64())
+            .map(|n| (n as usize).clamp(1, MAX_BODY_BYTES_LIMIT))
+            .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        let timeout = args
+            .get("timeout_secs")
+            .and_then(|v| v.as_u64())
+            .map(|secs| Duration::from_secs(secs.clamp(1, MAX_TIMEOUT.as_secs())))
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        Ok(Self {
+            url,
+            method,
+            headers,
+            body: args.get("body").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            max_body_bytes,
+            timeout,
+        })
+    }
+}
+
+/// Perform `req` through `proxy` and describe the response.
+pub async fn fetch(order_id: &str, proxy: &ProxyCredentials, req: &FetchRequest) -> Result<Value, String> {
+    let route = Route::Order {
+        order_id: order_id.to_string(),
+        proxy: proxy.clone(),
+    };
+    let gateway = Gateway::bind("127.0.0.1:0".parse().unwrap(), route).await?;
+    let started = Instant::now();
+    let result = send(&gateway, req).await;
+    gateway.stop("fetch finished");
+
+    let mut result = result?;
+    let snapshot = gateway.snapshot();
+    result["elapsed_ms"] = json!(started.elapsed().as_millis() as u64);
+    result["bytes"] = json!({
+        "sent": snapshot["bytes_up"],
+        "received": snapshot["bytes_down"],
+        "total": snapshot["bytes_total"]
+    });
+    Ok(result)
+}
+
+async fn send(gateway: &Gateway, req: &FetchRequest) -> Result<Value, String> {
+    let proxy = reqwest::Proxy::all(format!("socks5h://{}", gateway.listen))
+        .map_err(|e| format!("invalid proxy: {}", e))?;
+    let client = reqwest::Client::builder()
+        .proxy(proxy)
+        .timeout(req.timeout)
+        .build()
+        .map_err(|e| format!("failed to build proxy client: {}", e))?;
+
+    let mut builder = client.request(req.method.clone(), req.url.clone());
+    for (name, value) in &req.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = &req.body {
+        builder = builder.body(body.clone());
+    }
+
+    let mut resp = builder
+        .send()
+        .await
+        .map_err(|e| format!("Request through proxy failed: {}", e))?;
+
+    let status = resp.status();
+    let final_url = resp.url().to_string();
+    let mut headers = Map::new();
+    for (name, value) in resp.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        match headers.get_mut(name.as_str()) {
+            Some(Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            _ => {
+                headers.insert(name.as_str().to_string(), json!(value));
+            }
+        }
+    }
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    // Stop reading at the cap; the rest is never transferred
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| format!("Reading response body failed: {}", e))?
+    {
+        let room = req.max_body_bytes - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            truncated = true;
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    drop(resp);
+
+    let mut result = json!({
+        "status": status.as_u16(),
+        "url": final_url,
+        "headers": headers,
+        "content_type": content_type,
+        "body_bytes": body.len(),
+        "truncated": truncated
+    });
+    if is_text(&content_type) || (content_type.is_empty() && std::str::from_utf8(&body).is_ok()) {
+        result["body"] = json!(decode_text(&body, charset(&content_type)));
+    } else {
+        result["body"] = Value::Null;
+        result["note"] = json!(format!("Binary body ({}) not shown", content_type));
+    }
+    Ok(result)
+}
+
+fn is_text(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-www-form-urlencoded"
+        )
+}
+
+fn charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_ascii_lowercase())
+    })
+}
+
+/// Decode a body as UTF-8 (lossily) or Latin-1; other charsets are treated
+/// as UTF-8.
+fn decode_text(body: &[u8], charset: Option<String>) -> String {
+    match charset.as_deref() {
+        Some("iso-8859-1" | "latin1" | "us-ascii") => body.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::test_support;
+    use crate::socks5::test_server;
+
+    #[test]
+    fn test_request_from_args() {
+        let req = FetchRequest::from_args(&json!({
+            "url": "https://example.com/a?b=c",
+            "method": "post",
+            "headers": {"Accept": "application/json"},
+            "body": "{}"
+        }))
+        .unwrap();
+        assert_eq!(req.method, reqwest::Method::POST);
+        assert_eq!(req.headers, vec![("Accept".to_string(), "application/json".to_string())]);
+        assert_eq!(req.max_body_bytes, DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(req.timeout, DEFAULT_TIMEOUT);
+
+        let timeout = |secs: u64| FetchRequest::from_args(&json!({"url": "https://x", "timeout_secs": secs})).unwrap().timeout;
+        assert_eq!(timeout(0), Duration::from_secs(1));
+        assert_eq!(timeout(u64::MAX), MAX_TIMEOUT);
+
+        assert!(FetchRequest::from_args(&json!({"url": "ftp://example.com"})).is_err());
+        assert!(FetchRequest::from_args(&json!({"url": "https://x", "method": "GE T"})).is_err());
+        assert!(FetchRequest::from_args(&json!({"url": "https://x", "headers": {"a": 1}})).is_err());
+    }
+
+    #[test]
+    fn test_decoding() {
+        assert!(is_text("text/html; charset=utf-8"));
+        assert!(is_text("application/ld+json"));
+        assert!(!is_text("image/png"));
+        assert_eq!(charset("text/html; Charset=\"ISO-8859-1\""), Some("iso-8859-1".to_string()));
+        assert_eq!(decode_text(&[0x63, 0x61, 0x66, 0xe9], Some("iso-8859-1".to_string())), "café");
+        assert_eq!(decode_text("café".as_bytes(), None), "café");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_through_proxy_counts_bytes() {
+        let proxy = test_server::spawn("pb_user", "pw").await;
+        let echo = test_support::spawn_echo("203.0.113.7").await;
+        let creds = ProxyCredentials {
+            host: proxy.ip().to_string(),
+            port: proxy.port(),
+            username: "pb_user".to_string(),
+            password: "pw".to_string(),
+        };
+
+        let req = FetchRequest::from_args(&json!({"url": format!("http://{}/ip", echo)})).unwrap();
+        let result = fetch("kQx7p3Wn", &creds, &req).await.unwrap();
+        assert_eq!(result["status"], 200);
+        assert_eq!(result["body"], "{\"ip\":\"203.0.113.7\"}");
+        assert_eq!(result["truncated"], false);
+        assert!(result["bytes"]["sent"].as_u64().unwrap() > 0);
+        assert!(result["bytes"]["received"].as_u64().unwrap() > result["body_bytes"].as_u64().unwrap());
+
+        let req = FetchRequest::from_args(&json!({
+            "url": format!("http://{}/ip", echo),
+            "max_body_bytes": 5
+        }))
+        .unwrap();
+        let result = fetch("kQx7p3Wn", &creds, &req).await.unwrap();
+        assert_eq!(result["body"], "{\"ip\"");
+        assert_eq!(result["truncated"], true);
+    }
+}
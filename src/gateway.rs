@@ -0,0 +1,674 @@
+//! Local forwarding gateway to a purchased proxy.
+//!
+//! Many tools cannot do authenticated SOCKS5, so the gateway listens on
+//! loopback without authentication, speaking SOCKS5 and HTTP proxy
+//! (`CONNECT` plus absolute-form requests), and chains every connection
+//! through the order's upstream proxy with its credentials. Traffic is
+//! counted locally and the gateway stops once the order runs out of
+//! bandwidth. A gateway can also route to a [`ProxyPool`] of orders.
+
+use crate::http1;
+use crate::models::{OrderStatus, ProxyCredentials};
+use crate::notify::Notifier;
+use crate::order_id::OrderId;
+use crate::pool::{PoolMember, ProxyPool, Strategy};
+use crate::socks5;
+use crate::util::unix_now;
+use crate::ProxyBaseClient;
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// How often the monitor asks the backend for the order's usage.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+const RELAY_BUFFER: usize = 16 * 1024;
+/// Time the upstream proxy gets to accept a connection and open the tunnel.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time a client gets to say where it wants to go.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a gateway sends its connections.
+pub enum Route {
+    Order {
+        order_id: String,
+        proxy: ProxyCredentials,
+    },
+    Pool {
+        pool: Arc<ProxyPool>,
+        strategy: Strategy,
+    },
+}
+
+/// An open tunnel and the pool member carrying it, if any.
+struct Upstream {
+    stream: TcpStream,
+    member: Option<Arc<PoolMember>>,
+}
+
+pub struct Gateway {
+    pub listen: SocketAddr,
+    route: Route,
+    turn: AtomicUsize,
+    started_at: u64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    connections: AtomicU64,
+    active: AtomicU64,
+    shutdown: watch::Sender<bool>,
+    stop_reason: Mutex<Option<String>>,
+}
+
+impl Gateway {
+    /// Bind a loopback listener and start accepting connections.
+    pub async fn bind(listen: SocketAddr, route: Route) -> Result<Arc<Self>, String> {
+        if !listen.ip().is_loopback() {
+            return Err(format!(
+                "Refusing to expose an unauthenticated gateway on {}; bind to 127.0.0.1 instead",
+                listen
+            ));
+        }
+        let listener = TcpListener::bind(listen)
+            .await
+            .map_err(|e| format!("Failed to bind gateway on {}: {}", listen, e))?;
+        let listen = listener.local_addr().map_err(|e| e.to_string())?;
+
+        let gateway = Arc::new(Self {
+            listen,
+            route,
+            turn: AtomicUsize::new(0),
+            started_at: unix_now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            shutdown: watch::channel(false).0,
+            stop_reason: Mutex::new(None),
+        });
+        tokio::spawn(gateway.clone().accept_loop(listener));
+        Ok(gateway)
+    }
+
+    /// Stop accepting connections and close the open ones. The first reason
+    /// given is kept.
+    pub fn stop(&self, reason: &str) {
+        let mut stop_reason = self.stop_reason.lock().unwrap();
+        if stop_reason.is_none() {
+            log::info!("Gateway {} stopped: {}", self.listen, reason);
+            *stop_reason = Some(reason.to_string());
+        }
+        self.shutdown.send_replace(true);
+    }
+
+    /// The order this gateway forwards to, unless it routes to a pool.
+    pub fn order_id(&self) -> Option<&str> {
+        match &self.route {
+            Route::Order { order_id, .. } => Some(order_id),
+            Route::Pool { .. } => None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !*self.shutdown.borrow()
+    }
+
+    /// Resolves once the gateway has been stopped.
+    pub async fn stopped(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|stopped| *stopped).await;
+    }
+
+    pub fn bytes_total(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed) + self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let mut snapshot = json!({
+            "listen": self.listen.to_string(),
+            "socks5_url": format!("socks5h://{}", self.listen),
+            "http_proxy_url": format!("http://{}", self.listen),
+            "running": self.is_running(),
+            "stop_reason": *self.stop_reason.lock().unwrap(),
+            "started_at": self.started_at,
+            "bytes_up": self.bytes_up.load(Ordering::Relaxed),
+            "bytes_down": self.bytes_down.load(Ordering::Relaxed),
+            "bytes_total": self.bytes_total(),
+            "connections": self.connections.load(Ordering::Relaxed),
+            "active_connections": self.active.load(Ordering::Relaxed)
+        });
+        match &self.route {
+            Route::Order { order_id, proxy } => {
+                snapshot["order_id"] = json!(order_id);
+                snapshot["upstream"] = json!(proxy.addr());
+            }
+            Route::Pool { pool, strategy } => {
+                snapshot["pool"] = json!(true);
+                snapshot["strategy"] = json!(strategy.as_str());
+                if let Strategy::Country(country) = strategy {
+                    snapshot["country"] = json!(country);
+                }
+                snapshot["healthy_members"] = pool.snapshot()["healthy"].clone();
+            }
+        }
+        snapshot
+    }
+
+    async fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        let gateway = self.clone();
+                        tokio::spawn(async move {
+                            gateway.connections.fetch_add(1, Ordering::Relaxed);
+                            gateway.active.fetch_add(1, Ordering::Relaxed);
+                            if let Err(e) = gateway.handle(stream).await {
+                                log::debug!("Gateway connection from {} failed: {}", peer, e);
+                            }
+                            gateway.active.fetch_sub(1, Ordering::Relaxed);
+                        });
+                    }
+                    Err(e) => {
+                        self.stop(&format!("listener failed: {}", e));
+                        return;
+                    }
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => return,
+            }
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let mut client = BufReader::new(stream);
+        let first = from_client(async { Ok(client.fill_buf().await?.first().copied()) }).await?;
+        match first {
+            Some(socks5::VERSION) => self.handle_socks(client).await,
+            Some(_) => self.handle_http(client).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_socks(&self, mut client: BufReader<TcpStream>) -> io::Result<()> {
+        let (host, port) = from_client(async {
+            socks5::accept_no_auth(&mut client).await?;
+            socks5::read_connect_request(&mut client).await
+        })
+        .await?;
+
+        match self.open_upstream(&host, port).await {
+            Ok(upstream) => {
+                client
+                    .write_all(&socks5::encode_reply(socks5::REPLY_SUCCEEDED, None))
+                    .await?;
+                self.relay(client, upstream).await
+            }
+            Err(e) => {
+                client
+                    .write_all(&socks5::encode_reply(socks5::REPLY_GENERAL_FAILURE, None))
+                    .await?;
+                Err(io::Error::other(e))
+            }
+        }
+    }
+
+    async fn handle_http(&self, mut client: BufReader<TcpStream>) -> io::Result<()> {
+        let head = from_client(http1::read_head(&mut client)).await?;
+
+        if head.method.eq_ignore_ascii_case("CONNECT") {
+            let Some((host, port)) = split_host_port(&head.target) else {
+                return http_error(&mut client, 400, "Bad Request", "CONNECT target must be host:port").await;
+            };
+            return match self.open_upstream(&host, port).await {
+                Ok(upstream) => {
+                    client
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                        .await?;
+                    self.relay(client, upstream).await
+                }
+                Err(e) => http_error(&mut client, 502, "Bad Gateway", &e).await,
+            };
+        }
+
+        // Plain HTTP proxying: absolute-form request, forwarded in origin-form
+        let url = match reqwest::Url::parse(&head.target) {
+            Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+            _ => {
+                let msg = "This is a proxy gateway: use CONNECT, SOCKS5 or an absolute http:// URL";
+                return http_error(&mut client, 400, "Bad Request", msg).await;
+            }
+        };
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let mut upstream = match self.open_upstream(&host, port).await {
+            Ok(upstream) => upstream,
+            Err(e) => return http_error(&mut client, 502, "Bad Gateway", &e).await,
+        };
+
+        let request = origin_form_request(&head, &url);
+        upstream.stream.write_all(request.as_bytes()).await?;
+        for counter in counters(&self.bytes_up, upstream.member.as_ref()) {
+            counter.fetch_add(request.len() as u64, Ordering::Relaxed);
+        }
+        self.relay(client, upstream).await
+    }
+
+    async fn open_upstream(&self, host: &str, port: u16) -> Result<Upstream, String> {
+        let (pool, strategy) = match &self.route {
+            Route::Order { proxy, .. } => {
+                let stream = connect_via(proxy, host, port).await.map_err(|e| e.to_string())?;
+                return Ok(Upstream { stream, member: None });
+            }
+            Route::Pool { pool, strategy } => (pool, strategy),
+        };
+
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        let candidates = pool.candidates(strategy, host, turn);
+        if candidates.is_empty() {
+            return Err("No healthy upstream in the proxy pool".to_string());
+        }
+
+        let mut last_error = String::new();
+        for member in candidates {
+            match connect_via(&member.proxy, host, port).await {
+                Ok(stream) => {
+                    pool.report_success(&member);
+                    return Ok(Upstream { stream, member: Some(member) });
+                }
+                // The destination refusing is not the member's fault
+                Err(e) if e.stage == socks5::Stage::Request => return Err(e.to_string()),
+                Err(e) => {
+                    last_error = format!("{}: {}", member.order_id, e);
+                    pool.report_failure(&member, &e.to_string());
+                }
+            }
+        }
+        Err(format!("All pool upstreams failed, last: {}", last_error))
+    }
+
+    /// Copy both directions until each side closes or the gateway stops.
+    async fn relay(&self, client: BufReader<TcpStream>, upstream: Upstream) -> io::Result<()> {
+        let Upstream { stream, member } = upstream;
+        let up = counters(&self.bytes_up, member.as_ref());
+        let down = counters(&self.bytes_down, member.as_ref());
+        let pending = client.buffer().to_vec();
+        let (mut client_rd, mut client_wr) = client.into_inner().into_split();
+        let (mut upstream_rd, mut upstream_wr) = stream.into_split();
+
+        if !pending.is_empty() {
+            upstream_wr.write_all(&pending).await?;
+            for counter in &up {
+                counter.fetch_add(pending.len() as u64, Ordering::Relaxed);
+            }
+        }
+
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::select! {
+            result = async {
+                tokio::try_join!(
+                    pump(&mut client_rd, &mut upstream_wr, &up),
+                    pump(&mut upstream_rd, &mut client_wr, &down),
+                )
+            } => result.map(|_| ()),
+            _ = shutdown.wait_for(|stopped| *stopped) => Ok(()),
+        }
+    }
+}
+
+/// Run a step of the client's handshake, dropping clients that stall.
+async fn from_client<T>(step: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(CLIENT_TIMEOUT, step)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "client sent no request in time")))
+}
+
+/// The gateway-wide counter plus the pool member's, if any.
+fn counters<'a>(gateway: &'a AtomicU64, member: Option<&'a Arc<PoolMember>>) -> Vec<&'a AtomicU64> {
+    let mut counters = vec![gateway];
+    counters.extend(member.map(|m| m.bytes()));
+    counters
+}
+
+async fn connect_via(
+    proxy: &ProxyCredentials,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, socks5::Socks5Error> {
+    socks5::connect(&proxy.addr(), Some((&proxy.username, &proxy.password)), host, port, UPSTREAM_TIMEOUT).await
+}
+
+/// Poll the backend and stop `gateway` once the order is exhausted. Between
+/// polls the local byte count is compared with the last known remaining
+/// bandwidth so the gateway stops promptly.
+pub fn spawn_monitor(
+    gateway: Arc<Gateway>,
+    client: ProxyBaseClient,
+    api_key: String,
+    order_id: OrderId,
+    initial: OrderStatus,
+    interval: Duration,
+    notifier: Notifier,
+) {
+    tokio::spawn(async move {
+        let mut remaining = initial.remaining_bytes;
+        let mut baseline = gateway.bytes_total();
+        let mut last_poll = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                _ = gateway.stopped() => return,
+            }
+
+            let local_exhausted =
+                remaining.is_some_and(|rem| gateway.bytes_total().saturating_sub(baseline) >= rem);
+            let mut reason = local_exhausted
+                .then_some("local byte count reached the order's remaining bandwidth");
+
+            if reason.is_none() && last_poll.elapsed() >= interval {
+                last_poll = Instant::now();
+                match client
+                    .check_order_status(&api_key, &order_id)
+                    .await
+                    .and_then(|v| OrderStatus::from_value(&v))
+                {
+                    Ok(status) if status.is_exhausted() => reason = Some("order bandwidth exhausted"),
+                    Ok(status) => {
+                        remaining = status.remaining_bytes;
+                        baseline = gateway.bytes_total();
+                    }
+                    Err(e) => log::warn!("Gateway monitor for {} could not poll status: {}", order_id, e),
+                }
+            }
+
+            if let Some(reason) = reason {
+                gateway.stop(reason);
+                notifier.log("warning", "proxybase.gateway", gateway.snapshot());
+                return;
+            }
+        }
+    });
+}
+
+/// Running gateways, as managed through the MCP tools.
+#[derive(Default)]
+pub struct GatewayRegistry {
+    gateways: Mutex<Vec<Arc<Gateway>>>,
+}
+
+impl GatewayRegistry {
+    pub fn add(&self, gateway: Arc<Gateway>) {
+        self.gateways.lock().unwrap().push(gateway);
+    }
+
+    pub fn list(&self) -> Vec<Arc<Gateway>> {
+        self.gateways.lock().unwrap().clone()
+    }
+
+    /// Connections accepted so far by the gateways for an order.
+    pub fn connections_for(&self, order_id: &str) -> u64 {
+        self.list()
+            .iter()
+            .filter(|g| g.order_id() == Some(order_id))
+            .map(|g| g.connections.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Stop and remove every gateway matching `pred`.
+    pub fn remove(&self, pred: impl Fn(&Gateway) -> bool) -> Vec<Arc<Gateway>> {
+        let mut gateways = self.gateways.lock().unwrap();
+        let (removed, kept) = gateways.drain(..).partition(|g| pred(g));
+        *gateways = kept;
+        for gateway in &removed {
+            gateway.stop("stopped by request");
+        }
+        removed
+    }
+}
+
+async fn pump<R, W>(reader: &mut R, writer: &mut W, counters: &[&AtomicU64]) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        for counter in counters {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn http_error(
+    client: &mut BufReader<TcpStream>,
+    status: u16,
+    reason: &str,
+    message: &str,
+) -> io::Result<()> {
+    http1::write_response(client.get_mut(), status, reason, "text/plain", message.as_bytes()).await
+}
+
+/// Split a `host:port` authority, accepting bracketed IPv6 literals.
+fn split_host_port(target: &str) -> Option<(String, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Rewrite an absolute-form proxy request for the origin server, dropping
+/// hop-by-hop proxy headers and forcing one request per connection.
+fn origin_form_request(head: &http1::RequestHead, url: &reqwest::Url) -> String {
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let mut request = format!("{} {} HTTP/1.1\r\n", head.method, path);
+    if head.header("Host").is_none() {
+        request.push_str(&format!("Host: {}\r\n", url.authority()));
+    }
+    for (name, value) in &head.headers {
+        let lower = name.to_ascii_lowercase();
+        if matches!(
+            lower.as_str(),
+            "proxy-connection" | "proxy-authorization" | "connection" | "keep-alive"
+        ) {
+            continue;
+        }
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::test_support;
+    use crate::socks5::test_server;
+
+    async fn start(password: &'static str) -> (Arc<Gateway>, SocketAddr) {
+        let proxy = test_server::spawn("pb_user", password).await;
+        let creds = ProxyCredentials {
+            host: proxy.ip().to_string(),
+            port: proxy.port(),
+            username: "pb_user".to_string(),
+            password: password.to_string(),
+        };
+        let route = Route::Order {
+            order_id: "kQx7p3Wn".to_string(),
+            proxy: creds,
+        };
+        let gateway = Gateway::bind("127.0.0.1:0".parse().unwrap(), route).await.unwrap();
+        let echo = test_support::spawn_echo("203.0.113.7").await;
+        (gateway, echo)
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("example.com:443"), Some(("example.com".to_string(), 443)));
+        assert_eq!(split_host_port("[::1]:8080"), Some(("::1".to_string(), 8080)));
+        assert_eq!(split_host_port("example.com"), None);
+        assert_eq!(split_host_port(":80"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_non_loopback_bind() {
+        let creds = ProxyCredentials {
+            host: "127.0.0.1".to_string(),
+            port: 1080,
+            username: "u".to_string(),
+            password: "p".to_string(),
+        };
+        let route = Route::Order {
+            order_id: "x".to_string(),
+            proxy: creds,
+        };
+        let err = Gateway::bind("0.0.0.0:0".parse().unwrap(), route)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.contains("Refusing"));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_client_through_gateway() {
+        let (gateway, echo) = start("pw").await;
+
+        let mut stream = socks5::connect(&gateway.listen.to_string(), None, "127.0.0.1", echo.port(), UPSTREAM_TIMEOUT)
+            .await
+            .unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+
+        assert!(resp.contains("203.0.113.7"), "{}", resp);
+        assert!(gateway.bytes_total() > 0);
+        assert_eq!(gateway.snapshot()["connections"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_and_absolute_form() {
+        let (gateway, echo) = start("pw").await;
+
+        // CONNECT tunnel
+        let mut stream = TcpStream::connect(gateway.listen).await.unwrap();
+        let connect = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo);
+        stream.write_all(connect.as_bytes()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 Connection Established"), "{}", resp);
+        assert!(resp.contains("203.0.113.7"));
+
+        // Plain proxy request via reqwest
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://{}", gateway.listen)).unwrap())
+            .build()
+            .unwrap();
+        let body = client
+            .get(format!("http://{}/ip", echo))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains("203.0.113.7"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_auth_failure_is_reported() {
+        let proxy = test_server::spawn("pb_user", "right").await;
+        let creds = ProxyCredentials {
+            host: proxy.ip().to_string(),
+            port: proxy.port(),
+            username: "pb_user".to_string(),
+            password: "wrong".to_string(),
+        };
+        let route = Route::Order {
+            order_id: "x".to_string(),
+            proxy: creds,
+        };
+        let gateway = Gateway::bind("127.0.0.1:0".parse().unwrap(), route).await.unwrap();
+
+        let mut stream = TcpStream::connect(gateway.listen).await.unwrap();
+        stream.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 502"), "{}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_pool_fails_over_and_ejects_dead_member() {
+        let live = test_server::spawn("pb_user", "pw").await;
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let echo = test_support::spawn_echo("203.0.113.9").await;
+
+        let pool = Arc::new(ProxyPool::new(Notifier::default()));
+        for (id, addr) in [("dead", dead), ("live", live)] {
+            let creds = ProxyCredentials {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+                username: "pb_user".to_string(),
+                password: "pw".to_string(),
+            };
+            pool.add(PoolMember::new(OrderId::parse(id).unwrap(), "pk_test", creds, None, None));
+        }
+        let route = Route::Pool {
+            pool: pool.clone(),
+            strategy: Strategy::RoundRobin,
+        };
+        let gateway = Gateway::bind("127.0.0.1:0".parse().unwrap(), route).await.unwrap();
+
+        for _ in 0..6 {
+            let mut stream = socks5::connect(&gateway.listen.to_string(), None, "127.0.0.1", echo.port(), UPSTREAM_TIMEOUT)
+                .await
+                .unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.contains("203.0.113.9"));
+        }
+
+        let snapshot = pool.snapshot();
+        assert_eq!(snapshot["healthy"], 1);
+        assert_eq!(snapshot["members"][0]["healthy"], false);
+        assert!(snapshot["members"][1]["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(gateway.snapshot()["strategy"], "round_robin");
+    }
+
+    #[tokio::test]
+    async fn test_stop_closes_listener() {
+        let (gateway, _) = start("pw").await;
+        let registry = GatewayRegistry::default();
+        registry.add(gateway.clone());
+
+        let removed = registry.remove(|g| g.order_id() == Some("kQx7p3Wn"));
+        assert_eq!(removed.len(), 1);
+        assert!(!gateway.is_running());
+        assert_eq!(gateway.snapshot()["stop_reason"], "stopped by request");
+        assert!(registry.list().is_empty());
+
+        tokio::time::timeout(Duration::from_secs(1), gateway.stopped())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+//! Offline country lookup from a MaxMind DB file (GeoLite2-Country,
+//! GeoIP2-Country or -City, or any compatible database).
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| format!("Failed to open GeoIP database {}: {}", path.display(), e))?;
+        Ok(Self { reader })
+    }
+
+    /// Lowercase ISO 3166-1 alpha-2 code of the country `ip` is located in,
+    /// or `None` if the database has no entry for it.
+    pub fn country(&self, ip: IpAddr) -> Result<Option<String>, String> {
+        match self.reader.lookup::<geoip2::Country>(ip) {
+            Ok(record) => Ok(record
+                .country
+                .and_then(|c| c.iso_code)
+                .map(|code| code.to_lowercase())),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+            Err(e) => Err(format!("GeoIP lookup for {} failed: {}", ip, e)),
+        }
+    }
+
+    pub fn database_type(&self) -> &str {
+        &self.reader.metadata.database_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_missing_file() {
+        let err = GeoIp::open(Path::new("/nonexistent/GeoLite2-Country.mmdb")).err().unwrap();
+        assert!(err.contains("GeoLite2-Country.mmdb"), "{}", err);
+    }
+}
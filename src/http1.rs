@@ -0,0 +1,209 @@
+//! Just enough HTTP/1.1 to serve the embedded listeners.
+//!
+//! Only request heads and `Content-Length` bodies are supported; anything
+//! else is answered with an error by the caller.
+
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound for the request line plus headers.
+pub const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Case-insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Read a request line and headers, leaving any body in `reader`.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<RequestHead> {
+    let mut total = 0;
+    let mut lines = Vec::new();
+
+    loop {
+        let mut line = Vec::new();
+        let n = reader.read_until(b'\n', &mut line).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        total += n;
+        if total > MAX_HEAD_BYTES {
+            return Err(invalid("request head too large"));
+        }
+
+        let line = String::from_utf8(line).map_err(|_| invalid("request head is not UTF-8"))?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if lines.is_empty() {
+                continue; // tolerate leading blank lines
+            }
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut request_line = lines[0].split_whitespace();
+    let (Some(method), Some(target), Some(_version)) =
+        (request_line.next(), request_line.next(), request_line.next())
+    else {
+        return Err(invalid("malformed request line"));
+    };
+
+    let headers = lines[1..]
+        .iter()
+        .map(|l| {
+            l.split_once(':')
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .ok_or_else(|| invalid("malformed header"))
+        })
+        .collect::<io::Result<_>>()?;
+
+    Ok(RequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+    })
+}
+
+/// Read a `Content-Length` body of at most `max` bytes.
+pub async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    head: &RequestHead,
+    max: usize,
+) -> io::Result<Vec<u8>> {
+    if head.header("Transfer-Encoding").is_some() {
+        return Err(invalid("chunked bodies are not supported"));
+    }
+    let len: usize = match head.header("Content-Length") {
+        Some(v) => v.parse().map_err(|_| invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    if len > max {
+        return Err(invalid("body too large"));
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+pub mod test_support {
+    //! A stand-in for the ProxyBase backend: every request is answered with
+    //! the JSON chosen by a handler.
+
+    use super::*;
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    /// Serve `handler(method, target, body)` and return the base URL.
+    pub async fn spawn_backend<F>(handler: F) -> String
+    where
+        F: Fn(&str, &str, Value) -> (u16, Value) + Send + Sync + 'static,
+    {
+        spawn_backend_with_head(move |head, body| handler(&head.method, &head.target, body)).await
+    }
+
+    /// [`spawn_backend`] for handlers that need the request headers.
+    pub async fn spawn_backend_with_head<F>(handler: F) -> String
+    where
+        F: Fn(&RequestHead, Value) -> (u16, Value) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let Ok(head) = read_head(&mut reader).await else {
+                        return;
+                    };
+                    let body = read_body(&mut reader, &head, MAX_HEAD_BYTES).await.unwrap_or_default();
+                    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                    let (status, response) = handler(&head, body);
+                    let _ = write_response(
+                        reader.get_mut(),
+                        status,
+                        "Status",
+                        "application/json",
+                        response.to_string().as_bytes(),
+                    )
+                    .await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_read_head_and_body() {
+        let raw = b"POST /webhook HTTP/1.1\r\nHost: x\r\ncontent-length: 5\r\n\r\nhello";
+        let mut reader = BufReader::new(&raw[..]);
+        let head = read_head(&mut reader).await.unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.target, "/webhook");
+        assert_eq!(head.header("Content-Length"), Some("5"));
+
+        let body = read_body(&mut reader, &head, 1024).await.unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_oversized_body() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let head = read_head(&mut reader).await.unwrap();
+        assert!(read_body(&mut reader, &head, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_malformed_request_line() {
+        let mut reader = BufReader::new(&b"GARBAGE\r\n\r\n"[..]);
+        assert!(read_head(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Durable inbox of order events received through webhooks.
+//!
+//! Events are appended to a JSON Lines file in the data directory and kept in
+//! memory for querying. Each event gets a monotonically increasing `seq`
+//! that agents use as a cursor; redeliveries are dropped by `event_id` when
+//! the sender supplies one.
+
+use crate::jsonl::Journal;
+use crate::util::unix_now;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Events kept on disk and in memory; older ones are compacted away.
+pub const MAX_EVENTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderEvent {
+    /// Position in the inbox, assigned when the event is recorded.
+    #[serde(default)]
+    pub seq: u64,
+    pub event_id: String,
+    /// Whether `event_id` came from the sender. Only such ids identify
+    /// redeliveries; other events get `local-<seq>` when recorded.
+    #[serde(skip)]
+    pub supplied_id: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    pub event: String,
+    /// Unix timestamp (seconds) at which the callback was received.
+    pub received_at: u64,
+    pub payload: Value,
+}
+
+impl OrderEvent {
+    /// Build an event from a callback body. Without an explicit id, two
+    /// identical bodies may be two real events (a second `bandwidth_80` after
+    /// a top-up), so they are not treated as redeliveries.
+    pub fn from_payload(payload: Value) -> Result<Self, String> {
+        if !payload.is_object() {
+            return Err("callback body must be a JSON object".to_string());
+        }
+        let field = |keys: &[&str]| {
+            keys.iter().find_map(|k| match payload.get(*k) {
+                Some(Value::String(s)) => Some(s.clone()),
+                Some(Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            })
+        };
+
+        let event = field(&["event", "type", "status"]).unwrap_or_else(|| "unknown".to_string());
+        let order_id = field(&["order_id"]);
+        let event_id = field(&["event_id", "id"]);
+
+        Ok(Self {
+            seq: 0,
+            supplied_id: event_id.is_some(),
+            event_id: event_id.unwrap_or_default(),
+            order_id,
+            event,
+            received_at: unix_now(),
+            payload,
+        })
+    }
+}
+
+#[derive(Default)]
+struct InboxState {
+    seen: HashSet<String>,
+    next_seq: u64,
+}
+
+pub struct EventInbox {
+    events: Journal<OrderEvent>,
+    /// Locked around each journal push so ids and `seq` stay in step with it.
+    state: Mutex<InboxState>,
+}
+
+impl EventInbox {
+    pub fn in_memory() -> Self {
+        Self::with_events(Journal::in_memory(MAX_EVENTS))
+    }
+
+    /// Open (or create) the inbox file at `path`, loading existing events.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        Self::with_events(Journal::open(path, MAX_EVENTS))
+    }
+
+    fn with_events(events: Journal<OrderEvent>) -> Self {
+        let state = {
+            let records = events.records();
+            InboxState {
+                seen: records.iter().map(|e| e.event_id.clone()).collect(),
+                next_seq: records.last().map(|e| e.seq + 1).unwrap_or(1),
+            }
+        };
+        Self {
+            events,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Store an event, returning it with its assigned `seq`, or `None` if an
+    /// event with the same sender-supplied id was already recorded.
+    pub fn record(&self, mut event: OrderEvent) -> Option<OrderEvent> {
+        let mut state = self.state.lock().unwrap();
+        if event.supplied_id && !state.seen.insert(event.event_id.clone()) {
+            return None;
+        }
+
+        event.seq = state.next_seq;
+        state.next_seq += 1;
+        if !event.supplied_id {
+            event.event_id = format!("local-{}", event.seq);
+        }
+        for old in self.events.push(event.clone()) {
+            state.seen.remove(&old.event_id);
+        }
+        Some(event)
+    }
+
+    /// Events after `since` (exclusive), optionally for one order, oldest
+    /// first and capped at `limit`.
+    pub fn query(&self, order_id: Option<&str>, since: Option<u64>, limit: usize) -> Vec<OrderEvent> {
+        self.events
+            .records()
+            .iter()
+            .filter(|e| since.is_none_or(|s| e.seq > s))
+            .filter(|e| order_id.is_none_or(|id| e.order_id.as_deref() == Some(id)))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// `seq` of the newest event, or 0 when the inbox is empty.
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().unwrap().next_seq.saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(id: &str, order: &str, kind: &str) -> OrderEvent {
+        OrderEvent::from_payload(json!({"event_id": id, "order_id": order, "event": kind})).unwrap()
+    }
+
+    #[test]
+    fn test_event_from_payload() {
+        let ev = event("evt_1", "kQx7p3Wn", "bandwidth_80");
+        assert_eq!(ev.event_id, "evt_1");
+        assert_eq!(ev.event, "bandwidth_80");
+        assert_eq!(ev.order_id.as_deref(), Some("kQx7p3Wn"));
+
+        let ev = OrderEvent::from_payload(json!({"status": "paid", "order_id": "x"})).unwrap();
+        assert!(!ev.supplied_id);
+        assert_eq!(ev.event, "paid");
+
+        assert!(OrderEvent::from_payload(json!([1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_dedup_and_cursor() {
+        let inbox = EventInbox::in_memory();
+        assert_eq!(inbox.record(event("e1", "a", "payment_confirmed")).unwrap().seq, 1);
+        assert!(inbox.record(event("e1", "a", "payment_confirmed")).is_none());
+        inbox.record(event("e2", "b", "bandwidth_80"));
+        inbox.record(event("e3", "a", "bandwidth_95"));
+
+        let all = inbox.query(None, None, 100);
+        assert_eq!(all.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        let for_a = inbox.query(Some("a"), None, 100);
+        assert_eq!(for_a.len(), 2);
+
+        let since = inbox.query(None, Some(1), 1);
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].event_id, "e2");
+    }
+
+    #[test]
+    fn test_events_without_ids_are_never_dropped() {
+        let inbox = EventInbox::in_memory();
+        let body = json!({"event": "bandwidth_80", "order_id": "a"});
+        let first = inbox.record(OrderEvent::from_payload(body.clone()).unwrap()).unwrap();
+        let second = inbox.record(OrderEvent::from_payload(body).unwrap()).unwrap();
+        assert_eq!((first.event_id.as_str(), second.event_id.as_str()), ("local-1", "local-2"));
+        assert_eq!(inbox.query(Some("a"), None, 100).len(), 2);
+    }
+
+    #[test]
+    fn test_persists_across_reopen() {
+        let path = std::env::temp_dir()
+            .join(format!("proxybase-inbox-test-{}", std::process::id()))
+            .join("events.jsonl");
+
+        let inbox = EventInbox::open(&path);
+        inbox.record(event("e1", "a", "payment_confirmed"));
+        inbox.record(event("e2", "a", "bandwidth_exhausted"));
+        drop(inbox);
+
+        let reopened = EventInbox::open(&path);
+        assert!(reopened.record(event("e2", "a", "bandwidth_exhausted")).is_none());
+        assert_eq!(reopened.record(event("e3", "a", "rotated")).unwrap().seq, 3);
+        assert_eq!(reopened.query(Some("a"), None, 100).len(), 3);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_file_is_compacted_in_batches_past_the_cap() {
+        let path = std::env::temp_dir()
+            .join(format!("proxybase-inbox-cap-test-{}", std::process::id()))
+            .join("events.jsonl");
+        let file_lines = || {
+            crate::jsonl::flush();
+            std::fs::read_to_string(&path).unwrap().lines().count()
+        };
+        let limit = MAX_EVENTS + MAX_EVENTS / 10 + 1;
+
+        let inbox = EventInbox::open(&path);
+        for i in 0..limit {
+            inbox.record(event(&format!("e{}", i), "a", "bandwidth_80"));
+        }
+        // Past the cap but within the slack: the file only grows
+        assert_eq!(file_lines(), limit);
+        assert_eq!(inbox.query(None, None, usize::MAX).len(), MAX_EVENTS);
+
+        inbox.record(event("last", "a", "bandwidth_80"));
+        assert_eq!(file_lines(), MAX_EVENTS);
+        // Ids that left the inbox are no longer treated as redeliveries
+        assert!(inbox.record(event("e0", "a", "bandwidth_80")).is_some());
+        assert!(inbox.record(event("last", "a", "bandwidth_80")).is_none());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,316 @@
+//! Register the server in MCP client configuration files.
+//!
+//! Clients keep their servers under `mcpServers` in a JSON file. The file is
+//! edited in place: other entries, key order and indentation are kept, the
+//! original is backed up before every change, and running the same command
+//! twice changes nothing.
+
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+/// Key of our entry under `mcpServers`.
+pub const SERVER_NAME: &str = "proxybase";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Client {
+    ClaudeDesktop,
+    Cursor,
+}
+
+impl Client {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "claude" | "claude-desktop" | "claude_desktop" => Ok(Client::ClaudeDesktop),
+            "cursor" => Ok(Client::Cursor),
+            other => Err(format!("Unknown client: '{}'. Supported clients: claude-desktop, cursor", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Client::ClaudeDesktop => "Claude Desktop",
+            Client::Cursor => "Cursor",
+        }
+    }
+
+    /// Where the client reads its MCP servers from on this platform.
+    pub fn config_path(&self) -> Option<PathBuf> {
+        let home = env_path("HOME").or_else(|| env_path("USERPROFILE"));
+        match self {
+            Client::ClaudeDesktop => {
+                let dir = if cfg!(target_os = "macos") {
+                    home?.join("Library").join("Application Support")
+                } else if cfg!(windows) {
+                    env_path("APPDATA")?
+                } else {
+                    env_path("XDG_CONFIG_HOME").or_else(|| home.map(|h| h.join(".config")))?
+                };
+                Some(dir.join("Claude").join("claude_desktop_config.json"))
+            }
+            Client::Cursor => Some(home?.join(".cursor").join("mcp.json")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Added,
+    Updated,
+    Removed,
+    /// Nothing to do: the entry was already as requested.
+    Unchanged,
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub outcome: Outcome,
+    /// Copy of the file before it was changed, if it existed.
+    pub backup: Option<PathBuf>,
+}
+
+/// The `mcpServers` entry that runs `command` with `env`.
+pub fn server_entry(command: &Path, env: &[(&str, String)]) -> Value {
+    let mut entry = json!({ "command": command.to_string_lossy() });
+    if !env.is_empty() {
+        let env: Map<String, Value> = env.iter().map(|(k, v)| (k.to_string(), json!(v))).collect();
+        entry["env"] = Value::Object(env);
+    }
+    entry
+}
+
+/// Add or replace our entry in the config file at `path`, creating the file
+/// if needed.
+pub fn install(path: &Path, entry: Value) -> Result<Change, String> {
+    edit(path, |servers| match servers.get(SERVER_NAME) {
+        Some(existing) if *existing == entry => Outcome::Unchanged,
+        existing => {
+            let outcome = if existing.is_some() { Outcome::Updated } else { Outcome::Added };
+            servers.insert(SERVER_NAME.to_string(), entry);
+            outcome
+        }
+    })
+}
+
+/// Remove our entry from the config file at `path`.
+pub fn uninstall(path: &Path) -> Result<Change, String> {
+    if !path.exists() {
+        return Ok(Change { outcome: Outcome::Unchanged, backup: None });
+    }
+    edit(path, |servers| match servers.shift_remove(SERVER_NAME) {
+        Some(_) => Outcome::Removed,
+        None => Outcome::Unchanged,
+    })
+}
+
+fn edit(path: &Path, apply: impl FnOnce(&mut Map<String, Value>) -> Outcome) -> Result<Change, String> {
+    let original = match std::fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    let mut config = match original.as_deref().map(str::trim) {
+        None | Some("") => json!({}),
+        Some(text) => serde_json::from_str::<Value>(text)
+            .map_err(|e| format!("{} is not valid JSON ({}); fix or move it first", path.display(), e))?,
+    };
+    let root = config
+        .as_object_mut()
+        .ok_or_else(|| format!("{} does not contain a JSON object", path.display()))?;
+    let servers = root
+        .entry("mcpServers")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| format!("mcpServers in {} is not a JSON object", path.display()))?;
+
+    let outcome = apply(servers);
+    if outcome == Outcome::Unchanged {
+        return Ok(Change { outcome, backup: None });
+    }
+
+    // The file may hold an API key: its backup and replacement keep its
+    // permissions, and a new file is private.
+    let permissions = match std::fs::metadata(path) {
+        Ok(meta) => Some(meta.permissions()),
+        #[cfg(unix)]
+        Err(_) => Some(std::os::unix::fs::PermissionsExt::from_mode(0o600)),
+        #[cfg(not(unix))]
+        Err(_) => None,
+    };
+
+    let backup = match &original {
+        Some(text) => {
+            let backup = backup_path(path);
+            write_private(&backup, text, permissions.clone())
+                .map_err(|e| format!("Failed to back up {} to {}: {}", path.display(), backup.display(), e))?;
+            Some(backup)
+        }
+        None => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+            }
+            None
+        }
+    };
+
+    let mut text = to_string_with_indent(&config, original.as_deref().and_then(detect_indent).unwrap_or("  "))?;
+    if original.as_deref().is_none_or(|t| t.ends_with('\n')) {
+        text.push('\n');
+    }
+    // Write next to the file and rename, so a crash never leaves it half-written.
+    let tmp = path.with_extension("json.tmp");
+    write_private(&tmp, &text, permissions).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    std::fs::rename(&tmp, path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+
+    Ok(Change { outcome, backup })
+}
+
+/// Write `text` to a new file that only the user can read until it gets
+/// `permissions`, so it is never readable under the umask's defaults.
+fn write_private(path: &Path, text: &str, permissions: Option<std::fs::Permissions>) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+    file.write_all(text.as_bytes())
+}
+
+/// `<file>.<unix time>.bak`, numbered if needed so earlier backups are
+/// never overwritten.
+fn backup_path(path: &Path) -> PathBuf {
+    let now = crate::util::unix_now();
+    (0..)
+        .map(|n| {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            match n {
+                0 => name.push(format!(".{}.bak", now)),
+                n => name.push(format!(".{}-{}.bak", now, n)),
+            }
+            path.with_file_name(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Indentation of the first indented line.
+fn detect_indent(text: &str) -> Option<&str> {
+    text.lines().find_map(|line| {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        (!indent.is_empty() && !line.trim().is_empty()).then_some(indent)
+    })
+}
+
+fn to_string_with_indent(value: &Value, indent: &str) -> Result<String, String> {
+    use serde::Serialize;
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut serializer).map_err(|e| e.to_string())?;
+    String::from_utf8(out).map_err(|e| e.to_string())
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxybase-install-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn backups(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".bak"))
+            .count()
+    }
+
+    #[test]
+    fn test_install_preserves_other_entries_and_formatting() {
+        let dir = temp_dir("merge");
+        let path = dir.join("claude_desktop_config.json");
+        let original = "{\n    \"theme\": \"dark\",\n    \"mcpServers\": {\n        \"zeta\": {\n            \"command\": \"zeta\"\n        },\n        \"alpha\": {\n            \"command\": \"alpha\"\n        }\n    }\n}\n";
+        std::fs::write(&path, original).unwrap();
+
+        let entry = server_entry(Path::new("/usr/local/bin/proxybase-mcp"), &[("PROXYBASE_API_KEY", "pk_x".to_string())]);
+        let change = install(&path, entry.clone()).unwrap();
+        assert_eq!(change.outcome, Outcome::Added);
+        assert_eq!(std::fs::read_to_string(change.backup.unwrap()).unwrap(), original);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("{\n    \"theme\": \"dark\",\n    \"mcpServers\": {\n        \"zeta\""), "{}", text);
+        assert!(text.ends_with("}\n"));
+        let config: Value = serde_json::from_str(&text).unwrap();
+        let names: Vec<&String> = config["mcpServers"].as_object().unwrap().keys().collect();
+        assert_eq!(names, ["zeta", "alpha", "proxybase"]);
+        assert_eq!(config["mcpServers"]["proxybase"]["env"]["PROXYBASE_API_KEY"], "pk_x");
+
+        // Idempotent: no write, no new backup
+        assert_eq!(install(&path, entry).unwrap().outcome, Outcome::Unchanged);
+        assert_eq!(backups(&dir), 1);
+
+        let change = uninstall(&path).unwrap();
+        assert_eq!(change.outcome, Outcome::Removed);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        assert_eq!(uninstall(&path).unwrap().outcome, Outcome::Unchanged);
+        assert_eq!(backups(&dir), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_and_file_keep_the_original_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let dir = temp_dir("permissions");
+        let path = dir.join("mcp.json");
+        std::fs::write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let change = install(&path, server_entry(Path::new("proxybase-mcp"), &[])).unwrap();
+        assert_eq!(mode(&change.backup.unwrap()), 0o640);
+        assert_eq!(mode(&path), 0o640);
+
+        let created = dir.join("new.json");
+        install(&created, server_entry(Path::new("proxybase-mcp"), &[])).unwrap();
+        assert_eq!(mode(&created), 0o600);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_install_creates_missing_file_and_rejects_invalid_json() {
+        let dir = temp_dir("create");
+        let path = dir.join("nested").join("mcp.json");
+        let change = install(&path, server_entry(Path::new("proxybase-mcp"), &[])).unwrap();
+        assert_eq!(change.outcome, Outcome::Added);
+        assert!(change.backup.is_none());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\n  \"mcpServers\": {\n    \"proxybase\": {\n      \"command\": \"proxybase-mcp\"\n    }\n  }\n}\n"
+        );
+
+        std::fs::write(&path, "{ \"mcpServers\": ").unwrap();
+        assert!(install(&path, json!({})).unwrap_err().contains("not valid JSON"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ \"mcpServers\": ");
+        assert_eq!(uninstall(&dir.join("absent.json")).unwrap().outcome, Outcome::Unchanged);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_client() {
+        assert_eq!(Client::parse("Claude-Desktop").unwrap(), Client::ClaudeDesktop);
+        assert_eq!(Client::parse("cursor").unwrap(), Client::Cursor);
+        assert!(Client::parse("vim").unwrap_err().contains("claude-desktop, cursor"));
+    }
+}
@@ -0,0 +1,217 @@
+//! JSON Lines files for small local stores (event inbox, rotation history).
+//!
+//! Records are appended one per line; stores rewrite the file atomically
+//! when compacting. Corrupt lines are skipped with a warning rather than
+//! failing the whole load. [`Journal`] writes go through one background
+//! thread, so async callers never wait on the disk; call [`flush`] before
+//! exiting.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Queue a file write. Jobs run one at a time, in the order queued.
+fn enqueue(job: Job) {
+    static WRITER: OnceLock<Sender<Job>> = OnceLock::new();
+    let writer = WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("jsonl-writer".to_string())
+            .spawn(move || rx.into_iter().for_each(|job| job()))
+            .expect("failed to start the journal writer thread");
+        tx
+    });
+    if let Err(mpsc::SendError(job)) = writer.send(job) {
+        job();
+    }
+}
+
+/// Wait until every queued journal write has reached the disk.
+pub fn flush() {
+    let (tx, rx) = mpsc::channel();
+    enqueue(Box::new(move || {
+        let _ = tx.send(());
+    }));
+    let _ = rx.recv();
+}
+
+pub fn load<T: DeserializeOwned>(path: &Path) -> Vec<T> {
+    let Ok(data) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    data.lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| match serde_json::from_str(l) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                log::warn!("Skipping corrupt record in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn append<T: Serialize>(path: &Path, record: &T) {
+    let result = ensure_parent(path)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+        .and_then(|mut f| writeln!(f, "{}", serde_json::to_string(record).unwrap_or_default()));
+    if let Err(e) = result {
+        log::warn!("Failed to append to {}: {}", path.display(), e);
+    }
+}
+
+/// Replace the file's contents with `records` via a temporary file.
+pub fn rewrite<T: Serialize>(path: &Path, records: &[T]) {
+    let tmp = path.with_extension("jsonl.tmp");
+    let data: String = records
+        .iter()
+        .map(|r| serde_json::to_string(r).unwrap_or_default() + "\n")
+        .collect();
+    let result = ensure_parent(path)
+        .and_then(|_| std::fs::write(&tmp, data))
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if let Err(e) = result {
+        log::warn!("Failed to compact {}: {}", path.display(), e);
+    }
+}
+
+/// An append-only store of at most `max` records, optionally backed by a
+/// file. The oldest records are dropped when it overflows; the file may run
+/// up to [`Journal::slack`] lines over before it is compacted, so it is
+/// rewritten once per batch of records rather than on every push. File
+/// writes are queued for the writer thread and never hold up readers.
+pub struct Journal<T> {
+    path: Option<PathBuf>,
+    max: usize,
+    records: Mutex<Vec<T>>,
+    /// Lines in the file, changed only while `records` is locked.
+    lines: AtomicUsize,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + 'static> Journal<T> {
+    pub fn in_memory(max: usize) -> Self {
+        Self {
+            path: None,
+            max,
+            records: Mutex::new(Vec::new()),
+            lines: AtomicUsize::new(0),
+        }
+    }
+
+    /// Open (or create) the file at `path`, loading existing records.
+    pub fn open(path: impl Into<PathBuf>, max: usize) -> Self {
+        let path = path.into();
+        // Writes to this file from an earlier journal must land first
+        flush();
+        let mut records: Vec<T> = load(&path);
+        let mut lines = records.len();
+        let overflow = records.len().saturating_sub(max);
+        records.drain(..overflow);
+        if lines > max + Self::slack(max) {
+            rewrite(&path, &records);
+            lines = records.len();
+        }
+        Self {
+            path: Some(path),
+            max,
+            records: Mutex::new(records),
+            lines: AtomicUsize::new(lines),
+        }
+    }
+
+    /// How many lines past `max` the file may grow before it is compacted.
+    fn slack(max: usize) -> usize {
+        max / 10 + 1
+    }
+
+    /// Append a record, returning the oldest ones it pushed out. Writes are
+    /// queued while `records` is locked, so they reach the file in order.
+    pub fn push(&self, record: T) -> Vec<T> {
+        let mut records = self.records.lock().unwrap();
+        if let Some(path) = &self.path {
+            let (path, line) = (path.clone(), record.clone());
+            enqueue(Box::new(move || append(&path, &line)));
+        }
+        records.push(record);
+        let overflow = records.len().saturating_sub(self.max);
+        let dropped: Vec<T> = records.drain(..overflow).collect();
+
+        let Some(path) = &self.path else {
+            return dropped;
+        };
+        let lines = self.lines.load(Ordering::Relaxed) + 1;
+        if lines > self.max + Self::slack(self.max) {
+            let (path, snapshot) = (path.clone(), records.clone());
+            enqueue(Box::new(move || rewrite(&path, &snapshot)));
+            self.lines.store(records.len(), Ordering::Relaxed);
+        } else {
+            self.lines.store(lines, Ordering::Relaxed);
+        }
+        dropped
+    }
+
+    /// All records, oldest first.
+    pub fn records(&self) -> MutexGuard<'_, Vec<T>> {
+        self.records.lock().unwrap()
+    }
+}
+
+/// Create the store's directory, private to the user where supported.
+pub fn ensure_parent(path: &Path) -> std::io::Result<()> {
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_trims_in_memory_and_compacts_files_in_batches() {
+        let journal = Journal::in_memory(3);
+        for i in 0..10u32 {
+            journal.push(i);
+        }
+        assert_eq!(*journal.records(), vec![7, 8, 9]);
+
+        let dir = std::env::temp_dir().join(format!("proxybase-journal-test-{}", std::process::id()));
+        let path = dir.join("journal.jsonl");
+        let file_lines = || {
+            flush();
+            std::fs::read_to_string(&path).unwrap().lines().count()
+        };
+        let journal = Journal::open(&path, 10);
+        for i in 0..11u32 {
+            journal.push(i);
+        }
+        // Within the slack: one record over in memory is dropped, the file
+        // is left alone
+        assert_eq!(journal.records().len(), 10);
+        assert_eq!(file_lines(), 11);
+        journal.push(11);
+        assert_eq!(file_lines(), 12);
+        journal.push(12);
+        assert_eq!(file_lines(), 10);
+        assert_eq!(journal.records().first(), Some(&3));
+
+        // Reopening keeps the newest `max` records
+        journal.push(13);
+        drop(journal);
+        let reopened: Journal<u32> = Journal::open(&path, 10);
+        assert_eq!(*reopened.records(), (4..14).collect::<Vec<_>>());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
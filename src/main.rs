@@ -1,17 +1,68 @@
-/// ProxyBase MCP Server
-///
-/// A Model Context Protocol (MCP) server that lets AI agents purchase and
-/// manage SOCKS5 proxies through natural language tools.
-///
-/// Usage:
-///   PROXYBASE_API_URL=https://api.proxybase.xyz proxybase-mcp
-///
-/// Or for local development:
-///   PROXYBASE_API_URL=http://localhost:8080 cargo run
-
+//! ProxyBase MCP Server
+//!
+//! A Model Context Protocol (MCP) server that lets AI agents purchase and
+//! manage SOCKS5 proxies through natural language tools.
+//!
+//! Usage:
+//!   PROXYBASE_API_URL=https://api.proxybase.xyz proxybase-mcp
+//!
+//! Or for local development:
+//!   PROXYBASE_API_URL=http://localhost:8080 cargo run
+
+mod autotopup;
+mod cache;
+mod callback;
+mod catalog;
+mod cli;
+mod config;
+mod currency;
+mod doctor;
+mod export;
+mod fetch;
+mod gateway;
+mod geoip;
+mod http1;
+mod inbox;
+mod install;
+mod jsonl;
+mod logging;
+mod models;
+mod notify;
+mod order_id;
+mod output;
+mod payment;
+mod pool;
+mod probe;
+mod redact;
+mod rotation;
+mod schedule;
+mod socks5;
+mod trace;
+mod usage;
+mod util;
+mod webhook;
+
+use autotopup::AutoTopup;
+use base64::Engine;
+use cache::{cache_key, CacheEntry, CatalogCache};
+use cli::{Command, OrdersAction};
+use config::Config;
+use gateway::{Gateway, GatewayRegistry, Route};
+use geoip::GeoIp;
+use inbox::EventInbox;
+use models::{OrderStatus, PaymentInfo};
+use notify::Notifier;
+use order_id::{order_url, OrderId};
+use pool::{PoolMember, ProxyPool, Strategy};
+use schedule::{RotationHistory, RotationRecord, ScheduleSpec, Scheduler};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use usage::{LedgerEntry, UsageTracker};
+use webhook::WebhookReceiver;
 
 // ---------------------------------------------------------------------------
 // JSON-RPC 2.0 Types
@@ -19,6 +70,7 @@ use std::io::{self, BufRead, Write};
 
 #[derive(Debug, Deserialize)]
 struct JsonRpcRequest {
+    #[allow(dead_code)]
     jsonrpc: String,
     id: Option<Value>,
     method: String,
@@ -91,6 +143,10 @@ fn get_tools() -> Value {
                     "api_key": {
                         "type": "string",
                         "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "force_refresh": {
+                        "type": "boolean",
+                        "description": "Bypass the local cache and revalidate with the backend (default: false)"
                     }
                 },
                 "required": ["api_key"]
@@ -105,6 +161,10 @@ fn get_tools() -> Value {
                     "api_key": {
                         "type": "string",
                         "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "force_refresh": {
+                        "type": "boolean",
+                        "description": "Bypass the local cache and revalidate with the backend (default: false)"
                     }
                 },
                 "required": ["api_key"]
@@ -112,7 +172,7 @@ fn get_tools() -> Value {
         },
         {
             "name": "create_order",
-            "description": "Create a new proxy order. This generates a cryptocurrency payment invoice. Once payment is confirmed via the blockchain, your SOCKS5 proxy credentials will be provisioned automatically. Poll check_order_status to monitor payment and get credentials.",
+            "description": "Create a new proxy order. The package_id is checked against list_packages first, with suggestions for close misses. This generates a cryptocurrency payment invoice, returned with a wallet payment URI and a QR code (PNG image and ASCII) that a human can scan to pay. Once payment is confirmed via the blockchain, your SOCKS5 proxy credentials will be provisioned automatically. Poll check_order_status to monitor payment and get credentials.",
             "inputSchema": {
                 "type": "object",
                 "properties": {
@@ -126,11 +186,11 @@ fn get_tools() -> Value {
                     },
                     "pay_currency": {
                         "type": "string",
-                        "description": "Cryptocurrency to pay with. Use list_currencies to get valid values. Defaults to 'usdttrc20'."
+                        "description": "Cryptocurrency to pay with: a code from list_currencies (e.g. 'usdttrc20') or a name such as 'USDT on Tron', 'bitcoin' or 'USDC Solana'. If omitted, the server's configured default is used, or else the backend's own (currently usdttrc20)."
                     },
                     "callback_url": {
                         "type": "string",
-                        "description": "Optional webhook URL to receive status notifications (payment confirmed, bandwidth 80%/95%, exhausted)"
+                        "description": "Optional webhook URL to receive status notifications (payment confirmed, bandwidth 80%/95%, exhausted). Must be a public https:// URL without credentials."
                     }
                 },
                 "required": ["api_key", "package_id"]
@@ -138,7 +198,25 @@ fn get_tools() -> Value {
         },
         {
             "name": "check_order_status",
-            "description": "Check the current status of an order. Returns payment status, bandwidth usage, and SOCKS5 proxy credentials (host:port:username:password) once the proxy is active. Statuses: payment_pending → confirming → paid → proxy_active → bandwidth_exhausted.",
+            "description": "Check the current status of an order. Returns payment status, bandwidth usage, and SOCKS5 proxy credentials (host:port:username:password) once the proxy is active. Statuses: payment_pending → confirming → paid → proxy_active → bandwidth_exhausted; an invoice can also be partially_paid or expired (see payment_status).",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order ID returned from create_order"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "payment_status",
+            "description": "Explain where an order's payment stands and what to do next. Crypto invoices expire and can be underpaid: returns the state (awaiting_payment, partially_paid, confirming, paid, expired), amount expected vs. received, the amount still due, the expiry time and concrete next_steps such as 'send 0.42 more USDTTRC20 to the same address' or 'invoice expired, create a new order'. While something is due, includes a payment URI and QR code for the remaining amount.",
             "inputSchema": {
                 "type": "object",
                 "properties": {
@@ -156,7 +234,7 @@ fn get_tools() -> Value {
         },
         {
             "name": "topup_order",
-            "description": "Add more bandwidth to an existing order. Creates a new payment invoice for the additional bandwidth. The proxy credentials remain the same — only the bandwidth allowance increases. Can also reactivate an exhausted proxy.",
+            "description": "Add more bandwidth to an existing order. Creates a new payment invoice for the additional bandwidth, with a payment URI and QR code like create_order. The proxy credentials remain the same — only the bandwidth allowance increases. Can also reactivate an exhausted proxy. The result includes warnings if the top-up package's country or proxy type differs from the original order's.",
             "inputSchema": {
                 "type": "object",
                 "properties": {
@@ -174,15 +252,98 @@ fn get_tools() -> Value {
                     },
                     "pay_currency": {
                         "type": "string",
-                        "description": "Cryptocurrency to pay with. Use list_currencies to get valid values. Defaults to 'usdttrc20'."
+                        "description": "Cryptocurrency to pay with: a code from list_currencies (e.g. 'usdttrc20') or a name such as 'USDT on Tron', 'bitcoin' or 'USDC Solana'. If omitted, the server's configured default is used, or else the backend's own (currently usdttrc20)."
+                    }
+                },
+                "required": ["api_key", "order_id", "package_id"]
+            }
+        },
+        {
+            "name": "set_topup_rule",
+            "description": "Top up an order automatically when its bandwidth runs low: once usage_percentage reaches threshold_pct, topup_order is called with package_id and pay_currency, at most max_topups times and within budget_usd if given. Usage is polled in the background and re-checked when a webhook event for the order arrives. Each top-up creates a payment invoice that is sent as a log notification and recorded as an 'auto_topup_invoice' event (see get_order_events); bandwidth is only added once it is paid. After a top-up, the rule waits for the order's bandwidth to grow (the invoice was paid) or usage to drop below the threshold before it can fire again. Replaces any existing rule for the order.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to top up automatically"
+                    },
+                    "package_id": {
+                        "type": "string",
+                        "description": "The bandwidth package to add on each top-up (e.g., 'us_residential_1gb')"
+                    },
+                    "pay_currency": {
+                        "type": "string",
+                        "description": "Cryptocurrency to pay with: a code from list_currencies (e.g. 'usdttrc20') or a name such as 'USDT on Tron', 'bitcoin' or 'USDC Solana'. If omitted, the server's configured default is used, or else the backend's own (currently usdttrc20)."
+                    },
+                    "threshold_pct": {
+                        "type": "number",
+                        "description": "Top up when usage reaches this percentage (default: 85)"
+                    },
+                    "max_topups": {
+                        "type": "integer",
+                        "description": "Maximum number of automatic top-ups (default: 3)"
+                    },
+                    "budget_usd": {
+                        "type": "number",
+                        "description": "Maximum total price of all automatic top-ups, in USD"
                     }
                 },
                 "required": ["api_key", "order_id", "package_id"]
             }
         },
+        {
+            "name": "list_topup_rules",
+            "description": "List auto top-up rules with their limits, amount spent, last observed usage, whether they are blocked by a limit, and the invoices they created.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {},
+                "required": []
+            }
+        },
+        {
+            "name": "delete_topup_rule",
+            "description": "Remove the auto top-up rule of an order. Invoices already created are not affected.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order whose rule should be removed"
+                    }
+                },
+                "required": ["order_id"]
+            }
+        },
+        {
+            "name": "usage_report",
+            "description": "Report an order's bandwidth consumption over time: current usage, consumption rate over the window, projected exhaustion time, and the USD actually spent with the resulting cost per GB used and bought. Usage is sampled locally whenever the order's status is fetched and in the background for orders seen before, so the history grows without polling. Use it to decide whether to top up now or buy a bigger package.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to report on"
+                    },
+                    "window_hours": {
+                        "type": "integer",
+                        "description": "Hours of history used for the rate and forecast (default: 24, max: 720)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
         {
             "name": "rotate_proxy",
-            "description": "Rotate the proxy to get a fresh IP address. This calls the upstream partner's reset endpoint to invalidate the current session and assign a new IP. Only works on orders with proxy_active status. After rotation, your next SOCKS5 connection will use a new IP.",
+            "description": "Rotate the proxy to get a fresh IP address. This calls the upstream partner's reset endpoint to invalidate the current session and assign a new IP. Only works on orders with proxy_active status. After rotation, your next SOCKS5 connection will use a new IP. With verify=true the exit IP is read through the proxy before and after, rotation is retried if it did not change, and the new IP's country is checked against the package's country using the offline GeoIP database (if configured).",
             "inputSchema": {
                 "type": "object",
                 "properties": {
@@ -193,10 +354,354 @@ fn get_tools() -> Value {
                     "order_id": {
                         "type": "string",
                         "description": "The order ID whose proxy should be rotated"
+                    },
+                    "verify": {
+                        "type": "boolean",
+                        "description": "Confirm the exit IP changed and report its country (default: false)"
+                    },
+                    "max_attempts": {
+                        "type": "integer",
+                        "description": "With verify, rotations to try until the IP changes (default: 3, max: 10)"
+                    },
+                    "echo_url": {
+                        "type": "string",
+                        "description": "With verify, URL that responds with the caller's IP (default: https://api.ipify.org?format=json)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "schedule_rotation",
+            "description": "Rotate an order's proxy automatically in the background: every interval_secs (with random jitter) and/or after every_connections connections made through the order's local gateway (see start_local_gateway). Rotations are capped per rolling hour to respect upstream limits. Replaces any existing schedule for the order. Every rotation is recorded; see get_rotation_history.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order whose proxy should be rotated"
+                    },
+                    "interval_secs": {
+                        "type": "integer",
+                        "description": "Rotate every this many seconds (minimum: 30)"
+                    },
+                    "every_connections": {
+                        "type": "integer",
+                        "description": "Rotate after this many connections through the order's local gateway"
+                    },
+                    "jitter_pct": {
+                        "type": "integer",
+                        "description": "Randomize each interval by up to ± this percentage (default: 10, max: 50)"
+                    },
+                    "max_per_hour": {
+                        "type": "integer",
+                        "description": "Maximum rotations in any rolling hour (default: 12)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "list_schedules",
+            "description": "List rotation schedules with their settings, time to the next rotation, rotation counts, throttling and last error. Schedules cancel themselves after 5 consecutive failed rotations and are shown with their cancel_reason.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {},
+                "required": []
+            }
+        },
+        {
+            "name": "cancel_schedule",
+            "description": "Cancel rotation schedules, selected by schedule_id or order_id.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "schedule_id": {
+                        "type": "string",
+                        "description": "The schedule to cancel (from schedule_rotation or list_schedules)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "Cancel the schedule for this order"
+                    }
+                },
+                "required": []
+            }
+        },
+        {
+            "name": "get_rotation_history",
+            "description": "Show past rotations of an order, manual and scheduled, with their trigger and outcome. History is stored locally and survives restarts.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to show"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "Most recent rotations to return (default: 50, max: 1000)"
+                    }
+                },
+                "required": ["order_id"]
+            }
+        },
+        {
+            "name": "test_proxy",
+            "description": "Check that an active order's SOCKS5 proxy actually works. Runs a real SOCKS5 handshake with username/password auth against the proxy, then fetches an IP echo URL through it. Reports connect latency, auth result, exit IP and, on failure, the stage that failed (connect, greeting, auth, tunnel, fetch).",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order whose proxy should be tested (must be proxy_active)"
+                    },
+                    "echo_url": {
+                        "type": "string",
+                        "description": "URL that responds with the caller's IP (default: https://api.ipify.org?format=json)"
+                    },
+                    "timeout_secs": {
+                        "type": "integer",
+                        "description": "Per-stage timeout in seconds (default: 15, max: 120)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "export_proxy_config",
+            "description": "Render an active order's proxy credentials in ready-to-use formats: url (socks5h://), curl, env (ALL_PROXY exports), pac, playwright, puppeteer, python_requests, clash, sing_box, v2ray. Credentials are URL-escaped where needed. Browser formats point at the order's local gateway if one is running, since browsers cannot authenticate to SOCKS5.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to export (must be proxy_active)"
+                    },
+                    "format": {
+                        "type": "string",
+                        "enum": ["all", "url", "curl", "env", "pac", "playwright", "puppeteer", "python_requests", "clash", "sing_box", "v2ray"],
+                        "description": "Format to render (default: all)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "http_fetch",
+            "description": "Perform an HTTP request through an active order's SOCKS5 proxy (DNS is resolved on the proxy side). Returns status, headers and the body decoded as text, capped at max_body_bytes (binary bodies are not shown). Also reports the bytes this call sent and received through the proxy, which is the bandwidth it consumed from the order.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order whose proxy to use (must be proxy_active)"
+                    },
+                    "url": {
+                        "type": "string",
+                        "description": "http:// or https:// URL to fetch"
+                    },
+                    "method": {
+                        "type": "string",
+                        "description": "HTTP method (default: GET)"
+                    },
+                    "headers": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                        "description": "Request headers as name → value"
+                    },
+                    "body": {
+                        "type": "string",
+                        "description": "Request body"
+                    },
+                    "max_body_bytes": {
+                        "type": "integer",
+                        "description": "Stop reading the response body after this many bytes (default: 262144, max: 4194304)"
+                    },
+                    "timeout_secs": {
+                        "type": "integer",
+                        "description": "Timeout for the whole request in seconds (default: 30, max: 120)"
+                    }
+                },
+                "required": ["api_key", "order_id", "url"]
+            }
+        },
+        {
+            "name": "start_local_gateway",
+            "description": "Start a local proxy on 127.0.0.1 that needs no authentication, for tools that cannot do authenticated SOCKS5. It speaks SOCKS5 and HTTP (CONNECT and plain http:// requests) and chains every connection through the order's proxy. Traffic is counted locally and the gateway stops by itself when the order's bandwidth is exhausted. Returns socks5_url and http_proxy_url to use.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order whose proxy the gateway forwards to (must be proxy_active)"
+                    },
+                    "port": {
+                        "type": "integer",
+                        "description": "Local port to listen on (default: 0, any free port)"
+                    }
+                },
+                "required": ["api_key", "order_id"]
+            }
+        },
+        {
+            "name": "stop_local_gateway",
+            "description": "Stop local gateways started with start_local_gateway, selected by order_id or port. Open connections are closed.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "order_id": {
+                        "type": "string",
+                        "description": "Stop every gateway for this order"
+                    },
+                    "port": {
+                        "type": "integer",
+                        "description": "Stop the gateway listening on this port"
+                    }
+                },
+                "required": []
+            }
+        },
+        {
+            "name": "list_local_gateways",
+            "description": "List local gateways with their URLs, byte counts and connection counts. Gateways that stopped (e.g. because the order was exhausted) are shown with their stop_reason.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {},
+                "required": []
+            }
+        },
+        {
+            "name": "pool_add_order",
+            "description": "Add an active order to the local proxy pool used by start_pool_gateway. The order is tagged with its country (from its package, or the country argument) for the 'country' strategy. Members that keep failing or run out of bandwidth are ejected automatically; exhausted ones return after a top-up.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "Your ProxyBase API key (starts with pk_)"
+                    },
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to add (must be proxy_active)"
+                    },
+                    "country": {
+                        "type": "string",
+                        "description": "Country tag, e.g. 'us'. Defaults to the country of the order's package."
                     }
                 },
                 "required": ["api_key", "order_id"]
             }
+        },
+        {
+            "name": "pool_remove_order",
+            "description": "Remove an order from the local proxy pool. Connections already open through it are not interrupted.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "order_id": {
+                        "type": "string",
+                        "description": "The order to remove"
+                    }
+                },
+                "required": ["order_id"]
+            }
+        },
+        {
+            "name": "pool_status",
+            "description": "Show the proxy pool: each member's country tag, health, ejection reason, bytes relayed and remaining bandwidth, plus the gateways routing to the pool.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {},
+                "required": []
+            }
+        },
+        {
+            "name": "start_pool_gateway",
+            "description": "Start a local unauthenticated SOCKS5/HTTP proxy on 127.0.0.1 that load-balances each new connection across the pool. Strategies: round_robin, least_used (fewest bytes relayed), sticky_host (same destination host → same order), country (only members with the given country tag). If an upstream fails the next candidate is tried. Stop it with stop_local_gateway.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "strategy": {
+                        "type": "string",
+                        "enum": ["round_robin", "least_used", "sticky_host", "country"],
+                        "description": "How to pick an upstream per connection (default: round_robin)"
+                    },
+                    "country": {
+                        "type": "string",
+                        "description": "Country tag to route to; required for the 'country' strategy"
+                    },
+                    "port": {
+                        "type": "integer",
+                        "description": "Local port to listen on (default: 0, any free port)"
+                    }
+                },
+                "required": []
+            }
+        },
+        {
+            "name": "get_order_events",
+            "description": "Read webhook notifications received for your orders (payment confirmed, bandwidth 80%/95%, exhausted, rotated). Events are stored locally, so you can catch up after being offline: pass the returned next_cursor as 'since' on the next call. Requires the webhook receiver to be enabled.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "order_id": {
+                        "type": "string",
+                        "description": "Only return events for this order. Omit for events across all orders."
+                    },
+                    "since": {
+                        "type": "integer",
+                        "description": "Cursor from a previous call; only events after it are returned"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "Maximum number of events to return (default: 100, max: 1000)"
+                    }
+                },
+                "required": []
+            }
+        },
+        {
+            "name": "diagnose",
+            "description": "Diagnose setup problems: checks that the backend URL is reachable, TLS works, the API key is accepted (via list_packages), the local clock is not skewed against the backend, and local state and client configs holding the key are private. Optionally probes the proxies of active orders. Each check reports pass/warn/fail/skip with a remediation hint. Run this first when tools fail unexpectedly.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "api_key": {
+                        "type": "string",
+                        "description": "API key to verify (default: the server's PROXYBASE_API_KEY, if set)"
+                    },
+                    "test_proxies": {
+                        "type": "boolean",
+                        "description": "Also probe the proxies of active orders known to this server (default: false)"
+                    },
+                    "max_clock_skew_secs": {
+                        "type": "integer",
+                        "description": "Largest acceptable difference from the backend's clock (default: 60)"
+                    }
+                },
+                "required": []
+            }
         }
     ])
 }
@@ -205,9 +710,19 @@ fn get_tools() -> Value {
 // ProxyBase API Client
 // ---------------------------------------------------------------------------
 
+/// Retries of a GET that failed with a connection error or one of
+/// `RETRY_STATUSES`; the backoff doubles after each.
+const MAX_RETRIES: u32 = 2;
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(250);
+const RETRY_STATUSES: &[u16] = &[429, 502, 503, 504];
+
+#[derive(Clone)]
 struct ProxyBaseClient {
     http: reqwest::Client,
     base_url: String,
+    cache: Arc<CatalogCache>,
+    /// Receives every order status and invoice, when set.
+    usage: Option<Arc<UsageTracker>>,
 }
 
 impl ProxyBaseClient {
@@ -215,33 +730,88 @@ impl ProxyBaseClient {
         Self {
             http: reqwest::Client::new(),
             base_url: base_url.trim_end_matches('/').to_string(),
+            cache: Arc::new(CatalogCache::new(cache::DEFAULT_TTL)),
+            usage: None,
         }
     }
 
-    async fn register_agent(&self) -> Result<Value, String> {
-        let resp = self.http
-            .post(format!("{}/v1/agents", self.base_url))
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+    fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap_or_default();
+        self
+    }
 
-        let status = resp.status();
-        let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
+    fn with_cache(mut self, cache: CatalogCache) -> Self {
+        self.cache = Arc::new(cache);
+        self
+    }
 
-        if status.is_success() {
-            Ok(body)
-        } else {
-            Err(format!("API error ({}): {}", status, body))
+    fn with_usage(mut self, usage: Arc<UsageTracker>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    fn record_invoice(&self, kind: &str, order_id: Option<&OrderId>, package_id: &str, invoice: &Value) {
+        let entry = LedgerEntry::from_invoice(kind, order_id.map(|id| id.as_str()), package_id, invoice);
+        if let (Some(usage), Some(entry)) = (&self.usage, entry) {
+            usage.record_invoice(entry);
         }
     }
 
-    async fn list_packages(&self, api_key: &str) -> Result<Value, String> {
-        let resp = self.http
-            .get(format!("{}/v1/packages", self.base_url))
-            .header("X-API-Key", api_key)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+    /// Send a request to the backend, tagged with the current request id
+    /// as `X-Request-Id`. GETs are retried on connection errors and on
+    /// 429/502/503/504, with exponential backoff.
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response, String> {
+        let request_id = trace::current_id().unwrap_or_else(trace::new_id);
+        let (http, request) = req.header("X-Request-Id", &request_id).build_split();
+        let mut request = request.map_err(|e| format!("HTTP error: {}", e))?;
+        let endpoint = format!("{} {}", request.method(), request.url().path());
+        let idempotent = request.method() == reqwest::Method::GET;
+
+        let started = std::time::Instant::now();
+        let mut retries = 0;
+        let result = loop {
+            // A copy for the next attempt, while retries are left
+            let spare = match idempotent && retries < MAX_RETRIES {
+                true => request.try_clone(),
+                false => None,
+            };
+            let result = http.execute(request).await;
+            let retry = match &result {
+                Ok(resp) => RETRY_STATUSES.contains(&resp.status().as_u16()),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            match spare {
+                Some(spare) if retry => request = spare,
+                _ => break result,
+            }
+            tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(retries)).await;
+            retries += 1;
+        };
+
+        let call = trace::BackendCall {
+            endpoint,
+            status: result.as_ref().ok().map(|resp| resp.status().as_u16()),
+            retries,
+            duration: started.elapsed(),
+        };
+        log::debug!(
+            target: trace::TARGET,
+            request_id = request_id.as_str(),
+            endpoint = call.endpoint.as_str(),
+            status = call.status.unwrap_or(0),
+            retries = call.retries,
+            duration_ms = call.duration.as_millis() as u64;
+            "Backend {}", call.endpoint
+        );
+        trace::record(call);
+        result.map_err(|e| format!("HTTP error: {}", e))
+    }
+
+    async fn register_agent(&self) -> Result<Value, String> {
+        let resp = self.send(self.http.post(format!("{}/v1/agents", self.base_url))).await?;
 
         let status = resp.status();
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
@@ -249,25 +819,67 @@ impl ProxyBaseClient {
         if status.is_success() {
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
         }
     }
 
-    async fn list_currencies(&self, api_key: &str) -> Result<Value, String> {
-        let resp = self.http
-            .get(format!("{}/v1/currencies", self.base_url))
-            .header("X-API-Key", api_key)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+    async fn list_packages(&self, api_key: &str, force_refresh: bool) -> Result<Value, String> {
+        self.get_catalog("packages", api_key, force_refresh).await
+    }
+
+    async fn list_currencies(&self, api_key: &str, force_refresh: bool) -> Result<Value, String> {
+        self.get_catalog("currencies", api_key, force_refresh).await
+    }
+
+    /// GET a catalog endpoint through the TTL cache. Fresh entries are served
+    /// without a request; stale ones are revalidated with `If-None-Match`.
+    async fn get_catalog(
+        &self,
+        endpoint: &str,
+        api_key: &str,
+        force_refresh: bool,
+    ) -> Result<Value, String> {
+        let key = cache_key(endpoint, api_key);
+        let cached = self.cache.get(&key);
+
+        if let Some(entry) = &cached {
+            if !force_refresh && self.cache.is_fresh(entry) {
+                return Ok(entry.body.clone());
+            }
+        }
+
+        let mut req = self.http
+            .get(format!("{}/v1/{}", self.base_url, endpoint))
+            .header("X-API-Key", api_key);
+        if let Some(etag) = cached.as_ref().and_then(|e| e.etag.as_deref()) {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        let resp = self.send(req).await?;
 
         let status = resp.status();
+        if status == reqwest::StatusCode::NOT_MODIFIED {
+            // Only a cached entry's ETag is ever sent, so a 304 without one
+            // has no body to fall back on
+            let entry = cached.ok_or_else(|| {
+                format!("API error (304 Not Modified): GET /v1/{} answered 304 to a request without a cached copy", endpoint)
+            })?;
+            self.cache.touch(&key);
+            return Ok(entry.body);
+        }
+
+        let etag = resp
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
 
         if status.is_success() {
+            self.cache.put(&key, CacheEntry::new(body.clone(), etag));
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
         }
     }
 
@@ -287,47 +899,47 @@ impl ProxyBaseClient {
             payload["callback_url"] = json!(url);
         }
 
-        let resp = self.http
+        let req = self.http
             .post(format!("{}/v1/orders", self.base_url))
             .header("X-API-Key", api_key)
             .header("Content-Type", "application/json")
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+            .json(&payload);
+        let resp = self.send(req).await?;
 
         let status = resp.status();
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
 
         if status.is_success() {
+            self.record_invoice("order", None, package_id, &body);
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
         }
     }
 
-    async fn check_order_status(&self, api_key: &str, order_id: &str) -> Result<Value, String> {
-        let resp = self.http
-            .get(format!("{}/v1/orders/{}/status", self.base_url, order_id))
-            .header("X-API-Key", api_key)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+    async fn check_order_status(&self, api_key: &str, order_id: &OrderId) -> Result<Value, String> {
+        let req = self.http
+            .get(order_url(&self.base_url, order_id, "status")?)
+            .header("X-API-Key", api_key);
+        let resp = self.send(req).await?;
 
         let status = resp.status();
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
 
         if status.is_success() {
+            if let Some(usage) = &self.usage {
+                usage.observe(api_key, order_id, &body);
+            }
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
         }
     }
 
     async fn topup_order(
         &self,
         api_key: &str,
-        order_id: &str,
+        order_id: &OrderId,
         package_id: &str,
         pay_currency: Option<&str>,
     ) -> Result<Value, String> {
@@ -337,32 +949,29 @@ impl ProxyBaseClient {
             payload["pay_currency"] = json!(currency);
         }
 
-        let resp = self.http
-            .post(format!("{}/v1/orders/{}/topup", self.base_url, order_id))
+        let req = self.http
+            .post(order_url(&self.base_url, order_id, "topup")?)
             .header("X-API-Key", api_key)
             .header("Content-Type", "application/json")
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+            .json(&payload);
+        let resp = self.send(req).await?;
 
         let status = resp.status();
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
 
         if status.is_success() {
+            self.record_invoice("topup", Some(order_id), package_id, &body);
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
         }
     }
 
-    async fn rotate_proxy(&self, api_key: &str, order_id: &str) -> Result<Value, String> {
-        let resp = self.http
-            .post(format!("{}/v1/orders/{}/rotate", self.base_url, order_id))
-            .header("X-API-Key", api_key)
-            .send()
-            .await
-            .map_err(|e| format!("HTTP error: {}", e))?;
+    async fn rotate_proxy(&self, api_key: &str, order_id: &OrderId) -> Result<Value, String> {
+        let req = self.http
+            .post(order_url(&self.base_url, order_id, "rotate")?)
+            .header("X-API-Key", api_key);
+        let resp = self.send(req).await?;
 
         let status = resp.status();
         let body: Value = resp.json().await.map_err(|e| format!("Parse error: {}", e))?;
@@ -370,8 +979,111 @@ impl ProxyBaseClient {
         if status.is_success() {
             Ok(body)
         } else {
-            Err(format!("API error ({}): {}", status, body))
+            Err(api_error(status, &body))
+        }
+    }
+}
+
+/// The error for a failed backend call. The body is included for context,
+/// with any keys or proxy passwords in it masked.
+fn api_error(status: reqwest::StatusCode, body: &Value) -> String {
+    redact::secrets(&format!("API error ({}): {}", status, body))
+}
+
+/// The HTTP status of an error made by [`api_error`], if it is one.
+pub(crate) fn api_error_status(error: &str) -> Option<u16> {
+    error.strip_prefix("API error (")?.get(..3)?.parse().ok()
+}
+
+// ---------------------------------------------------------------------------
+// Server State
+// ---------------------------------------------------------------------------
+
+struct AppState {
+    client: ProxyBaseClient,
+    config: Config,
+    inbox: Arc<EventInbox>,
+    notifier: Notifier,
+    gateways: Arc<GatewayRegistry>,
+    pool: Arc<ProxyPool>,
+    geoip: Option<GeoIp>,
+    scheduler: Scheduler,
+    autotopup: Arc<AutoTopup>,
+    usage: Arc<UsageTracker>,
+}
+
+impl AppState {
+    fn new(config: Config, notifier: Notifier) -> Self {
+        let mut cache = CatalogCache::new(config.cache_ttl);
+        if let Some(dir) = &config.cache_dir {
+            cache = cache.with_dir(dir);
         }
+
+        let (inbox, history, usage) = match &config.data_dir {
+            Some(dir) => (
+                EventInbox::open(dir.join("events.jsonl")),
+                RotationHistory::open(dir.join("rotations.jsonl")),
+                UsageTracker::open(dir),
+            ),
+            None => (
+                EventInbox::in_memory(),
+                RotationHistory::in_memory(),
+                UsageTracker::in_memory(),
+            ),
+        };
+
+        let geoip = config.geoip_db.as_ref().and_then(|path| match GeoIp::open(path) {
+            Ok(geoip) => Some(geoip),
+            Err(e) => {
+                log::warn!("{}; rotation checks will skip the country", e);
+                None
+            }
+        });
+
+        let inbox = Arc::new(inbox);
+        let usage = Arc::new(usage);
+        Self {
+            client: ProxyBaseClient::new(&config.api_url)
+                .with_timeout(config.request_timeout)
+                .with_cache(cache)
+                .with_usage(usage.clone()),
+            usage,
+            config,
+            autotopup: Arc::new(AutoTopup::new(inbox.clone(), notifier.clone())),
+            inbox,
+            pool: Arc::new(ProxyPool::new(notifier.clone())),
+            notifier,
+            gateways: Arc::new(GatewayRegistry::default()),
+            geoip,
+            scheduler: Scheduler::new(history),
+        }
+    }
+
+    /// Start a rotation schedule that counts connections through this
+    /// server's local gateways.
+    fn schedule_rotation(
+        &self,
+        api_key: &str,
+        order_id: OrderId,
+        spec: ScheduleSpec,
+    ) -> Result<Arc<schedule::RotationSchedule>, String> {
+        let gateways = self.gateways.clone();
+        self.scheduler.start(
+            self.client.clone(),
+            api_key,
+            order_id,
+            spec,
+            Arc::new(move |order_id| gateways.connections_for(order_id)),
+        )
+    }
+
+    /// Rotate an order's proxy and record it in the rotation history.
+    async fn rotate_now(&self, api_key: &str, order_id: &OrderId) -> Result<Value, String> {
+        let result = self.client.rotate_proxy(api_key, order_id).await;
+        self.scheduler
+            .history()
+            .record(RotationRecord::new(order_id, "manual", None, &result));
+        result
     }
 }
 
@@ -379,7 +1091,22 @@ impl ProxyBaseClient {
 // MCP Request Handler
 // ---------------------------------------------------------------------------
 
-async fn handle_request(client: &ProxyBaseClient, req: &JsonRpcRequest) -> JsonRpcResponse {
+/// Handle one JSON-RPC request under a fresh correlation id, and log it.
+async fn handle_request(state: &AppState, req: &JsonRpcRequest) -> JsonRpcResponse {
+    let request = trace::Request::new();
+    let response = request.scope(dispatch(state, req)).await;
+
+    let tool = match req.method.as_str() {
+        "tools/call" => req.params.as_ref().and_then(|p| p.get("name")).and_then(|n| n.as_str()),
+        _ => None,
+    };
+    let ok = response.error.is_none()
+        && !response.result.as_ref().is_some_and(|r| r["isError"] == json!(true));
+    request.finish(&req.method, tool, ok);
+    response
+}
+
+async fn dispatch(state: &AppState, req: &JsonRpcRequest) -> JsonRpcResponse {
     let id = req.id.clone().unwrap_or(Value::Null);
 
     match req.method.as_str() {
@@ -387,7 +1114,8 @@ async fn handle_request(client: &ProxyBaseClient, req: &JsonRpcRequest) -> JsonR
         "initialize" => JsonRpcResponse::success(id, json!({
             "protocolVersion": "2024-11-05",
             "capabilities": {
-                "tools": {}
+                "tools": {},
+                "logging": {}
             },
             "serverInfo": {
                 "name": "proxybase-mcp",
@@ -396,9 +1124,16 @@ async fn handle_request(client: &ProxyBaseClient, req: &JsonRpcRequest) -> JsonR
         })),
 
         // MCP Tool Discovery
-        "tools/list" => JsonRpcResponse::success(id, json!({
-            "tools": get_tools()
-        })),
+        "tools/list" => {
+            let tools: Vec<Value> = get_tools()
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|t| state.config.tools.allows(t["name"].as_str().unwrap_or_default()))
+                .cloned()
+                .collect();
+            JsonRpcResponse::success(id, json!({ "tools": tools }))
+        }
 
         // MCP Tool Execution
         "tools/call" => {
@@ -412,106 +1147,685 @@ async fn handle_request(client: &ProxyBaseClient, req: &JsonRpcRequest) -> JsonR
                 .cloned()
                 .unwrap_or(json!({}));
 
-            let result = execute_tool(client, tool_name, &args).await;
+            // The tool policy limits agents; CLI commands are not subject to it
+            let result = match state.config.tools.check(tool_name) {
+                Ok(()) => call_tool(state, tool_name, &args).await,
+                Err(e) => Err(e),
+            };
 
             match result {
-                Ok(content) => JsonRpcResponse::success(id, json!({
-                    "content": [{
+                Ok(output) => {
+                    let mut content = vec![json!({
                         "type": "text",
-                        "text": serde_json::to_string_pretty(&content).unwrap_or_default()
-                    }]
-                })),
+                        "text": serde_json::to_string_pretty(&output.value).unwrap_or_default()
+                    })];
+                    content.extend(output.extra);
+                    JsonRpcResponse::success(id, json!({ "content": content }))
+                }
                 Err(err_msg) => JsonRpcResponse::success(id, json!({
                     "content": [{
                         "type": "text",
-                        "text": err_msg
+                        "text": redact::secrets(&err_msg)
                     }],
                     "isError": true
                 })),
             }
         }
 
-        // Notifications (no response needed)
-        "notifications/initialized" | "notifications/cancelled" => {
-            JsonRpcResponse::success(id, json!(null))
+        // Webhook events are always forwarded; the requested level is accepted
+        // but not used for filtering.
+        "logging/setLevel" => JsonRpcResponse::success(id, json!({})),
+
+        // Notifications (no response needed)
+        "notifications/initialized" | "notifications/cancelled" => {
+            JsonRpcResponse::success(id, json!(null))
+        }
+
+        _ => JsonRpcResponse::error(id, -32601, format!("Method not found: {}", req.method)),
+    }
+}
+
+/// A tool result: JSON for the text block, plus extra content blocks such
+/// as images.
+struct ToolOutput {
+    value: Value,
+    extra: Vec<Value>,
+}
+
+/// Run a tool and attach rich content. Invoices, and payment statuses with
+/// an amount due, get a payment URI and a QR code, as text and as an
+/// `image` block. Proxy passwords are masked if the config says so.
+async fn call_tool(state: &AppState, tool_name: &str, args: &Value) -> Result<ToolOutput, String> {
+    let mut value = execute_tool(state, tool_name, args).await?;
+    let mut extra = Vec::new();
+    if state.config.mask_proxy_passwords {
+        redact::proxy_passwords(&mut value);
+    }
+
+    if matches!(tool_name, "create_order" | "topup_order" | "payment_status") {
+        match payment::Invoice::from_value(&value).map(|invoice| invoice.render()) {
+            Some(Ok((details, png))) => {
+                value["payment"] = details;
+                extra.push(json!({
+                    "type": "image",
+                    "data": base64::engine::general_purpose::STANDARD.encode(png),
+                    "mimeType": "image/png"
+                }));
+            }
+            // The invoice itself is still valid without a QR code
+            Some(Err(e)) => log::warn!("Could not render payment QR code: {}", e),
+            None => {}
+        }
+    }
+    Ok(ToolOutput { value, extra })
+}
+
+async fn execute_tool(
+    state: &AppState,
+    tool_name: &str,
+    args: &Value,
+) -> Result<Value, String> {
+    let client = &state.client;
+
+    match tool_name {
+        "register_agent" => client.register_agent().await,
+
+        "list_packages" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            client.list_packages(&api_key, get_bool_arg(args, "force_refresh")).await
+        }
+
+        "list_currencies" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            client.list_currencies(&api_key, get_bool_arg(args, "force_refresh")).await
+        }
+
+        "create_order" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let package_id = get_str_arg(args, "package_id")?;
+            let pay_currency = args
+                .get("pay_currency")
+                .and_then(|v| v.as_str())
+                .or(state.config.default_pay_currency.as_deref());
+
+            // Local checks first, before any backend round-trips
+            let callback_url = match args.get("callback_url").and_then(|v| v.as_str()) {
+                Some(raw) => Some(callback::validate_callback_url(raw, &state.config.callback).await?.to_string()),
+                // Operator-configured, so not subject to the agent-facing policy
+                None => state.config.webhook.callback_url(),
+            };
+
+            let package = validate_package_id(client, &api_key, &package_id).await?;
+            let pay_currency = match pay_currency {
+                Some(currency) => Some(resolve_pay_currency(client, &api_key, currency).await?),
+                None => None,
+            };
+
+            let mut result = client
+                .create_order(&api_key, &package.id, pay_currency.as_deref(), callback_url.as_deref())
+                .await?;
+            if let (Some(warning), Some(obj)) = (package.warning, result.as_object_mut()) {
+                obj.insert("warnings".to_string(), json!([warning]));
+            }
+            Ok(result)
+        }
+
+        "check_order_status" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            client.check_order_status(&api_key, &order_id).await
+        }
+
+        "payment_status" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let status_val = client.check_order_status(&api_key, &order_id).await?;
+            let info = PaymentInfo::from_value(&status_val);
+            Ok(payment::status_view(order_id.as_str(), &info, util::unix_now()))
+        }
+
+        "topup_order" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let package_id = get_str_arg(args, "package_id")?;
+            let pay_currency = args
+                .get("pay_currency")
+                .and_then(|v| v.as_str())
+                .or(state.config.default_pay_currency.as_deref());
+
+            let package = validate_package_id(client, &api_key, &package_id).await?;
+            let pay_currency = match pay_currency {
+                Some(currency) => Some(resolve_pay_currency(client, &api_key, currency).await?),
+                None => None,
+            };
+
+            let mut warnings: Vec<String> = package.warning.into_iter().collect();
+            if let Some(pkg) = &package.package {
+                warnings.extend(topup_warnings(client, &api_key, &order_id, pkg).await);
+            }
+
+            let mut result = client.topup_order(&api_key, &order_id, &package.id, pay_currency.as_deref()).await?;
+            if !warnings.is_empty() {
+                if let Some(obj) = result.as_object_mut() {
+                    obj.insert("warnings".to_string(), json!(warnings));
+                }
+            }
+            Ok(result)
+        }
+
+        "set_topup_rule" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let defaults = &state.config.topup;
+            let (max_topups, budget_usd) = defaults.limits(
+                args.get("max_topups").and_then(|v| v.as_u64()),
+                args.get("budget_usd").and_then(|v| v.as_f64()),
+            )?;
+            let mut spec = autotopup::RuleSpec {
+                threshold_pct: args
+                    .get("threshold_pct")
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(defaults.threshold_pct),
+                package_id: get_str_arg(args, "package_id")?,
+                pay_currency: args
+                    .get("pay_currency")
+                    .and_then(|v| v.as_str())
+                    .or(state.config.default_pay_currency.as_deref())
+                    .map(|s| s.to_string()),
+                max_topups,
+                budget_usd,
+            };
+            spec.validate()?;
+
+            let package = validate_package_id(client, &api_key, &spec.package_id).await?;
+            spec.package_id = package.id;
+            if let Some(currency) = &spec.pay_currency {
+                spec.pay_currency = Some(resolve_pay_currency(client, &api_key, currency).await?);
+            }
+            let mut warnings: Vec<String> = package.warning.into_iter().collect();
+            if let Some(pkg) = &package.package {
+                warnings.extend(topup_warnings(client, &api_key, &order_id, pkg).await);
+            }
+
+            let rule = state.autotopup.set(autotopup::TopupRule::new(order_id, &api_key, spec));
+            state.autotopup.ensure_monitor(client.clone(), autotopup::DEFAULT_POLL_INTERVAL);
+            // An order already over the threshold is topped up right away
+            state.autotopup.evaluate(client, &rule, "set").await;
+
+            let mut result = rule.snapshot();
+            if !warnings.is_empty() {
+                result["warnings"] = json!(warnings);
+            }
+            Ok(result)
+        }
+
+        "list_topup_rules" => Ok(json!({
+            "rules": state.autotopup.list().iter().map(|r| r.snapshot()).collect::<Vec<_>>()
+        })),
+
+        "delete_topup_rule" => {
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            match state.autotopup.remove(&order_id) {
+                Some(rule) => Ok(json!({ "deleted": rule.snapshot() })),
+                None => Err(format!("No auto top-up rule for order {}", order_id)),
+            }
+        }
+
+        "usage_report" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let window = args
+                .get("window_hours")
+                .and_then(|v| v.as_u64())
+                .map(|h| std::time::Duration::from_secs(h.clamp(1, 720) * 3600))
+                .unwrap_or(usage::DEFAULT_WINDOW);
+
+            // Fetching the status also stores a fresh sample; without the
+            // backend the report falls back to the stored history
+            let status_val = match client.check_order_status(&api_key, &order_id).await {
+                Ok(v) => Some(v),
+                Err(e) if state.usage.samples(order_id.as_str()).is_empty() => return Err(e),
+                Err(e) => {
+                    log::warn!("Reporting stored usage of {}: {}", order_id, e);
+                    None
+                }
+            };
+            let ledger = state.usage.ledger(order_id.as_str());
+            let order_package = status_val
+                .as_ref()
+                .and_then(|v| v.get("package_id"))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .or_else(|| ledger.iter().find(|e| e.kind == "order").and_then(|e| e.package_id.clone()));
+            let catalog_val = client.list_packages(&api_key, false).await.ok();
+
+            let mut result = usage::report(
+                order_id.as_str(),
+                &state.usage.samples(order_id.as_str()),
+                &ledger,
+                util::unix_now(),
+                window,
+                order_package.as_deref(),
+                |id| {
+                    let pkg = catalog_val.as_ref().and_then(|c| catalog::find_package(c, id));
+                    (pkg.and_then(catalog::package_bytes), pkg.and_then(catalog::package_price))
+                },
+            );
+            if status_val.is_none() {
+                result["warning"] = json!("The backend could not be reached; figures are from the last stored sample");
+            }
+            Ok(result)
+        }
+
+        "rotate_proxy" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            if !get_bool_arg(args, "verify") {
+                return state.rotate_now(&api_key, &order_id).await;
+            }
+
+            let echo_url = args
+                .get("echo_url")
+                .and_then(|v| v.as_str())
+                .unwrap_or(&state.config.echo_url);
+            let max_attempts = args
+                .get("max_attempts")
+                .and_then(|v| v.as_u64())
+                .map(|n| n.clamp(1, 10) as u32)
+                .unwrap_or(rotation::DEFAULT_MAX_ATTEMPTS);
+
+            let status_val = client.check_order_status(&api_key, &order_id).await?;
+            let proxy = OrderStatus::from_value(&status_val)?.active_proxy()?.clone();
+            let exit_ip = || probe::fetch_exit_ip(&proxy, echo_url, probe::DEFAULT_TIMEOUT);
+
+            let old_ip = match exit_ip().await {
+                Ok(ip) => Some(ip),
+                Err(e) => {
+                    log::warn!("Could not read exit IP of {} before rotating: {}", order_id, e);
+                    None
+                }
+            };
+            let (mut result, mut check) = rotation::rotate_until_changed(
+                old_ip,
+                || state.rotate_now(&api_key, &order_id),
+                exit_ip,
+                max_attempts,
+                rotation::SETTLE_DELAY,
+            )
+            .await?;
+
+            let expected_country = order_country(client, &api_key, &status_val).await;
+            check.locate(state.geoip.as_ref(), expected_country.as_deref());
+            result["verification"] = serde_json::to_value(&check).map_err(|e| e.to_string())?;
+            Ok(result)
+        }
+
+        "schedule_rotation" => {
+            let api_key = get_str_arg(args, "api_key")?;
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let defaults = &state.config.rotation;
+            let spec = ScheduleSpec {
+                interval: args
+                    .get("interval_secs")
+                    .and_then(|v| v.as_u64())
+                    .map(std::time::Duration::from_secs),
+                every_connections: args.get("every_connections").and_then(|v| v.as_u64()),
+                jitter_pct: args
+                    .get("jitter_pct")
+                    .and_then(|v| v.as_u64())
+                    .map(|n| n.min(u32::MAX as u64) as u32)
+                    .unwrap_or(defaults.jitter_pct),
+                max_per_hour: args
+                    .get("max_per_hour")
+                    .and_then(|v| v.as_u64())
+                    .map(|n| n.min(u32::MAX as u64) as u32)
+                    .unwrap_or(defaults.max_per_hour),
+            };
+            spec.validate()?;
+
+            // Fail early on orders that cannot be rotated
+            OrderStatus::from_value(&client.check_order_status(&api_key, &order_id).await?)?.active_proxy()?;
+            Ok(state.schedule_rotation(&api_key, order_id, spec)?.snapshot())
         }
 
-        _ => JsonRpcResponse::error(id, -32601, format!("Method not found: {}", req.method)),
-    }
-}
+        "list_schedules" => Ok(json!({
+            "schedules": state.scheduler.list().iter().map(|s| s.snapshot()).collect::<Vec<_>>()
+        })),
 
-async fn execute_tool(
-    client: &ProxyBaseClient,
-    tool_name: &str,
-    args: &Value,
-) -> Result<Value, String> {
-    match tool_name {
-        "register_agent" => client.register_agent().await,
+        "cancel_schedule" => {
+            let schedule_id = args.get("schedule_id").and_then(|v| v.as_str());
+            let order_id = args.get("order_id").and_then(|v| v.as_str());
+            if schedule_id.is_none() && order_id.is_none() {
+                return Err("Provide schedule_id or order_id to select the schedule to cancel".to_string());
+            }
 
-        "list_packages" => {
+            let cancelled = state.scheduler.cancel(
+                |s| {
+                    schedule_id.is_none_or(|id| s.id == id)
+                        && order_id.is_none_or(|id| s.order_id.as_str() == id)
+                },
+                "cancelled by request",
+            );
+            if cancelled.is_empty() {
+                return Err("No matching rotation schedule".to_string());
+            }
+            Ok(json!({
+                "cancelled": cancelled.iter().map(|s| s.snapshot()).collect::<Vec<_>>()
+            }))
+        }
+
+        "get_rotation_history" => {
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let limit = args
+                .get("limit")
+                .and_then(|v| v.as_u64())
+                .map(|n| n.clamp(1, 1000) as usize)
+                .unwrap_or(50);
+            Ok(json!({
+                "order_id": order_id.as_str(),
+                "rotations": state.scheduler.history().query(order_id.as_str(), limit)
+            }))
+        }
+
+        "test_proxy" => {
             let api_key = get_str_arg(args, "api_key")?;
-            client.list_packages(&api_key).await
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let echo_url = args
+                .get("echo_url")
+                .and_then(|v| v.as_str())
+                .unwrap_or(&state.config.echo_url);
+            let timeout = args
+                .get("timeout_secs")
+                .and_then(|v| v.as_u64())
+                .map(|secs| std::time::Duration::from_secs(secs.clamp(1, probe::MAX_TIMEOUT.as_secs())))
+                .unwrap_or(probe::DEFAULT_TIMEOUT);
+
+            let order = OrderStatus::from_value(&client.check_order_status(&api_key, &order_id).await?)?;
+            let report = probe::probe(order.active_proxy()?, echo_url, timeout).await;
+
+            let mut result = serde_json::to_value(&report).map_err(|e| e.to_string())?;
+            result["order_id"] = json!(order_id.as_str());
+            Ok(result)
         }
 
-        "list_currencies" => {
+        "export_proxy_config" => {
             let api_key = get_str_arg(args, "api_key")?;
-            client.list_currencies(&api_key).await
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let format = args.get("format").and_then(|v| v.as_str()).unwrap_or("all");
+
+            let order = OrderStatus::from_value(&client.check_order_status(&api_key, &order_id).await?)?;
+            let gateway = state
+                .gateways
+                .list()
+                .into_iter()
+                .find(|g| g.order_id() == Some(order_id.as_str()) && g.is_running())
+                .map(|g| g.listen);
+            export::export(order_id.as_str(), order.active_proxy()?, gateway, format)
         }
 
-        "create_order" => {
+        "http_fetch" => {
             let api_key = get_str_arg(args, "api_key")?;
-            let package_id = get_str_arg(args, "package_id")?;
-            let pay_currency = args.get("pay_currency").and_then(|v| v.as_str());
-
-            if let Some(currency) = pay_currency {
-                let currencies_val = client.list_currencies(&api_key).await?;
-                if let Some(currencies_arr) = currencies_val.get("currencies").and_then(|v| v.as_array()) {
-                    let valid_currencies: Vec<&str> = currencies_arr.iter().filter_map(|v| v.as_str()).collect();
-                    if !valid_currencies.contains(&currency.to_lowercase().as_str()) {
-                        return Err(format!("Invalid pay_currency: '{}'. Supported currencies: {}", currency, valid_currencies.join(", ")));
-                    }
-                }
-            }
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let request = fetch::FetchRequest::from_args(args)?;
 
-            let callback_url = args.get("callback_url").and_then(|v| v.as_str());
-            client.create_order(&api_key, &package_id, pay_currency, callback_url).await
+            let order = OrderStatus::from_value(&client.check_order_status(&api_key, &order_id).await?)?;
+            let mut result = fetch::fetch(order_id.as_str(), order.usable_proxy()?, &request).await?;
+            result["order_id"] = json!(order_id.as_str());
+            Ok(result)
         }
 
-        "check_order_status" => {
+        "start_local_gateway" => {
             let api_key = get_str_arg(args, "api_key")?;
-            let order_id = get_str_arg(args, "order_id")?;
-            client.check_order_status(&api_key, &order_id).await
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            let port = match args.get("port").and_then(|v| v.as_u64()) {
+                Some(port) => u16::try_from(port).map_err(|_| format!("Invalid port: {}", port))?,
+                None => 0,
+            };
+
+            let gateway = start_gateway(client, &api_key, &order_id, SocketAddr::from(([127, 0, 0, 1], port)), state.notifier.clone()).await?;
+            state.gateways.add(gateway.clone());
+            Ok(gateway.snapshot())
         }
 
-        "topup_order" => {
-            let api_key = get_str_arg(args, "api_key")?;
-            let order_id = get_str_arg(args, "order_id")?;
-            let package_id = get_str_arg(args, "package_id")?;
-            let pay_currency = args.get("pay_currency").and_then(|v| v.as_str());
-
-            if let Some(currency) = pay_currency {
-                let currencies_val = client.list_currencies(&api_key).await?;
-                if let Some(currencies_arr) = currencies_val.get("currencies").and_then(|v| v.as_array()) {
-                    let valid_currencies: Vec<&str> = currencies_arr.iter().filter_map(|v| v.as_str()).collect();
-                    if !valid_currencies.contains(&currency.to_lowercase().as_str()) {
-                        return Err(format!("Invalid pay_currency: '{}'. Supported currencies: {}", currency, valid_currencies.join(", ")));
-                    }
-                }
+        "stop_local_gateway" => {
+            let order_id = args.get("order_id").and_then(|v| v.as_str());
+            let port = args.get("port").and_then(|v| v.as_u64());
+            if order_id.is_none() && port.is_none() {
+                return Err("Provide order_id or port to select the gateway to stop".to_string());
             }
 
-            client.topup_order(&api_key, &order_id, &package_id, pay_currency).await
+            let stopped = state.gateways.remove(|g| {
+                order_id.is_none_or(|id| g.order_id() == Some(id))
+                    && port.is_none_or(|p| u64::from(g.listen.port()) == p)
+            });
+            if stopped.is_empty() {
+                return Err("No matching local gateway".to_string());
+            }
+            Ok(json!({
+                "stopped": stopped.iter().map(|g| g.snapshot()).collect::<Vec<_>>()
+            }))
         }
 
-        "rotate_proxy" => {
+        "pool_add_order" => {
             let api_key = get_str_arg(args, "api_key")?;
-            let order_id = get_str_arg(args, "order_id")?;
-            client.rotate_proxy(&api_key, &order_id).await
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+
+            let status_val = client.check_order_status(&api_key, &order_id).await?;
+            let order = OrderStatus::from_value(&status_val)?;
+            let proxy = order.usable_proxy()?.clone();
+            let country = match args.get("country").and_then(|v| v.as_str()) {
+                Some(country) => Some(country.to_string()),
+                None => order_country(client, &api_key, &status_val).await,
+            };
+
+            state.pool.add(PoolMember::new(order_id, &api_key, proxy, country, order.remaining_bytes));
+            state.pool.ensure_monitor(client.clone(), pool::DEFAULT_POLL_INTERVAL);
+            Ok(state.pool.snapshot())
+        }
+
+        "pool_remove_order" => {
+            let order_id = OrderId::parse(&get_str_arg(args, "order_id")?)?;
+            if !state.pool.remove(&order_id) {
+                return Err(format!("Order {} is not in the pool", order_id));
+            }
+            Ok(state.pool.snapshot())
+        }
+
+        "pool_status" => {
+            let mut result = state.pool.snapshot();
+            result["gateways"] = state
+                .gateways
+                .list()
+                .iter()
+                .filter(|g| g.order_id().is_none())
+                .map(|g| g.snapshot())
+                .collect();
+            Ok(result)
+        }
+
+        "start_pool_gateway" => {
+            let strategy = Strategy::parse(
+                args.get("strategy").and_then(|v| v.as_str()).unwrap_or("round_robin"),
+                args.get("country").and_then(|v| v.as_str()),
+            )?;
+            let port = match args.get("port").and_then(|v| v.as_u64()) {
+                Some(port) => u16::try_from(port).map_err(|_| format!("Invalid port: {}", port))?,
+                None => 0,
+            };
+
+            let route = Route::Pool {
+                pool: state.pool.clone(),
+                strategy,
+            };
+            let gateway = Gateway::bind(SocketAddr::from(([127, 0, 0, 1], port)), route).await?;
+            state.gateways.add(gateway.clone());
+            Ok(gateway.snapshot())
+        }
+
+        "list_local_gateways" => Ok(json!({
+            "gateways": state.gateways.list().iter().map(|g| g.snapshot()).collect::<Vec<_>>()
+        })),
+
+        "get_order_events" => {
+            let order_id = match args.get("order_id").and_then(|v| v.as_str()) {
+                Some(raw) => Some(OrderId::parse(raw)?),
+                None => None,
+            };
+            let since = args.get("since").and_then(|v| v.as_u64());
+            let limit = args
+                .get("limit")
+                .and_then(|v| v.as_u64())
+                .map(|n| n.clamp(1, 1000) as usize)
+                .unwrap_or(100);
+
+            let events = state.inbox.query(order_id.as_ref().map(|id| id.as_str()), since, limit);
+            let next_cursor = events.last().map(|e| e.seq).or(since).unwrap_or(0);
+            Ok(json!({
+                "events": events,
+                "next_cursor": next_cursor,
+                "webhook_receiver_enabled": state.config.webhook.enabled()
+            }))
+        }
+
+        "diagnose" => {
+            let options = doctor::Options {
+                api_key: args
+                    .get("api_key")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+                    .or(state.config.api_key.clone()),
+                max_clock_skew: args
+                    .get("max_clock_skew_secs")
+                    .and_then(|v| v.as_u64())
+                    .map(std::time::Duration::from_secs)
+                    .unwrap_or(doctor::DEFAULT_MAX_CLOCK_SKEW),
+                test_proxies: get_bool_arg(args, "test_proxies"),
+            };
+            Ok(doctor::run(client, &state.config, &state.usage.order_ids(), &options).await)
         }
 
         _ => Err(format!("Unknown tool: {}", tool_name)),
     }
 }
 
+/// Resolve `currency`, a code or a friendly name such as "USDT on Tron",
+/// against the (cached) currency list from the backend.
+async fn resolve_pay_currency(
+    client: &ProxyBaseClient,
+    api_key: &str,
+    currency: &str,
+) -> Result<String, String> {
+    let currencies_val = client.list_currencies(api_key, false).await?;
+    match currencies_val.get("currencies").and_then(|v| v.as_array()) {
+        Some(currencies_arr) => {
+            let valid_currencies: Vec<&str> = currencies_arr.iter().filter_map(|v| v.as_str()).collect();
+            currency::resolve(currency, &valid_currencies)
+        }
+        None => Ok(currency.to_string()),
+    }
+}
+
+/// Check `package_id` against the (cached) package catalog, returning the
+/// matching package when the catalog could be read.
+async fn validate_package_id(
+    client: &ProxyBaseClient,
+    api_key: &str,
+    package_id: &str,
+) -> Result<CheckedPackage, String> {
+    let catalog_val = match client.list_packages(api_key, false).await {
+        Ok(catalog_val) => catalog_val,
+        Err(e) => {
+            // The backend has the last word on the id anyway
+            log::warn!("Sending package_id '{}' unvalidated: {}", package_id, e);
+            return Ok(CheckedPackage {
+                id: package_id.to_string(),
+                package: None,
+                warning: Some(format!("package_id '{}' was not validated: the package catalog could not be fetched", package_id)),
+            });
+        }
+    };
+    let package = catalog::check_package(&catalog_val, package_id)?.cloned();
+    let id = package.as_ref().and_then(catalog::package_id).unwrap_or(package_id).to_string();
+    Ok(CheckedPackage { id, package, warning: None })
+}
+
+/// A `package_id` checked against the catalog.
+struct CheckedPackage {
+    /// The id as the catalog spells it, or as given when it was not checked.
+    id: String,
+    package: Option<Value>,
+    /// Why the id was not checked.
+    warning: Option<String>,
+}
+
+/// Compare a top-up package with the package the order was created with.
+/// Lookup failures only cost the warning, never the top-up itself.
+async fn topup_warnings(
+    client: &ProxyBaseClient,
+    api_key: &str,
+    order_id: &OrderId,
+    topup_package: &Value,
+) -> Vec<String> {
+    let order = match client.check_order_status(api_key, order_id).await {
+        Ok(order) => order,
+        Err(e) => {
+            log::debug!("Skipping top-up package comparison for {}: {}", order_id, e);
+            return Vec::new();
+        }
+    };
+
+    let original = match order.get("package_id").and_then(|v| v.as_str()) {
+        Some(id) => match client.list_packages(api_key, false).await {
+            Ok(catalog_val) => catalog::find_package(&catalog_val, id).cloned(),
+            Err(_) => None,
+        },
+        None => None,
+    };
+
+    // Fall back to attributes reported on the order itself
+    catalog::topup_mismatch(original.as_ref().unwrap_or(&order), topup_package)
+}
+
+/// Country of an order, as reported on the order or by its package.
+async fn order_country(client: &ProxyBaseClient, api_key: &str, order: &Value) -> Option<String> {
+    if let Some(country) = catalog::package_country(order) {
+        return Some(country.to_string());
+    }
+    let package_id = order.get("package_id").and_then(|v| v.as_str())?;
+    let catalog_val = client.list_packages(api_key, false).await.ok()?;
+    let package = catalog::find_package(&catalog_val, package_id)?;
+    catalog::package_country(package).map(|c| c.to_string())
+}
+
+/// Start a gateway for an active order and the monitor that stops it once
+/// the order is exhausted.
+async fn start_gateway(
+    client: &ProxyBaseClient,
+    api_key: &str,
+    order_id: &OrderId,
+    listen: SocketAddr,
+    notifier: Notifier,
+) -> Result<Arc<Gateway>, String> {
+    let order = OrderStatus::from_value(&client.check_order_status(api_key, order_id).await?)?;
+    let route = Route::Order {
+        order_id: order_id.to_string(),
+        proxy: order.usable_proxy()?.clone(),
+    };
+
+    let gateway = Gateway::bind(listen, route).await?;
+    gateway::spawn_monitor(
+        gateway.clone(),
+        client.clone(),
+        api_key.to_string(),
+        order_id.clone(),
+        order,
+        gateway::DEFAULT_POLL_INTERVAL,
+        notifier,
+    );
+    log::info!("Gateway for order {} listening on {}", order_id, gateway.listen);
+    Ok(gateway)
+}
+
 fn get_str_arg(args: &Value, key: &str) -> Result<String, String> {
     args.get(key)
         .and_then(|v| v.as_str())
@@ -519,30 +1833,402 @@ fn get_str_arg(args: &Value, key: &str) -> Result<String, String> {
         .ok_or_else(|| format!("Missing required argument: {}", key))
 }
 
+fn get_bool_arg(args: &Value, key: &str) -> bool {
+    args.get(key).and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
 // ---------------------------------------------------------------------------
 // Main: Stdio JSON-RPC Transport
 // ---------------------------------------------------------------------------
 
 #[tokio::main]
 async fn main() {
-    // Logging goes to stderr so it doesn't interfere with JSON-RPC on stdout
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .target(env_logger::Target::Stderr)
-        .init();
+    logging::init();
+
+    let (overrides, command) = match cli::split_global(std::env::args().skip(1))
+        .and_then(|(overrides, args)| Ok((overrides, Command::parse(args)?)))
+    {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = logging::configure(&config.log) {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(2);
+    }
+    let tools = get_tools();
+    let tool_names: Vec<&str> = tools.as_array().into_iter().flatten().filter_map(|t| t["name"].as_str()).collect();
+    if let Err(e) = config.tools.validate(&tool_names) {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(2);
+    }
+
+    match command {
+        Command::Serve => serve(config).await,
+        Command::Gateway { order_id, listen, api_key } => {
+            let result = run_gateway(config, &order_id, listen, api_key).await;
+            jsonl::flush();
+            if let Err(e) = result {
+                eprintln!("Error: {}", redact::secrets(&e));
+                std::process::exit(1);
+            }
+        }
+        Command::Help => print!("{}", cli::USAGE),
+        Command::ConfigShow { json: false } => print!("{}", config.show()),
+        Command::ConfigShow { json: true } => {
+            println!("{}", serde_json::to_string_pretty(&config.show_json()).unwrap_or_default())
+        }
+        Command::Install { client, config_path, api_key } => {
+            exit_on_error(run_install(&config, &client, config_path, api_key))
+        }
+        Command::Uninstall { client, config_path } => exit_on_error(run_uninstall(&client, config_path)),
+        command => {
+            let state = AppState::new(config, Notifier::default());
+            let result = run_command(&state, command).await;
+            // Usage samples and ledger entries are written in the background
+            jsonl::flush();
+            match result {
+                Ok(out) => print!("{}", out),
+                Err(e) => {
+                    eprintln!("Error: {}", redact::secrets(&e));
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(e) = result {
+        eprintln!("Error: {}", redact::secrets(&e));
+        std::process::exit(1);
+    }
+}
+
+/// `install` subcommand: point the client at this binary, passing on a
+/// custom backend URL and the API key if there is one.
+fn run_install(
+    config: &Config,
+    client: &str,
+    config_path: Option<std::path::PathBuf>,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    let client = install::Client::parse(client)?;
+    let path = client_config_path(client, config_path)?;
+    let exe = std::env::current_exe()
+        .and_then(|p| p.canonicalize())
+        .map_err(|e| format!("Cannot locate the proxybase-mcp binary: {}", e))?;
+
+    let mut env = Vec::new();
+    if config.api_url != config::DEFAULT_API_URL {
+        env.push(("PROXYBASE_API_URL", config.api_url.clone()));
+    }
+    if let Some(key) = api_key.or(config.api_key.clone()) {
+        env.push(("PROXYBASE_API_KEY", key));
+    }
+
+    let change = install::install(&path, install::server_entry(&exe, &env))?;
+    report_change(client, &path, &change);
+    if change.outcome != install::Outcome::Unchanged {
+        println!("Restart {} to load the server.", client.name());
+    }
+    Ok(())
+}
+
+fn run_uninstall(client: &str, config_path: Option<std::path::PathBuf>) -> Result<(), String> {
+    let client = install::Client::parse(client)?;
+    let path = client_config_path(client, config_path)?;
+    let change = install::uninstall(&path)?;
+    report_change(client, &path, &change);
+    Ok(())
+}
+
+fn client_config_path(client: install::Client, config_path: Option<std::path::PathBuf>) -> Result<std::path::PathBuf, String> {
+    config_path
+        .or_else(|| client.config_path())
+        .ok_or_else(|| format!("Cannot find the {} config directory; pass --config-path", client.name()))
+}
+
+fn report_change(client: install::Client, path: &std::path::Path, change: &install::Change) {
+    let what = match change.outcome {
+        install::Outcome::Added => "Added",
+        install::Outcome::Updated => "Updated",
+        install::Outcome::Removed => "Removed",
+        install::Outcome::Unchanged => "Nothing to change for",
+    };
+    println!("{} the '{}' server in {} ({})", what, install::SERVER_NAME, path.display(), client.name());
+    if let Some(backup) = &change.backup {
+        println!("Backup: {}", backup.display());
+    }
+}
+
+/// API subcommands. They run the same tools as the MCP server and render
+/// the result as a table, or as the raw JSON with `--json`. The tool policy
+/// only applies to MCP clients, so they work on locked-down servers too.
+async fn run_command(state: &AppState, command: Command) -> Result<String, String> {
+    let require_key = |api_key: Option<String>| {
+        api_key
+            .or(state.config.api_key.clone())
+            .ok_or("No API key: pass --api-key or set PROXYBASE_API_KEY")
+    };
+    let pretty = |value: &Value| format!("{}\n", serde_json::to_string_pretty(value).unwrap_or_default());
+
+    match command {
+        Command::Doctor { api_key, test_proxies, max_clock_skew, json } => {
+            let mut args = json!({ "test_proxies": test_proxies });
+            if let Some(key) = api_key {
+                args["api_key"] = json!(key);
+            }
+            if let Some(secs) = max_clock_skew {
+                args["max_clock_skew_secs"] = json!(secs);
+            }
+            let report = execute_tool(state, "diagnose", &args).await?;
+            let text = if json { pretty(&report) } else { doctor::render(&report) };
+            if report["ok"] == json!(false) {
+                // Still show the report, but exit non-zero
+                print!("{}", text);
+                return Err("some checks failed".to_string());
+            }
+            Ok(text)
+        }
+
+        Command::Register { json } => {
+            let value = execute_tool(state, "register_agent", &json!({})).await?;
+            Ok(if json { pretty(&value) } else { output::key_values(&value) })
+        }
+
+        Command::Packages { api_key, refresh, json } => {
+            let args = json!({ "api_key": require_key(api_key)?, "force_refresh": refresh });
+            let value = execute_tool(state, "list_packages", &args).await?;
+            if json {
+                return Ok(pretty(&value));
+            }
+            let rows: Vec<Vec<String>> = catalog::packages(&value)
+                .map(|pkgs| pkgs.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|pkg| {
+                    vec![
+                        catalog::package_id(pkg).unwrap_or("").to_string(),
+                        catalog::package_country(pkg).unwrap_or("").to_string(),
+                        catalog::package_type(pkg).unwrap_or("").to_string(),
+                        catalog::package_bytes(pkg).map(output::human_bytes).unwrap_or_default(),
+                        catalog::package_price(pkg).map(|p| format!("{:.2}", p)).unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            Ok(output::table(&["ID", "COUNTRY", "TYPE", "BANDWIDTH", "PRICE USD"], &rows))
+        }
+
+        Command::Orders { action: OrdersAction::List, api_key, json } => {
+            let api_key = api_key.or(state.config.api_key.clone());
+            let mut orders = Vec::new();
+            for id in state.usage.order_ids() {
+                orders.push(order_summary(state, api_key.as_deref(), &id).await);
+            }
+            if json {
+                return Ok(pretty(&json!(orders)));
+            }
+            if orders.is_empty() {
+                return Ok("No orders recorded locally yet. Orders created, topped up or checked with proxybase-mcp are remembered in its data directory.\n".to_string());
+            }
+            let rows: Vec<Vec<String>> = orders
+                .iter()
+                .map(|o| {
+                    let bytes = |key: &str| o.get(key).and_then(|v| v.as_u64());
+                    let usage = match (bytes("used_bytes"), bytes("bandwidth_bytes")) {
+                        (Some(used), Some(total)) if total > 0 => format!("{:.1}%", used as f64 * 100.0 / total as f64),
+                        _ => String::new(),
+                    };
+                    vec![
+                        output::scalar(&o["order_id"]),
+                        output::scalar(&o["status"]),
+                        bytes("used_bytes").map(output::human_bytes).unwrap_or_default(),
+                        bytes("bandwidth_bytes").map(output::human_bytes).unwrap_or_default(),
+                        usage,
+                        output::scalar(&o["package_id"]),
+                    ]
+                })
+                .collect();
+            Ok(output::table(&["ORDER", "STATUS", "USED", "BANDWIDTH", "USAGE", "PACKAGE"], &rows))
+        }
+
+        Command::Orders { action, api_key, json } => {
+            let api_key = require_key(api_key)?;
+            let (tool, args) = match action {
+                OrdersAction::Status { order_id } => ("check_order_status", json!({ "api_key": api_key, "order_id": order_id })),
+                OrdersAction::Rotate { order_id, verify } => (
+                    "rotate_proxy",
+                    json!({ "api_key": api_key, "order_id": order_id, "verify": verify }),
+                ),
+                OrdersAction::Topup { order_id, package_id, pay_currency } => (
+                    "topup_order",
+                    json!({
+                        "api_key": api_key,
+                        "order_id": order_id,
+                        "package_id": package_id,
+                        "pay_currency": pay_currency
+                    }),
+                ),
+                OrdersAction::List => unreachable!("handled above"),
+            };
+            let mut value = call_tool(state, tool, &args).await?.value;
+            if json {
+                return Ok(pretty(&value));
+            }
+            // The QR code is shown as drawn, not as a table cell
+            let qr = value
+                .get_mut("payment")
+                .and_then(|p| p.as_object_mut())
+                .and_then(|p| p.remove("qr_ascii"));
+            let mut out = output::key_values(&value);
+            if let Some(qr) = qr.as_ref().and_then(|v| v.as_str()) {
+                out.push('\n');
+                out.push_str(qr);
+            }
+            Ok(out)
+        }
+
+        _ => unreachable!("handled by main"),
+    }
+}
+
+/// Live status of a locally recorded order for `orders list`, falling back
+/// to the last stored sample when there is no API key or the backend fails.
+async fn order_summary(state: &AppState, api_key: Option<&str>, id: &str) -> Value {
+    let mut error = None;
+    if let (Some(api_key), Ok(order_id)) = (api_key, OrderId::parse(id)) {
+        match state.client.check_order_status(api_key, &order_id).await {
+            Ok(value) => return value,
+            Err(e) => error = Some(e),
+        }
+    }
+
+    let mut summary = json!({ "order_id": id });
+    if let Some(sample) = state.usage.samples(id).last() {
+        summary["status"] = json!(sample.status);
+        summary["used_bytes"] = json!(sample.used_bytes);
+        summary["bandwidth_bytes"] = json!(sample.bandwidth_bytes);
+        summary["as_of"] = json!(sample.at);
+    }
+    if let Some(entry) = state.usage.ledger(id).iter().find(|e| e.kind == "order") {
+        summary["package_id"] = json!(entry.package_id);
+    }
+    if let Some(e) = error {
+        summary["error"] = json!(e);
+    }
+    summary
+}
 
-    let base_url = std::env::var("PROXYBASE_API_URL")
-        .unwrap_or_else(|_| "https://api.proxybase.xyz".to_string());
+/// `gateway` subcommand: run a gateway in the foreground until Ctrl-C or
+/// until the order is exhausted.
+async fn run_gateway(
+    config: Config,
+    order_id: &str,
+    listen: SocketAddr,
+    api_key: Option<String>,
+) -> Result<(), String> {
+    let api_key = api_key
+        .or(config.api_key.clone())
+        .ok_or("No API key: pass --api-key or set PROXYBASE_API_KEY")?;
+    let order_id = OrderId::parse(order_id)?;
+    let state = AppState::new(config, Notifier::default());
+
+    let gateway = start_gateway(&state.client, &api_key, &order_id, listen, Notifier::default()).await?;
+    eprintln!("SOCKS5: socks5h://{}", gateway.listen);
+    eprintln!("HTTP:   http://{}", gateway.listen);
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => gateway.stop("interrupted"),
+        _ = gateway.stopped() => {}
+    }
+    eprintln!("{}", serde_json::to_string_pretty(&gateway.snapshot()).unwrap_or_default());
+    Ok(())
+}
 
-    log::info!("ProxyBase MCP Server starting (backend: {})", base_url);
+/// Run the MCP server on stdio until stdin closes.
+async fn serve(config: Config) {
+    log::info!("ProxyBase MCP Server starting (backend: {})", config.api_url);
+
+    // All stdout writes go through this channel so webhook notifications
+    // never interleave with responses.
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(mut line) = rx.recv().await {
+            // An empty message marks shutdown; background tasks may still
+            // hold senders, so the channel itself never closes.
+            if line.is_empty() {
+                break;
+            }
+            line.push('\n');
+            if stdout.write_all(line.as_bytes()).await.is_err() || stdout.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let state = AppState::new(config, Notifier::new(tx.clone()));
+
+    if let Err(e) = state.config.webhook.check() {
+        log::error!("Not starting the webhook listener: {}", e);
+    } else if let Some(addr) = state.config.webhook.listen {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                log::info!("Webhook listener on http://{}{}", addr, webhook::WEBHOOK_PATH);
+                let receiver = Arc::new(WebhookReceiver::new(
+                    state.config.webhook.secret.clone(),
+                    state.inbox.clone(),
+                    state.notifier.clone(),
+                ));
+                tokio::spawn(receiver.serve(listener));
+            }
+            Err(e) => log::error!("Failed to bind webhook listener on {}: {}", addr, e),
+        }
+    }
 
-    let client = ProxyBaseClient::new(&base_url);
+    // Read-only servers start nothing in the background either
+    if state.config.tools.read_only {
+        if !state.config.rotation.schedules.is_empty() {
+            log::warn!("Read-only mode: not starting the rotation schedules from the config");
+        }
+    } else {
+        state.usage.ensure_sampler(state.client.clone(), state.config.usage_sample_interval);
+
+        for (order_id, interval) in &state.config.rotation.schedules {
+            let spec = ScheduleSpec {
+                interval: Some(*interval),
+                every_connections: None,
+                jitter_pct: state.config.rotation.jitter_pct,
+                max_per_hour: state.config.rotation.max_per_hour,
+            };
+            let started = match &state.config.api_key {
+                Some(api_key) => OrderId::parse(order_id)
+                    .and_then(|id| state.schedule_rotation(api_key, id, spec)),
+                None => Err("PROXYBASE_API_KEY is not set".to_string()),
+            };
+            match started {
+                Ok(schedule) => log::info!("Rotating {} every {}s ({})", order_id, interval.as_secs(), schedule.id),
+                Err(e) => log::error!("Cannot schedule rotation of {}: {}", order_id, e),
+            }
+        }
+    }
 
-    let stdin = io::stdin();
-    let stdout = io::stdout();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
 
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(l)) => l,
+            Ok(None) => break,
             Err(e) => {
                 log::error!("Failed to read stdin: {}", e);
                 break;
@@ -563,16 +2249,13 @@ async fn main() {
                     -32700,
                     format!("Parse error: {}", e),
                 );
-                let mut out = stdout.lock();
-                let _ = serde_json::to_writer(&mut out, &error_resp);
-                let _ = writeln!(out);
-                let _ = out.flush();
+                let _ = tx.send(serde_json::to_string(&error_resp).unwrap_or_default());
                 continue;
             }
         };
 
         // Handle request
-        let response = handle_request(&client, &req).await;
+        let response = handle_request(&state, &req).await;
 
         // Don't send responses for notifications (no id)
         if req.id.is_none() {
@@ -580,12 +2263,14 @@ async fn main() {
         }
 
         // Write response
-        let mut out = stdout.lock();
-        let _ = serde_json::to_writer(&mut out, &response);
-        let _ = writeln!(out);
-        let _ = out.flush();
+        let _ = tx.send(serde_json::to_string(&response).unwrap_or_default());
     }
 
+    // Let the writer drain queued messages before exiting
+    let _ = tx.send(String::new());
+    let _ = writer.await;
+    jsonl::flush();
+
     log::info!("ProxyBase MCP Server shutting down");
 }
 
@@ -597,11 +2282,21 @@ async fn main() {
 mod tests {
     use super::*;
 
+    fn test_state() -> AppState {
+        AppState::new(
+            Config {
+                api_url: "http://localhost:9999".to_string(),
+                ..Config::default()
+            },
+            Notifier::default(),
+        )
+    }
+
     #[test]
     fn test_get_tools_valid_json() {
         let tools = get_tools();
         let arr = tools.as_array().unwrap();
-        assert_eq!(arr.len(), 7);
+        assert_eq!(arr.len(), 28);
 
         let names: Vec<&str> = arr
             .iter()
@@ -613,8 +2308,29 @@ mod tests {
         assert!(names.contains(&"list_currencies"));
         assert!(names.contains(&"create_order"));
         assert!(names.contains(&"check_order_status"));
+        assert!(names.contains(&"payment_status"));
+        assert!(names.contains(&"diagnose"));
         assert!(names.contains(&"topup_order"));
+        assert!(names.contains(&"set_topup_rule"));
+        assert!(names.contains(&"list_topup_rules"));
+        assert!(names.contains(&"delete_topup_rule"));
+        assert!(names.contains(&"usage_report"));
         assert!(names.contains(&"rotate_proxy"));
+        assert!(names.contains(&"get_order_events"));
+        assert!(names.contains(&"test_proxy"));
+        assert!(names.contains(&"schedule_rotation"));
+        assert!(names.contains(&"list_schedules"));
+        assert!(names.contains(&"cancel_schedule"));
+        assert!(names.contains(&"get_rotation_history"));
+        assert!(names.contains(&"http_fetch"));
+        assert!(names.contains(&"export_proxy_config"));
+        assert!(names.contains(&"start_local_gateway"));
+        assert!(names.contains(&"stop_local_gateway"));
+        assert!(names.contains(&"list_local_gateways"));
+        assert!(names.contains(&"pool_add_order"));
+        assert!(names.contains(&"pool_remove_order"));
+        assert!(names.contains(&"pool_status"));
+        assert!(names.contains(&"start_pool_gateway"));
     }
 
     #[test]
@@ -653,7 +2369,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_initialize() {
-        let client = ProxyBaseClient::new("http://localhost:9999");
+        let state = test_state();
         let req = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             id: Some(json!(1)),
@@ -661,16 +2377,17 @@ mod tests {
             params: None,
         };
 
-        let resp = handle_request(&client, &req).await;
+        let resp = handle_request(&state, &req).await;
         let result = resp.result.unwrap();
         assert_eq!(result["protocolVersion"], "2024-11-05");
         assert!(result["capabilities"]["tools"].is_object());
+        assert!(result["capabilities"]["logging"].is_object());
         assert_eq!(result["serverInfo"]["name"], "proxybase-mcp");
     }
 
     #[tokio::test]
     async fn test_handle_tools_list() {
-        let client = ProxyBaseClient::new("http://localhost:9999");
+        let state = test_state();
         let req = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             id: Some(json!(2)),
@@ -678,15 +2395,15 @@ mod tests {
             params: None,
         };
 
-        let resp = handle_request(&client, &req).await;
+        let resp = handle_request(&state, &req).await;
         let result = resp.result.unwrap();
         let tools = result["tools"].as_array().unwrap();
-        assert_eq!(tools.len(), 7);
+        assert_eq!(tools.len(), 28);
     }
 
     #[tokio::test]
     async fn test_handle_unknown_method() {
-        let client = ProxyBaseClient::new("http://localhost:9999");
+        let state = test_state();
         let req = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             id: Some(json!(3)),
@@ -694,14 +2411,14 @@ mod tests {
             params: None,
         };
 
-        let resp = handle_request(&client, &req).await;
+        let resp = handle_request(&state, &req).await;
         assert!(resp.error.is_some());
         assert_eq!(resp.error.unwrap().code, -32601);
     }
 
     #[tokio::test]
     async fn test_handle_tools_call_missing_arg() {
-        let client = ProxyBaseClient::new("http://localhost:9999");
+        let state = test_state();
         let req = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             id: Some(json!(4)),
@@ -712,10 +2429,451 @@ mod tests {
             })),
         };
 
-        let resp = handle_request(&client, &req).await;
+        let resp = handle_request(&state, &req).await;
         let result = resp.result.unwrap();
         assert_eq!(result["isError"], true);
         let text = result["content"][0]["text"].as_str().unwrap();
         assert!(text.contains("Missing required argument: api_key"));
     }
+
+    #[tokio::test]
+    async fn test_handle_tools_call_rejects_traversal_order_id() {
+        let state = test_state();
+        for order_id in ["../agents", "x?y="] {
+            let req = JsonRpcRequest {
+                jsonrpc: "2.0".to_string(),
+                id: Some(json!(5)),
+                method: "tools/call".to_string(),
+                params: Some(json!({
+                    "name": "check_order_status",
+                    "arguments": {"api_key": "pk_test", "order_id": order_id}
+                })),
+            };
+
+            let resp = handle_request(&state, &req).await;
+            let result = resp.result.unwrap();
+            assert_eq!(result["isError"], true);
+            let text = result["content"][0]["text"].as_str().unwrap();
+            assert!(text.starts_with("Invalid order_id"), "{}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_order_events_cursor() {
+        let state = test_state();
+        for (id, order) in [("e1", "a"), ("e2", "b"), ("e3", "a")] {
+            let event = inbox::OrderEvent::from_payload(json!({
+                "event_id": id, "order_id": order, "event": "bandwidth_80"
+            })).unwrap();
+            state.inbox.record(event);
+        }
+
+        let page = execute_tool(&state, "get_order_events", &json!({"order_id": "a"})).await.unwrap();
+        assert_eq!(page["events"].as_array().unwrap().len(), 2);
+        assert_eq!(page["next_cursor"], 3);
+
+        let page = execute_tool(&state, "get_order_events", &json!({"since": 1, "limit": 1})).await.unwrap();
+        assert_eq!(page["events"][0]["event_id"], "e2");
+        assert_eq!(page["next_cursor"], 2);
+
+        let page = execute_tool(&state, "get_order_events", &json!({"since": 3})).await.unwrap();
+        assert!(page["events"].as_array().unwrap().is_empty());
+        assert_eq!(page["next_cursor"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_rotation_validates_before_calling_backend() {
+        let state = test_state();
+        let err = execute_tool(&state, "schedule_rotation", &json!({
+            "api_key": "pk_test",
+            "order_id": "kQx7p3Wn"
+        })).await.unwrap_err();
+        assert!(err.contains("interval_secs"), "{}", err);
+
+        let err = execute_tool(&state, "schedule_rotation", &json!({
+            "api_key": "pk_test",
+            "order_id": "kQx7p3Wn",
+            "interval_secs": 600,
+            "max_per_hour": 0
+        })).await.unwrap_err();
+        assert!(err.contains("max_per_hour"), "{}", err);
+        assert!(state.scheduler.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_topup_rule_validates_before_calling_backend() {
+        let state = test_state();
+        let err = execute_tool(&state, "set_topup_rule", &json!({
+            "api_key": "pk_test",
+            "order_id": "kQx7p3Wn",
+            "package_id": "us_residential_1gb",
+            "threshold_pct": 120
+        })).await.unwrap_err();
+        assert!(err.contains("threshold_pct"), "{}", err);
+        assert!(state.autotopup.list().is_empty());
+
+        let err = execute_tool(&state, "delete_topup_rule", &json!({"order_id": "kQx7p3Wn"})).await.unwrap_err();
+        assert!(err.contains("No auto top-up rule"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_usage_report_samples_status_and_ledger() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "status": "proxy_active",
+                "package_id": "us_residential_1gb",
+                "bandwidth_bytes": 1073741824u64,
+                "used_bytes": 536870912u64
+            })),
+            ("POST", "/v1/orders/kQx7p3Wn/topup") => (200, json!({"order_id": "kQx7p3Wn", "price_usd": 10.0})),
+            ("GET", "/v1/packages") => (200, json!({"packages": [
+                {"id": "us_residential_1gb", "bandwidth_gb": 1, "price_usd": 10.0}
+            ]})),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+        let args = json!({"api_key": "pk_test", "order_id": "kQx7p3Wn"});
+
+        execute_tool(&state, "check_order_status", &args).await.unwrap();
+        let order_id = OrderId::parse("kQx7p3Wn").unwrap();
+        state.client.topup_order("pk_test", &order_id, "us_residential_1gb", None).await.unwrap();
+
+        let report = execute_tool(&state, "usage_report", &args).await.unwrap();
+        assert_eq!(report["samples"], 1);
+        assert_eq!(report["usage_percentage"], 50.0);
+        // The original order is priced from the catalog; the unpaid top-up
+        // does not fit in the current bandwidth
+        assert_eq!(report["cost"]["spent_usd"], 10.0);
+        assert_eq!(report["cost"]["pending_usd"], 10.0);
+        assert_eq!(report["cost"]["cost_per_gb_used"], 20.0);
+    }
+
+    #[tokio::test]
+    async fn test_payment_status_for_underpaid_invoice() {
+        let expires_at = util::unix_now() + 1800;
+        let base = http1::test_support::spawn_backend(move |method, target, _| match (method, target) {
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "status": "partially_paid",
+                "pay_currency": "usdttrc20",
+                "pay_address": "TXyz",
+                "pay_amount": 10.15,
+                "actually_paid": 9.73,
+                "expires_at": expires_at
+            })),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+        let out = call_tool(&state, "payment_status", &json!({"api_key": "pk_test", "order_id": "kQx7p3Wn"}))
+            .await
+            .unwrap();
+
+        assert_eq!(out.value["state"], "partially_paid");
+        assert_eq!(out.value["amount_due"], 0.42);
+        assert!(out.value["next_steps"][0].as_str().unwrap().contains("0.42 USDTTRC20 more to the same address TXyz"));
+        assert!(out.value["payment"]["uri"].as_str().unwrap().starts_with("tron:TXyz?amount=0.42"));
+        assert_eq!(out.extra.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cli_commands_render_tables() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/packages") => (200, json!({"packages": [
+                {"id": "us_residential_1gb", "country": "US", "proxy_type": "residential", "bandwidth_gb": 1, "price_usd": 10.0}
+            ]})),
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "status": "proxy_active",
+                "package_id": "us_residential_1gb",
+                "bandwidth_bytes": 1073741824u64,
+                "used_bytes": 536870912u64,
+                "proxy": {"host": "api.proxybase.xyz", "port": 1080, "username": "u", "password": "p"}
+            })),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+        let cli = |args: &[&str]| Command::parse(args.iter().map(|s| s.to_string())).unwrap();
+
+        let out = run_command(&state, cli(&["packages", "--api-key", "pk_test"])).await.unwrap();
+        assert_eq!(out.lines().nth(1).unwrap(), "us_residential_1gb  US       residential  1 GiB      10.00");
+        let err = run_command(&state, cli(&["orders", "status", "kQx7p3Wn"])).await.unwrap_err();
+        assert!(err.contains("No API key"), "{}", err);
+
+        assert!(run_command(&state, cli(&["orders", "list"])).await.unwrap().starts_with("No orders"));
+        let out = run_command(&state, cli(&["orders", "status", "kQx7p3Wn", "--api-key", "pk_test"])).await.unwrap();
+        assert!(out.lines().any(|l| l.starts_with("proxy.host ") && l.ends_with(" api.proxybase.xyz")), "{}", out);
+
+        // Without a key the list falls back to the recorded sample
+        let out = run_command(&state, cli(&["orders", "list"])).await.unwrap();
+        assert_eq!(out.lines().nth(1).unwrap(), "kQx7p3Wn  proxy_active  512 MiB  1 GiB      50.0%");
+        let out = run_command(&state, cli(&["orders", "list", "--json", "--api-key", "pk_test"])).await.unwrap();
+        let orders: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(orders[0]["package_id"], "us_residential_1gb");
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_reports_each_check() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb"}]})),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+
+        let report = execute_tool(&state, "diagnose", &json!({"api_key": "pk_test"})).await.unwrap();
+        let status = |name: &str| {
+            report["checks"].as_array().unwrap().iter().find(|c| c["name"] == name).unwrap()["status"].clone()
+        };
+        assert_eq!(status("api_url"), "pass");
+        assert_eq!(status("reachable"), "pass");
+        // Plain http works, but is flagged
+        assert_eq!(status("tls"), "warn");
+        assert_eq!(status("api_key"), "pass");
+
+        let report = execute_tool(&state, "diagnose", &json!({})).await.unwrap();
+        assert_eq!(report["checks"][4]["status"], "skip");
+        assert!(report["checks"][4]["hint"].as_str().unwrap().contains("PROXYBASE_API_KEY"));
+
+        let state = AppState::new(Config { api_url: "http://127.0.0.1:1".to_string(), ..Config::default() }, Notifier::default());
+        let report = execute_tool(&state, "diagnose", &json!({"api_key": "pk_test"})).await.unwrap();
+        assert_eq!(report["ok"], false);
+        assert_eq!(report["checks"][1]["status"], "fail");
+        assert_eq!(report["checks"][4]["status"], "skip");
+        assert!(doctor::render(&report).contains("[FAIL] reachable"));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_mode_hides_and_rejects_tools() {
+        let mut config = Config::default();
+        config.tools.read_only = true;
+        let state = AppState::new(config, Notifier::default());
+        let req = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/list".to_string(),
+            params: None,
+        };
+        let result = handle_request(&state, &req).await.result.unwrap();
+        let listed: Vec<&str> = result["tools"].as_array().unwrap().iter().filter_map(|t| t["name"].as_str()).collect();
+        assert_eq!(listed, ["list_packages", "list_currencies", "check_order_status"]);
+
+        let req = JsonRpcRequest {
+            method: "tools/call".to_string(),
+            params: Some(json!({"name": "create_order", "arguments": {"api_key": "pk_test", "package_id": "x"}})),
+            ..req
+        };
+        let result = handle_request(&state, &req).await.result.unwrap();
+        assert_eq!(result["isError"], true);
+        assert_eq!(result["content"][0]["text"], "Tool 'create_order' is disabled on this server (read-only mode)");
+    }
+
+    #[tokio::test]
+    async fn test_cli_commands_ignore_the_tool_policy() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb"}]})),
+            _ => (404, json!({})),
+        })
+        .await;
+        let mut config = Config { api_url: base, ..Config::default() };
+        config.tools.read_only = true;
+        config.tools.disabled = vec!["diagnose".to_string(), "list_packages".to_string()];
+        let state = AppState::new(config, Notifier::default());
+
+        let packages = Command::Packages { api_key: Some("pk_test".to_string()), refresh: false, json: false };
+        assert!(run_command(&state, packages).await.unwrap().contains("us_residential_1gb"));
+
+        let doctor = Command::Doctor { api_key: Some("pk_test".to_string()), test_proxies: false, max_clock_skew: None, json: true };
+        // Local file permissions may fail the report, but it always runs
+        match run_command(&state, doctor).await {
+            Ok(report) => assert!(report.contains("\"api_key\"")),
+            Err(e) => assert_eq!(e, "some checks failed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice_carries_payment_uri_and_qr_image() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb"}]})),
+            ("POST", "/v1/orders") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "pay_address": "TXyz",
+                "pay_currency": "usdttrc20",
+                "pay_amount": 10.15,
+                "price_usd": 10.0,
+                "status": "payment_pending"
+            })),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+        let req = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(6)),
+            method: "tools/call".to_string(),
+            params: Some(json!({
+                "name": "create_order",
+                "arguments": {"api_key": "pk_test", "package_id": "us_residential_1gb"}
+            })),
+        };
+
+        let result = handle_request(&state, &req).await.result.unwrap();
+        let content = result["content"].as_array().unwrap();
+        assert_eq!(content.len(), 2);
+        let text: Value = serde_json::from_str(content[0]["text"].as_str().unwrap()).unwrap();
+        assert!(text["payment"]["uri"].as_str().unwrap().starts_with("tron:TXyz?amount=10.15"));
+        assert_eq!(content[1]["type"], "image");
+        assert_eq!(content[1]["mimeType"], "image/png");
+    }
+
+    #[test]
+    fn test_api_error_status() {
+        let err = api_error(reqwest::StatusCode::TOO_MANY_REQUESTS, &json!({}));
+        assert_eq!(api_error_status(&err), Some(429));
+        assert_eq!(api_error_status("API error (404 Not Found): {}"), Some(404));
+        assert_eq!(api_error_status("Request failed: connection refused"), None);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_not_modified_is_a_clear_error() {
+        let base = http1::test_support::spawn_backend(|_, _, _| (304, Value::Null)).await;
+        let client = ProxyBaseClient::new(&base);
+        let err = client.list_packages("pk_test", false).await.unwrap_err();
+        assert!(err.starts_with("API error (304 Not Modified): GET /v1/packages"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_orders_carry_the_catalog_package_id() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let catalog_down = Arc::new(AtomicBool::new(false));
+        let down = catalog_down.clone();
+        let base = http1::test_support::spawn_backend(move |method, target, body| match (method, target) {
+            ("GET", "/v1/packages") if down.load(Ordering::SeqCst) => (500, json!({"error": "catalog unavailable"})),
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb"}]})),
+            ("POST", "/v1/orders") => (200, json!({"order_id": "kQx7p3Wn", "package_id": body["package_id"]})),
+            ("POST", "/v1/orders/kQx7p3Wn/topup") => (200, json!({"order_id": "kQx7p3Wn", "package_id": body["package_id"]})),
+            _ => (404, json!({})),
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+
+        let result = execute_tool(&state, "create_order", &json!({"api_key": "pk_test", "package_id": "US_Residential_1GB"}))
+            .await
+            .unwrap();
+        assert_eq!(result["package_id"], "us_residential_1gb");
+        assert!(result.get("warnings").is_none());
+        let args = json!({"api_key": "pk_test", "order_id": "kQx7p3Wn", "package_id": "US_RESIDENTIAL_1GB"});
+        assert_eq!(execute_tool(&state, "topup_order", &args).await.unwrap()["package_id"], "us_residential_1gb");
+
+        // Without a catalog the order goes out as given, with a warning
+        // (another key, so the cached catalog is not used)
+        catalog_down.store(true, Ordering::SeqCst);
+        let result = execute_tool(&state, "create_order", &json!({"api_key": "pk_other", "package_id": "de_mobile_5gb"}))
+            .await
+            .unwrap();
+        assert_eq!(result["package_id"], "de_mobile_5gb");
+        assert!(result["warnings"][0].as_str().unwrap().contains("was not validated"), "{}", result);
+    }
+
+    #[tokio::test]
+    async fn test_pay_currency_aliases_resolve_against_live_list() {
+        let base = http1::test_support::spawn_backend(|method, target, body| match (method, target) {
+            ("GET", "/v1/packages") => (200, json!({"packages": [{"id": "us_residential_1gb"}]})),
+            ("GET", "/v1/currencies") => (200, json!({"currencies": ["btc", "usdttrc20", "usdterc20", "usdcsol"]})),
+            ("POST", "/v1/orders") => (200, json!({"order_id": "kQx7p3Wn", "pay_currency": body["pay_currency"]})),
+            _ => (404, json!({})),
+        })
+        .await;
+        let config = Config { api_url: base, default_pay_currency: Some("USDT on Tron".to_string()), ..Config::default() };
+        let state = AppState::new(config, Notifier::default());
+
+        let args = json!({"api_key": "pk_test", "package_id": "us_residential_1gb"});
+        let order = execute_tool(&state, "create_order", &args).await.unwrap();
+        assert_eq!(order["pay_currency"], "usdttrc20");
+
+        let args = json!({"api_key": "pk_test", "package_id": "us_residential_1gb", "pay_currency": "USDC Solana"});
+        let order = execute_tool(&state, "create_order", &args).await.unwrap();
+        assert_eq!(order["pay_currency"], "usdcsol");
+
+        let args = json!({"api_key": "pk_test", "package_id": "us_residential_1gb", "pay_currency": "usdt"});
+        let err = execute_tool(&state, "create_order", &args).await.unwrap_err();
+        assert!(err.contains("matches usdttrc20, usdterc20"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_reaches_backend_and_gets_are_retried() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let base = http1::test_support::spawn_backend_with_head(move |head, _| {
+            let mut seen = log.lock().unwrap();
+            seen.push((head.target.clone(), head.header("X-Request-Id").unwrap_or_default().to_string()));
+            match (head.method.as_str(), head.target.as_str()) {
+                // Unavailable on the first attempt only
+                ("GET", "/v1/packages") if seen.len() == 1 => (503, json!({})),
+                ("GET", "/v1/packages") => (200, json!({"packages": []})),
+                ("POST", "/v1/orders/kQx7p3Wn/rotate") => (503, json!({"error": "busy"})),
+                _ => (404, json!({})),
+            }
+        })
+        .await;
+        let state = AppState::new(Config { api_url: base, ..Config::default() }, Notifier::default());
+
+        let request = trace::Request::new();
+        request
+            .scope(execute_tool(&state, "list_packages", &json!({"api_key": "pk_test"})))
+            .await
+            .unwrap();
+        // POSTs are not retried
+        let err = request.scope(state.client.rotate_proxy("pk_test", &OrderId::parse("kQx7p3Wn").unwrap())).await;
+        assert!(err.unwrap_err().contains("503"));
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.iter().all(|(_, id)| *id == request.id), "{:?}", seen);
+        let calls = request.calls();
+        assert_eq!((calls[0].endpoint.as_str(), calls[0].status, calls[0].retries), ("GET /v1/packages", Some(200), 1));
+        assert_eq!((calls[1].endpoint.as_str(), calls[1].status, calls[1].retries), ("POST /v1/orders/kQx7p3Wn/rotate", Some(503), 0));
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_masked_in_errors_and_optionally_in_results() {
+        let base = http1::test_support::spawn_backend(|method, target, _| match (method, target) {
+            ("GET", "/v1/orders/kQx7p3Wn/status") => (200, json!({
+                "order_id": "kQx7p3Wn",
+                "status": "proxy_active",
+                "proxy": {"host": "api.proxybase.xyz", "port": 1080, "username": "pb_user", "password": "9f8e7d6c5b4a3210"}
+            })),
+            _ => (401, json!({"error": "Invalid API key pk_live_1234567890abcdef"})),
+        })
+        .await;
+        let config = Config { api_url: base, mask_proxy_passwords: true, ..Config::default() };
+        let state = AppState::new(config, Notifier::default());
+        let call = |name: &str| JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/call".to_string(),
+            params: Some(json!({"name": name, "arguments": {"api_key": "pk_live_1234567890abcdef", "order_id": "kQx7p3Wn"}})),
+        };
+
+        let result = handle_request(&state, &call("list_packages")).await.result.unwrap();
+        assert_eq!(result["content"][0]["text"], "API error (401 Unauthorized): {\"error\":\"Invalid API key pk_…cdef\"}");
+
+        let result = handle_request(&state, &call("export_proxy_config")).await.result.unwrap();
+        let text = result["content"][0]["text"].as_str().unwrap();
+        assert!(text.contains("pb_user") && !text.contains("9f8e7d6c5b4a3210"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_create_order_rejects_internal_callback() {
+        let state = test_state();
+        let err = execute_tool(&state, "create_order", &json!({
+            "api_key": "pk_test",
+            "package_id": "us_residential_1gb",
+            "callback_url": "https://169.254.169.254/latest/meta-data"
+        })).await.unwrap_err();
+        assert!(err.contains("link-local"), "{}", err);
+    }
 }
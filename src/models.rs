@@ -0,0 +1,282 @@
+//! Typed views of backend responses.
+//!
+//! Tool results are still passed through as raw JSON; these types are used
+//! where the server itself needs to act on a response (e.g. connect to the
+//! proxy). Parsing is lenient so new backend fields never break anything.
+
+use crate::util::parse_rfc3339;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const STATUS_CONFIRMING: &str = "confirming";
+pub const STATUS_PARTIALLY_PAID: &str = "partially_paid";
+pub const STATUS_EXPIRED: &str = "expired";
+pub const STATUS_PAID: &str = "paid";
+pub const STATUS_PROXY_ACTIVE: &str = "proxy_active";
+pub const STATUS_BANDWIDTH_EXHAUSTED: &str = "bandwidth_exhausted";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyCredentials {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyCredentials {
+    /// `host:port`, with IPv6 literals bracketed.
+    pub fn addr(&self) -> String {
+        if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderStatus {
+    pub status: String,
+    #[serde(default)]
+    pub bandwidth_bytes: Option<u64>,
+    #[serde(default)]
+    pub used_bytes: Option<u64>,
+    #[serde(default)]
+    pub remaining_bytes: Option<u64>,
+    #[serde(default)]
+    pub usage_percentage: Option<f64>,
+    #[serde(default)]
+    pub proxy: Option<ProxyCredentials>,
+}
+
+impl OrderStatus {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        serde_json::from_value(value.clone())
+            .map_err(|e| format!("Unexpected order status response: {}", e))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == STATUS_PROXY_ACTIVE
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.status == STATUS_BANDWIDTH_EXHAUSTED || self.remaining_bytes == Some(0)
+    }
+
+    /// Percentage of the bandwidth used, as reported or computed from the
+    /// byte counts.
+    pub fn usage(&self) -> Option<f64> {
+        self.usage_percentage.or_else(|| {
+            let total = self.bandwidth_bytes.filter(|t| *t > 0)?;
+            let used = self
+                .used_bytes
+                .or_else(|| self.remaining_bytes.map(|r| total.saturating_sub(r)))?;
+            Some(used as f64 * 100.0 / total as f64)
+        })
+    }
+
+    /// Credentials of an active proxy, or an error explaining why there are none.
+    pub fn active_proxy(&self) -> Result<&ProxyCredentials, String> {
+        match (&self.proxy, self.is_active()) {
+            (Some(proxy), true) => Ok(proxy),
+            _ => Err(format!(
+                "Order is '{}', not '{}'; proxy credentials are only available once the proxy is active",
+                self.status, STATUS_PROXY_ACTIVE
+            )),
+        }
+    }
+
+    /// Like [`active_proxy`](Self::active_proxy), but also refuses orders with
+    /// no bandwidth left.
+    pub fn usable_proxy(&self) -> Result<&ProxyCredentials, String> {
+        if self.is_exhausted() {
+            return Err("Order has no bandwidth left; top it up first".to_string());
+        }
+        self.active_proxy()
+    }
+}
+
+/// Where an order's invoice stands, derived from the order status, the
+/// amounts and the expiry time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentState {
+    AwaitingPayment,
+    PartiallyPaid,
+    Confirming,
+    Paid,
+    Expired,
+}
+
+/// Payment fields of an order status. Field names fall back to the
+/// payment processor's (`actually_paid`, `expiration_estimate_date`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentInfo {
+    pub order_status: String,
+    pub pay_address: Option<String>,
+    pub pay_currency: Option<String>,
+    pub amount_expected: Option<f64>,
+    pub amount_received: Option<f64>,
+    /// Unix timestamp (seconds) after which the invoice is void.
+    pub expires_at: Option<u64>,
+    /// Memo / destination tag the payment must carry.
+    pub extra_id: Option<String>,
+}
+
+impl PaymentInfo {
+    pub fn from_value(value: &Value) -> Self {
+        let text = |keys: &[&str]| {
+            keys.iter().find_map(|k| match value.get(*k) {
+                Some(Value::String(s)) if !s.is_empty() => Some(s.clone()),
+                Some(Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            })
+        };
+        let amount = |keys: &[&str]| text(keys).and_then(|v| v.parse::<f64>().ok());
+        let expires_at = ["expires_at", "expiration_estimate_date", "valid_until"]
+            .iter()
+            .find_map(|k| match value.get(*k)? {
+                // Milliseconds are told apart from seconds by magnitude
+                Value::Number(n) => n.as_u64().map(|t| if t > 100_000_000_000 { t / 1000 } else { t }),
+                Value::String(s) => parse_rfc3339(s).or_else(|| s.parse().ok()),
+                _ => None,
+            });
+
+        Self {
+            order_status: text(&["status"]).unwrap_or_default(),
+            pay_address: text(&["pay_address"]),
+            pay_currency: text(&["pay_currency"]).map(|c| c.to_lowercase()),
+            amount_expected: amount(&["pay_amount"]),
+            amount_received: amount(&["amount_received", "actually_paid", "paid_amount"]),
+            expires_at,
+            extra_id: text(&["payin_extra_id", "extra_id"]),
+        }
+    }
+
+    /// What is still missing, or `None` if the expected amount is unknown.
+    pub fn amount_due(&self) -> Option<f64> {
+        let expected = self.amount_expected?;
+        let due = expected - self.amount_received.unwrap_or(0.0);
+        // Ignore float noise and dust well below any wallet's precision
+        Some(if due > expected * 1e-6 { due } else { 0.0 })
+    }
+
+    pub fn state(&self, now: u64) -> PaymentState {
+        match self.order_status.as_str() {
+            STATUS_PAID | STATUS_PROXY_ACTIVE | STATUS_BANDWIDTH_EXHAUSTED => return PaymentState::Paid,
+            STATUS_EXPIRED => return PaymentState::Expired,
+            _ => {}
+        }
+        let received = self.amount_received.unwrap_or(0.0) > 0.0;
+        let short = self.amount_due().is_none_or(|due| due > 0.0);
+
+        if self.expires_at.is_some_and(|t| now >= t) && (!received || short) {
+            PaymentState::Expired
+        } else if self.order_status == STATUS_PARTIALLY_PAID || (received && short) {
+            PaymentState::PartiallyPaid
+        } else if self.order_status == STATUS_CONFIRMING || received {
+            PaymentState::Confirming
+        } else {
+            PaymentState::AwaitingPayment
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_active_order() {
+        let status = OrderStatus::from_value(&json!({
+            "order_id": "kQx7p3Wn",
+            "status": "proxy_active",
+            "bandwidth_bytes": 1073741824u64,
+            "used_bytes": 52428800,
+            "remaining_bytes": 1021313024,
+            "usage_percentage": 4.88,
+            "proxy": {
+                "host": "api.proxybase.xyz",
+                "port": 1080,
+                "username": "pb_a1b2c3d4e5f6g7h8",
+                "password": "9f8e7d6c5b4a3210"
+            },
+            "some_new_field": true
+        }))
+        .unwrap();
+
+        let proxy = status.active_proxy().unwrap();
+        assert_eq!(proxy.addr(), "api.proxybase.xyz:1080");
+        assert!(!status.is_exhausted());
+        assert_eq!(status.usage(), Some(4.88));
+    }
+
+    #[test]
+    fn test_exhausted_order() {
+        let status = OrderStatus::from_value(&json!({
+            "status": "proxy_active",
+            "remaining_bytes": 0
+        }))
+        .unwrap();
+        assert!(status.is_exhausted());
+        assert!(status.usable_proxy().unwrap_err().contains("top it up"));
+
+        let status = OrderStatus::from_value(&json!({"status": "bandwidth_exhausted"})).unwrap();
+        assert!(status.is_exhausted());
+        assert_eq!(status.usage(), None);
+
+        // Computed when the backend omits the percentage
+        let status = OrderStatus::from_value(&json!({
+            "status": "proxy_active",
+            "bandwidth_bytes": 1000,
+            "remaining_bytes": 150
+        }))
+        .unwrap();
+        assert_eq!(status.usage(), Some(85.0));
+    }
+
+    #[test]
+    fn test_payment_states() {
+        let now = 1_760_000_000;
+        let info = |v: Value| PaymentInfo::from_value(&v);
+
+        let pending = info(json!({
+            "status": "payment_pending",
+            "pay_amount": 10.15,
+            "expires_at": "2025-10-09T09:23:20Z"
+        }));
+        assert_eq!(pending.expires_at, Some(now + 1800));
+        assert_eq!(pending.state(now), PaymentState::AwaitingPayment);
+        assert_eq!(pending.state(now + 1800), PaymentState::Expired);
+
+        let partial = info(json!({
+            "status": "payment_pending",
+            "pay_amount": "10.15",
+            "actually_paid": 9.73
+        }));
+        assert!((partial.amount_due().unwrap() - 0.42).abs() < 1e-9);
+        assert_eq!(partial.state(now), PaymentState::PartiallyPaid);
+
+        let full = info(json!({"status": "confirming", "pay_amount": 10.15, "amount_received": 10.15}));
+        assert_eq!(full.amount_due(), Some(0.0));
+        // Fully paid invoices do not expire while confirming
+        assert_eq!(full.state(u64::MAX), PaymentState::Confirming);
+
+        assert_eq!(info(json!({"status": "proxy_active"})).state(now), PaymentState::Paid);
+        assert_eq!(info(json!({"status": "expired"})).state(now), PaymentState::Expired);
+        let ms = info(json!({"status": "payment_pending", "expires_at": 1_760_001_800_000u64}));
+        assert_eq!(ms.expires_at, Some(now + 1800));
+    }
+
+    #[test]
+    fn test_pending_order_has_no_proxy() {
+        let status = OrderStatus::from_value(&json!({
+            "order_id": "kQx7p3Wn",
+            "status": "payment_pending"
+        }))
+        .unwrap();
+        let err = status.active_proxy().unwrap_err();
+        assert!(err.contains("payment_pending"));
+    }
+}
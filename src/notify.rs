@@ -0,0 +1,41 @@
+//! Server-initiated messages to the MCP client.
+//!
+//! Everything written to stdout goes through one writer task; background
+//! work holds a [`Notifier`] to queue JSON-RPC notifications alongside the
+//! regular responses without interleaving partial lines.
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    tx: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl Notifier {
+    /// `tx` carries serialized JSON-RPC messages, one per line.
+    pub fn new(tx: mpsc::UnboundedSender<String>) -> Self {
+        Self { tx: Some(tx) }
+    }
+
+    /// Send a `notifications/message` log notification to the client.
+    pub fn log(&self, level: &str, logger: &str, data: Value) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/message",
+            "params": {
+                "level": level,
+                "logger": logger,
+                "data": data
+            }
+        }));
+    }
+
+    fn send(&self, message: Value) {
+        if let Some(tx) = &self.tx {
+            if tx.send(message.to_string()).is_err() {
+                log::debug!("Dropping notification: client output closed");
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Validated order identifiers.
+//!
+//! Order ids come straight from the agent and end up in request paths, so
+//! they are checked against the backend's id alphabet before any URL is
+//! built. URL construction itself goes through [`reqwest::Url`] path
+//! segments, which percent-encode anything that slips through.
+
+use std::fmt;
+
+/// Longest order id the backend issues, with headroom for future formats.
+pub const MAX_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OrderId(String);
+
+impl OrderId {
+    /// Accepts 1–64 characters of `[A-Za-z0-9_-]`.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        if raw.is_empty() {
+            return Err("Invalid order_id: must not be empty".to_string());
+        }
+        if raw.len() > MAX_LEN {
+            return Err(format!(
+                "Invalid order_id: must be at most {} characters (got {})",
+                MAX_LEN,
+                raw.len()
+            ));
+        }
+        if let Some(c) = raw
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+        {
+            return Err(format!(
+                "Invalid order_id: '{}' contains {:?}; only letters, digits, '_' and '-' are allowed",
+                raw, c
+            ));
+        }
+        Ok(Self(raw.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Build `{base_url}/v1/orders/{order_id}/{action}` with each part appended
+/// as an encoded path segment.
+pub fn order_url(base_url: &str, order_id: &OrderId, action: &str) -> Result<reqwest::Url, String> {
+    let mut url = reqwest::Url::parse(base_url)
+        .map_err(|e| format!("Invalid backend URL '{}': {}", base_url, e))?;
+    url.path_segments_mut()
+        .map_err(|_| format!("Invalid backend URL '{}': cannot be a base", base_url))?
+        .pop_if_empty()
+        .extend(["v1", "orders", order_id.as_str(), action]);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_backend_ids() {
+        for id in ["kQx7p3Wn", "6xAMqAGN", "order_123-abc"] {
+            assert_eq!(OrderId::parse(id).unwrap().as_str(), id);
+        }
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        for id in [
+            "../agents",
+            "..",
+            "x/../../agents",
+            "x?y=",
+            "x#frag",
+            "%2e%2e",
+            "a b",
+            "ordér",
+            "x\\y",
+        ] {
+            assert!(OrderId::parse(id).is_err(), "accepted {:?}", id);
+        }
+    }
+
+    #[test]
+    fn test_rejects_empty_and_long() {
+        assert!(OrderId::parse("").is_err());
+        assert!(OrderId::parse(&"a".repeat(MAX_LEN)).is_ok());
+        assert!(OrderId::parse(&"a".repeat(MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_order_url() {
+        let id = OrderId::parse("kQx7p3Wn").unwrap();
+        let url = order_url("https://api.proxybase.xyz", &id, "status").unwrap();
+        assert_eq!(url.as_str(), "https://api.proxybase.xyz/v1/orders/kQx7p3Wn/status");
+
+        let url = order_url("http://localhost:8080/api/", &id, "rotate").unwrap();
+        assert_eq!(url.as_str(), "http://localhost:8080/api/v1/orders/kQx7p3Wn/rotate");
+    }
+
+    #[test]
+    fn test_order_url_encodes_segments() {
+        // Bypass validation to check the second line of defence
+        let id = OrderId("../agents?x=1".to_string());
+        let url = order_url("https://api.proxybase.xyz", &id, "status").unwrap();
+        assert_eq!(url.path(), "/v1/orders/..%2Fagents%3Fx=1/status");
+        assert!(url.query().is_none());
+    }
+}
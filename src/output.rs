@@ -0,0 +1,127 @@
+//! Plain-text rendering of tool results for the CLI subcommands.
+
+use serde_json::Value;
+
+/// Left-aligned columns separated by two spaces, with a header row.
+pub fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let header: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// One `key  value` line per leaf of a JSON object. Nested objects use
+/// dotted keys, arrays of scalars are joined and nulls are left out.
+pub fn key_values(value: &Value) -> String {
+    let mut rows = Vec::new();
+    flatten("", value, &mut rows);
+    let width = rows.iter().map(|(k, _)| k.chars().count()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(key, value)| format!("{:<width$}  {}\n", key, value, width = width))
+        .collect()
+}
+
+fn flatten(prefix: &str, value: &Value, rows: &mut Vec<(String, String)>) {
+    let key = |name: &str| match prefix {
+        "" => name.to_string(),
+        _ => format!("{}.{}", prefix, name),
+    };
+    match value {
+        Value::Null => {}
+        Value::Object(map) => {
+            for (name, v) in map {
+                flatten(&key(name), v, rows);
+            }
+        }
+        Value::Array(items) if items.iter().all(|v| !v.is_object() && !v.is_array()) => {
+            let joined: Vec<String> = items.iter().map(scalar).collect();
+            rows.push((prefix.to_string(), joined.join(", ")));
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                flatten(&format!("{}[{}]", prefix, i), v, rows);
+            }
+        }
+        _ => rows.push((prefix.to_string(), scalar(value))),
+    }
+}
+
+/// A JSON scalar without the quotes around strings.
+pub fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Byte count in binary units, e.g. `1.5 GiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let size = format!("{:.1}", size);
+    format!("{} {}", size.trim_end_matches(".0"), UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_table_alignment() {
+        let out = table(
+            &["ID", "PRICE"],
+            &[
+                vec!["us_residential_1gb".to_string(), "10.00".to_string()],
+                vec!["de_dc_5gb".to_string(), "".to_string()],
+            ],
+        );
+        assert_eq!(
+            out,
+            "ID                  PRICE\nus_residential_1gb  10.00\nde_dc_5gb\n"
+        );
+    }
+
+    #[test]
+    fn test_key_values_flattens() {
+        let out = key_values(&json!({
+            "order_id": "kQx7p3Wn",
+            "proxy": {"host": "api.proxybase.xyz", "port": 1080},
+            "warnings": ["a", "b"],
+            "callback_url": null
+        }));
+        assert_eq!(
+            out,
+            "order_id    kQx7p3Wn\nproxy.host  api.proxybase.xyz\nproxy.port  1080\nwarnings    a, b\n"
+        );
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1073741824), "1 GiB");
+        assert_eq!(human_bytes(1610612736), "1.5 GiB");
+        assert_eq!(human_bytes(52428800), "50 MiB");
+    }
+}
@@ -0,0 +1,457 @@
+//! Payment URIs and QR codes for invoices.
+//!
+//! `create_order` and `topup_order` return an address, an amount and a
+//! currency. This module turns them into the URI a wallet understands
+//! (BIP-21, EIP-681, Solana Pay or TronLink's `tron:` scheme) and renders
+//! it as a QR code, both as a PNG for MCP `image` content and as ASCII for
+//! clients that only show text.
+
+use crate::models::{PaymentInfo, PaymentState};
+use crate::util::percent_encode;
+use qrcode::{Color, QrCode};
+use serde_json::{json, Value};
+
+/// PNG pixels per QR module.
+const PNG_SCALE: usize = 8;
+/// Light modules around the code, as required by the QR spec.
+const QUIET_ZONE: usize = 4;
+
+const USDT_ERC20: &str = "0xdAC17F958D2ee523a2206206994597C13D831ec7";
+const USDC_ERC20: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+const USDT_SPL: &str = "Es9vMFrzaCERmJfrF4H2FYD4KCoNkY11McCe8BenwNYB";
+const USDC_SPL: &str = "EPjFWdw5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+const USDT_TRC20: &str = "TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t";
+
+enum Scheme {
+    /// BIP-21 and its forks, with the URI scheme name.
+    Bip21(&'static str),
+    /// Native ether, EIP-681 with the amount in wei.
+    Ether,
+    /// ERC-20 `transfer` call, EIP-681 with the amount in token units.
+    Erc20 { contract: &'static str, decimals: u32 },
+    /// Solana Pay transfer request, optionally of an SPL token.
+    SolanaPay { spl_token: Option<&'static str> },
+    /// TronLink's `tron:` URI, optionally of a TRC-20 token.
+    Tron { token: Option<&'static str> },
+}
+
+fn scheme(currency: &str) -> Option<Scheme> {
+    Some(match currency {
+        "btc" => Scheme::Bip21("bitcoin"),
+        "ltc" => Scheme::Bip21("litecoin"),
+        "doge" => Scheme::Bip21("dogecoin"),
+        "eth" => Scheme::Ether,
+        "usdterc20" => Scheme::Erc20 { contract: USDT_ERC20, decimals: 6 },
+        "usdc" | "usdcerc20" => Scheme::Erc20 { contract: USDC_ERC20, decimals: 6 },
+        "sol" => Scheme::SolanaPay { spl_token: None },
+        "usdtsol" => Scheme::SolanaPay { spl_token: Some(USDT_SPL) },
+        "usdcsol" => Scheme::SolanaPay { spl_token: Some(USDC_SPL) },
+        "trx" => Scheme::Tron { token: None },
+        "usdttrc20" => Scheme::Tron { token: Some(USDT_TRC20) },
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub currency: String,
+    pub address: String,
+    /// Plain decimal amount, e.g. `"10.15"`.
+    pub amount: String,
+    pub order_id: Option<String>,
+    /// Memo / destination tag the payment must carry, if any.
+    pub extra_id: Option<String>,
+}
+
+impl Invoice {
+    /// Read the payment fields of a `create_order` / `topup_order` response.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let text = |k: &str| match value.get(k) {
+            Some(Value::String(s)) if !s.is_empty() => Some(s.clone()),
+            Some(Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        };
+        Some(Self {
+            currency: text("pay_currency")?.to_lowercase(),
+            address: text("pay_address")?,
+            amount: plain_decimal(&text("pay_amount")?)?,
+            order_id: text("order_id"),
+            extra_id: text("payin_extra_id").or_else(|| text("extra_id")),
+        })
+    }
+
+    /// The wallet URI for this invoice, or `None` for currencies without a
+    /// supported scheme (and for invoices that need a memo, which the
+    /// schemes cannot carry).
+    pub fn uri(&self) -> Option<String> {
+        if self.extra_id.is_some() {
+            return None;
+        }
+        let label = percent_encode(&match &self.order_id {
+            Some(id) => format!("ProxyBase order {}", id),
+            None => "ProxyBase".to_string(),
+        });
+        let addr = &self.address;
+        let amount = &self.amount;
+
+        Some(match scheme(&self.currency)? {
+            Scheme::Bip21(name) => format!("{}:{}?amount={}&label={}", name, addr, amount, label),
+            Scheme::Ether => format!("ethereum:{}@1?value={}", addr, to_units(amount, 18)?),
+            Scheme::Erc20 { contract, decimals } => format!(
+                "ethereum:{}@1/transfer?address={}&uint256={}",
+                contract,
+                addr,
+                to_units(amount, decimals)?
+            ),
+            Scheme::SolanaPay { spl_token } => {
+                let mut uri = format!("solana:{}?amount={}", addr, amount);
+                if let Some(token) = spl_token {
+                    uri.push_str(&format!("&spl-token={}", token));
+                }
+                uri.push_str(&format!("&label={}", label));
+                uri
+            }
+            Scheme::Tron { token } => {
+                let mut uri = format!("tron:{}?amount={}", addr, amount);
+                if let Some(token) = token {
+                    uri.push_str(&format!("&token={}", token));
+                }
+                uri
+            }
+        })
+    }
+
+    /// Payment details for the tool result, plus the QR code as PNG. The QR
+    /// code holds the URI, or just the address when there is no URI.
+    pub fn render(&self) -> Result<(Value, Vec<u8>), String> {
+        let uri = self.uri();
+        let qr_data = uri.clone().unwrap_or_else(|| self.address.clone());
+        let code = QrCode::new(qr_data.as_bytes()).map_err(|e| format!("QR code error: {}", e))?;
+
+        let mut details = json!({
+            "uri": uri,
+            "qr_data": qr_data,
+            "qr_ascii": ascii(&code)
+        });
+        if uri.is_none() {
+            details["note"] = json!(match &self.extra_id {
+                Some(memo) => format!(
+                    "The payment must carry the memo / destination tag {}; the QR code only contains the address, enter the amount and memo by hand",
+                    memo
+                ),
+                None => format!(
+                    "No payment URI scheme for {}; the QR code only contains the address, enter the amount by hand",
+                    self.currency
+                ),
+            });
+        }
+        Ok((details, png(&code)?))
+    }
+}
+
+/// An order's payment state with the next steps for the agent. When
+/// something is left to pay, `pay_amount` is the amount to send now, so the
+/// view doubles as an invoice for [`Invoice::from_value`].
+pub fn status_view(order_id: &str, info: &PaymentInfo, now: u64) -> Value {
+    let state = info.state(now);
+    let currency = info.pay_currency.as_deref().unwrap_or("").to_uppercase();
+    let address = info.pay_address.as_deref().unwrap_or("the invoice address");
+    let due = info.amount_due().map(round_up);
+    let expires_in = info.expires_at.map(|t| t.saturating_sub(now));
+    let deadline = match expires_in {
+        Some(secs) => format!(" within {} (the invoice expires at {})", human_duration(secs), info.expires_at.unwrap_or(0)),
+        None => String::new(),
+    };
+    let memo = match &info.extra_id {
+        Some(memo) => format!(" with memo / destination tag {}", memo),
+        None => String::new(),
+    };
+
+    let next_steps: Vec<String> = match state {
+        PaymentState::AwaitingPayment => vec![match due {
+            Some(due) => format!("Send {} {} to {}{}{}.", fmt_amount(due), currency, address, memo, deadline),
+            None => format!("Pay the invoice to {}{}{}.", address, memo, deadline),
+        }, "Then poll payment_status or check_order_status until the payment is confirmed.".to_string()],
+        PaymentState::PartiallyPaid => vec![
+            format!(
+                "Received {} of {} {}. Send {} {} more to the same address {}{}{}.",
+                fmt_amount(info.amount_received.unwrap_or(0.0)),
+                info.amount_expected.map(fmt_amount).unwrap_or_else(|| "?".to_string()),
+                currency,
+                due.map(fmt_amount).unwrap_or_else(|| "the rest".to_string()),
+                currency,
+                address,
+                memo,
+                deadline
+            ),
+            "Do not create a new order: a new invoice would not include what was already paid.".to_string(),
+        ],
+        PaymentState::Confirming => vec![
+            "The payment was seen and is waiting for blockchain confirmations; nothing more to send.".to_string(),
+            "Poll payment_status again in a minute or two.".to_string(),
+        ],
+        PaymentState::Paid => vec![if info.order_status == crate::models::STATUS_PROXY_ACTIVE {
+            "Paid; the proxy is active. Use check_order_status for the credentials.".to_string()
+        } else {
+            "Paid. Poll check_order_status until the proxy is active.".to_string()
+        }],
+        PaymentState::Expired => {
+            let mut steps = vec!["The invoice expired. Create a new order with create_order (or a new top-up with topup_order).".to_string()];
+            if info.amount_received.is_some_and(|r| r > 0.0) {
+                steps.push(format!(
+                    "{} {} was received before it expired and is not applied to a new invoice; contact ProxyBase support about it.",
+                    fmt_amount(info.amount_received.unwrap_or(0.0)),
+                    currency
+                ));
+            }
+            steps
+        }
+    };
+
+    let mut view = json!({
+        "order_id": order_id,
+        "order_status": info.order_status,
+        "state": state,
+        "pay_currency": info.pay_currency,
+        "pay_address": info.pay_address,
+        "amount_expected": info.amount_expected,
+        "amount_received": info.amount_received,
+        "amount_due": due,
+        "expires_at": info.expires_at,
+        "expires_in_secs": expires_in,
+        "next_steps": next_steps
+    });
+    if matches!(state, PaymentState::AwaitingPayment | PaymentState::PartiallyPaid) {
+        if let Some(due) = due.filter(|d| *d > 0.0) {
+            view["pay_amount"] = json!(fmt_amount(due));
+            if let Some(memo) = &info.extra_id {
+                view["payin_extra_id"] = json!(memo);
+            }
+        }
+    }
+    view
+}
+
+/// Round up to 8 decimals so rounding never leaves an invoice short.
+fn round_up(amount: f64) -> f64 {
+    // Round away float noise first, so 0.42 does not become 0.42000001
+    ((amount * 1e10).round() / 100.0).ceil() / 1e8
+}
+
+fn fmt_amount(amount: f64) -> String {
+    let s = format!("{:.8}", amount);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..=119 => format!("{} seconds", secs),
+        120..=7199 => format!("{} minutes", secs / 60),
+        _ => format!("{} hours", secs / 3600),
+    }
+}
+
+/// Normalise a JSON number or string to a plain decimal (no exponent).
+fn plain_decimal(s: &str) -> Option<String> {
+    let s = s.trim();
+    let (mantissa, exp) = match s.find(['e', 'E']) {
+        Some(i) => (&s[..i], s[i + 1..].parse::<i32>().ok().filter(|e| e.abs() <= 64)?),
+        None => (s, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if (int.is_empty() && frac.is_empty())
+        || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    // All digits with the decimal point `point` places from the left
+    let digits = format!("{}{}", int, frac);
+    let point = int.len() as i32 + exp;
+    let (int, frac) = if point <= 0 {
+        ("0".to_string(), format!("{}{}", "0".repeat((-point) as usize), digits))
+    } else if point as usize >= digits.len() {
+        (format!("{}{}", digits, "0".repeat(point as usize - digits.len())), String::new())
+    } else {
+        (digits[..point as usize].to_string(), digits[point as usize..].to_string())
+    };
+
+    let int = int.trim_start_matches('0');
+    let frac = frac.trim_end_matches('0');
+    let int = if int.is_empty() { "0" } else { int };
+    Some(if frac.is_empty() {
+        int.to_string()
+    } else {
+        format!("{}.{}", int, frac)
+    })
+}
+
+/// Scale a plain decimal to integer base units, e.g. ether to wei. `None`
+/// if the amount is more precise than the unit allows.
+fn to_units(amount: &str, decimals: u32) -> Option<String> {
+    let (int, frac) = amount.split_once('.').unwrap_or((amount, ""));
+    if frac.len() > decimals as usize {
+        return None;
+    }
+    let units = format!("{}{}{}", int, frac, "0".repeat(decimals as usize - frac.len()));
+    let units = units.trim_start_matches('0');
+    Some(if units.is_empty() { "0".to_string() } else { units.to_string() })
+}
+
+fn is_dark(code: &QrCode, colors: &[Color], x: isize, y: isize) -> bool {
+    let w = code.width() as isize;
+    (0..w).contains(&x) && (0..w).contains(&y) && colors[(y * w + x) as usize] == Color::Dark
+}
+
+/// Two characters per module so the code comes out roughly square.
+fn ascii(code: &QrCode) -> String {
+    let colors = code.to_colors();
+    let (q, w) = (QUIET_ZONE as isize, code.width() as isize);
+    let mut out = String::new();
+    for y in -q..w + q {
+        for x in -q..w + q {
+            out.push_str(if is_dark(code, &colors, x, y) { "##" } else { "  " });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Black-on-white 8-bit greyscale PNG.
+fn png(code: &QrCode) -> Result<Vec<u8>, String> {
+    let colors = code.to_colors();
+    let (q, w) = (QUIET_ZONE as isize, code.width() as isize);
+    let side = (code.width() + 2 * QUIET_ZONE) * PNG_SCALE;
+
+    let mut pixels = Vec::with_capacity(side * side);
+    for y in -q..w + q {
+        let row: Vec<u8> = (-q..w + q)
+            .flat_map(|x| {
+                let shade = if is_dark(code, &colors, x, y) { 0 } else { 255 };
+                std::iter::repeat_n(shade, PNG_SCALE)
+            })
+            .collect();
+        for _ in 0..PNG_SCALE {
+            pixels.extend_from_slice(&row);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| format!("PNG error: {}", e))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice(currency: &str, address: &str, amount: Value) -> Invoice {
+        Invoice::from_value(&json!({
+            "order_id": "kQx7p3Wn",
+            "pay_currency": currency,
+            "pay_address": address,
+            "pay_amount": amount
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_plain_decimal() {
+        assert_eq!(plain_decimal("10.15").as_deref(), Some("10.15"));
+        assert_eq!(plain_decimal("1e-5").as_deref(), Some("0.00001"));
+        assert_eq!(plain_decimal("1.5E3").as_deref(), Some("1500"));
+        assert_eq!(plain_decimal("007.500").as_deref(), Some("7.5"));
+        assert_eq!(plain_decimal("abc"), None);
+        assert_eq!(plain_decimal("-1"), None);
+        assert_eq!(plain_decimal("1e99999"), None);
+        assert_eq!(to_units("0.00001", 18).as_deref(), Some("10000000000000"));
+        assert_eq!(to_units("10.15", 6).as_deref(), Some("10150000"));
+        assert_eq!(to_units("0.0000001", 6), None);
+    }
+
+    #[test]
+    fn test_uris() {
+        let btc = invoice("BTC", "bc1qexample", json!(0.00015));
+        assert_eq!(
+            btc.uri().unwrap(),
+            "bitcoin:bc1qexample?amount=0.00015&label=ProxyBase%20order%20kQx7p3Wn"
+        );
+
+        let eth = invoice("eth", "0xabc", json!(0.01));
+        assert_eq!(eth.uri().unwrap(), "ethereum:0xabc@1?value=10000000000000000");
+
+        let usdt = invoice("usdterc20", "0xabc", json!("10.15"));
+        assert_eq!(
+            usdt.uri().unwrap(),
+            format!("ethereum:{}@1/transfer?address=0xabc&uint256=10150000", USDT_ERC20)
+        );
+
+        let sol = invoice("usdcsol", "So1Addr", json!(10.15));
+        assert_eq!(
+            sol.uri().unwrap(),
+            format!("solana:So1Addr?amount=10.15&spl-token={}&label=ProxyBase%20order%20kQx7p3Wn", USDC_SPL)
+        );
+
+        let trc = invoice("usdttrc20", "TXyz", json!(10.15));
+        assert_eq!(trc.uri().unwrap(), format!("tron:TXyz?amount=10.15&token={}", USDT_TRC20));
+
+        assert_eq!(invoice("xmr", "4Abc", json!(1)).uri(), None);
+        assert!(Invoice::from_value(&json!({"order_id": "x", "status": "paid"})).is_none());
+    }
+
+    #[test]
+    fn test_status_view_next_steps() {
+        let now = 1_760_000_000;
+        let partial = PaymentInfo::from_value(&json!({
+            "status": "partially_paid",
+            "pay_currency": "usdttrc20",
+            "pay_address": "TXyz",
+            "pay_amount": 10.15,
+            "actually_paid": 9.73,
+            "expires_at": now + 1200
+        }));
+        let view = status_view("kQx7p3Wn", &partial, now);
+        assert_eq!(view["state"], "partially_paid");
+        assert_eq!(view["amount_due"], 0.42);
+        assert_eq!(view["pay_amount"], "0.42");
+        let step = view["next_steps"][0].as_str().unwrap();
+        assert!(step.contains("Send 0.42 USDTTRC20 more to the same address TXyz within 20 minutes"), "{}", step);
+        // The view is an invoice for the remainder
+        let invoice = Invoice::from_value(&view).unwrap();
+        assert_eq!(invoice.uri().unwrap(), format!("tron:TXyz?amount=0.42&token={}", USDT_TRC20));
+
+        let view = status_view("kQx7p3Wn", &partial, now + 1200);
+        assert_eq!(view["state"], "expired");
+        assert!(view["next_steps"][0].as_str().unwrap().contains("create_order"));
+        assert!(view["next_steps"][1].as_str().unwrap().contains("9.73"));
+        assert!(view.get("pay_amount").is_none());
+
+        assert_eq!(round_up(0.1 + 0.2), 0.3);
+        assert_eq!(round_up(0.123456781), 0.12345679);
+    }
+
+    #[test]
+    fn test_render_png_and_ascii() {
+        let (details, png) = invoice("btc", "bc1qexample", json!(0.00015)).render().unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(details["qr_data"], details["uri"]);
+        let ascii = details["qr_ascii"].as_str().unwrap();
+        assert!(ascii.contains("##"));
+        // Square: two characters per module on every line
+        let lines: Vec<&str> = ascii.lines().collect();
+        assert_eq!(lines[0].len(), 2 * lines.len());
+
+        let mut memo = invoice("xrp", "rAddr", json!(5));
+        memo.extra_id = Some("12345".to_string());
+        let (details, _) = memo.render().unwrap();
+        assert_eq!(details["uri"], Value::Null);
+        assert_eq!(details["qr_data"], "rAddr");
+        assert!(details["note"].as_str().unwrap().contains("12345"));
+    }
+}
@@ -0,0 +1,448 @@
+//! A pool of active orders behind one local gateway.
+//!
+//! Each new gateway connection asks the pool for an ordered list of
+//! candidate upstreams according to the gateway's [`Strategy`]; the gateway
+//! tries them in turn. Members are ejected when their proxy keeps failing
+//! (for a cooldown, then retried) or when their order runs out of bandwidth
+//! (until the backend reports it active again, e.g. after a top-up).
+
+use crate::models::{OrderStatus, ProxyCredentials};
+use crate::notify::Notifier;
+use crate::order_id::OrderId;
+use crate::util::fnv1a;
+use crate::ProxyBaseClient;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Consecutive proxy failures before a member is ejected.
+pub const MAX_FAILURES: u32 = 3;
+/// How long a failing member stays out before it is tried again.
+pub const EJECT_COOLDOWN: Duration = Duration::from_secs(60);
+/// How often member orders are polled for status and remaining bandwidth.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    RoundRobin,
+    /// Fewest bytes sent through the pool so far.
+    LeastUsed,
+    /// The same destination host always maps to the same member while it
+    /// stays healthy.
+    StickyHost,
+    /// Only members tagged with this country, least used first.
+    Country(String),
+}
+
+impl Strategy {
+    pub fn parse(name: &str, country: Option<&str>) -> Result<Self, String> {
+        match (name, country) {
+            ("round_robin", _) => Ok(Strategy::RoundRobin),
+            ("least_used", _) => Ok(Strategy::LeastUsed),
+            ("sticky_host", _) => Ok(Strategy::StickyHost),
+            ("country", Some(c)) if !c.trim().is_empty() => Ok(Strategy::Country(c.trim().to_lowercase())),
+            ("country", _) => Err("Strategy 'country' requires a country".to_string()),
+            _ => Err(format!(
+                "Unknown strategy: '{}'. Use round_robin, least_used, sticky_host or country",
+                name
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::RoundRobin => "round_robin",
+            Strategy::LeastUsed => "least_used",
+            Strategy::StickyHost => "sticky_host",
+            Strategy::Country(_) => "country",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ejection {
+    reason: String,
+    /// `None` means until a status poll shows the order active again.
+    until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct MemberState {
+    remaining_bytes: Option<u64>,
+    /// Member byte count when `remaining_bytes` was last refreshed.
+    baseline: u64,
+    failures: u32,
+    ejected: Option<Ejection>,
+}
+
+impl MemberState {
+    /// Not ejected, or past the ejection's cooldown.
+    fn is_available(&self, now: Instant) -> bool {
+        match &self.ejected {
+            None => true,
+            Some(ejection) => ejection.until.is_some_and(|until| now >= until),
+        }
+    }
+}
+
+pub struct PoolMember {
+    pub order_id: OrderId,
+    pub country: Option<String>,
+    pub proxy: ProxyCredentials,
+    api_key: String,
+    bytes: AtomicU64,
+    state: Mutex<MemberState>,
+}
+
+impl PoolMember {
+    pub fn new(
+        order_id: OrderId,
+        api_key: &str,
+        proxy: ProxyCredentials,
+        country: Option<String>,
+        remaining_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            order_id,
+            country: country.map(|c| c.to_lowercase()),
+            proxy,
+            api_key: api_key.to_string(),
+            bytes: AtomicU64::new(0),
+            state: Mutex::new(MemberState {
+                remaining_bytes,
+                ..MemberState::default()
+            }),
+        }
+    }
+
+    /// Counter for bytes relayed through this member.
+    pub fn bytes(&self) -> &AtomicU64 {
+        &self.bytes
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.state.lock().unwrap().is_available(now)
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        json!({
+            "order_id": self.order_id.as_str(),
+            "country": self.country,
+            "upstream": self.proxy.addr(),
+            "healthy": state.is_available(now),
+            "ejected_reason": state.ejected.as_ref().map(|e| e.reason.clone()),
+            "retry_in_secs": state.ejected.as_ref()
+                .and_then(|e| e.until)
+                .map(|until| until.saturating_duration_since(now).as_secs()),
+            "consecutive_failures": state.failures,
+            "bytes": self.bytes.load(Ordering::Relaxed),
+            "remaining_bytes": state.remaining_bytes
+                .map(|rem| rem.saturating_sub(self.bytes.load(Ordering::Relaxed) - state.baseline))
+        })
+    }
+}
+
+pub struct ProxyPool {
+    members: Mutex<Vec<Arc<PoolMember>>>,
+    notifier: Notifier,
+    monitor_started: AtomicBool,
+}
+
+impl ProxyPool {
+    pub fn new(notifier: Notifier) -> Self {
+        Self {
+            members: Mutex::new(Vec::new()),
+            notifier,
+            monitor_started: AtomicBool::new(false),
+        }
+    }
+
+    /// Add a member, replacing any existing member for the same order.
+    pub fn add(&self, member: PoolMember) {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|m| m.order_id != member.order_id);
+        members.push(Arc::new(member));
+    }
+
+    pub fn remove(&self, order_id: &OrderId) -> bool {
+        let mut members = self.members.lock().unwrap();
+        let before = members.len();
+        members.retain(|m| &m.order_id != order_id);
+        members.len() != before
+    }
+
+    pub fn members(&self) -> Vec<Arc<PoolMember>> {
+        self.members.lock().unwrap().clone()
+    }
+
+    /// Available members in the order they should be tried for a connection
+    /// to `host`. `turn` drives round-robin.
+    pub fn candidates(&self, strategy: &Strategy, host: &str, turn: usize) -> Vec<Arc<PoolMember>> {
+        let now = Instant::now();
+        let mut members: Vec<_> = self
+            .members()
+            .into_iter()
+            .filter(|m| m.is_available(now))
+            .collect();
+
+        match strategy {
+            Strategy::RoundRobin => {
+                if !members.is_empty() {
+                    let len = members.len();
+                    members.rotate_left(turn % len);
+                }
+            }
+            Strategy::LeastUsed => members.sort_by_key(|m| m.bytes.load(Ordering::Relaxed)),
+            Strategy::StickyHost => {
+                // Rendezvous hashing: only hosts mapped to an ejected member move
+                members.sort_by_key(|m| {
+                    std::cmp::Reverse(fnv1a(format!("{}|{}", host, m.order_id).as_bytes()))
+                });
+            }
+            Strategy::Country(country) => {
+                members.retain(|m| m.country.as_deref() == Some(country.as_str()));
+                members.sort_by_key(|m| m.bytes.load(Ordering::Relaxed));
+            }
+        }
+        members
+    }
+
+    pub fn report_success(&self, member: &PoolMember) {
+        let mut state = member.state.lock().unwrap();
+        state.failures = 0;
+        if state.ejected.as_ref().is_some_and(|e| e.until.is_some()) {
+            state.ejected = None;
+        }
+    }
+
+    /// Count a failure to reach or authenticate with the member's proxy,
+    /// ejecting it for [`EJECT_COOLDOWN`] after [`MAX_FAILURES`] in a row.
+    pub fn report_failure(&self, member: &PoolMember, error: &str) {
+        let ejected = {
+            let mut state = member.state.lock().unwrap();
+            state.failures += 1;
+            let already_out = state.ejected.is_some();
+            if state.failures >= MAX_FAILURES {
+                state.ejected = Some(Ejection {
+                    reason: format!("{} consecutive failures, last: {}", state.failures, error),
+                    until: Some(Instant::now() + EJECT_COOLDOWN),
+                });
+            }
+            !already_out && state.ejected.is_some()
+        };
+        if ejected {
+            self.announce(member);
+        }
+    }
+
+    fn eject_exhausted(&self, member: &PoolMember, reason: &str) {
+        let newly = {
+            let mut state = member.state.lock().unwrap();
+            let newly = state.ejected.as_ref().is_none_or(|e| e.until.is_some());
+            state.ejected = Some(Ejection {
+                reason: reason.to_string(),
+                until: None,
+            });
+            newly
+        };
+        if newly {
+            self.announce(member);
+        }
+    }
+
+    fn announce(&self, member: &PoolMember) {
+        log::warn!("Ejected order {} from the proxy pool", member.order_id);
+        self.notifier.log("warning", "proxybase.pool", member.snapshot());
+    }
+
+    /// Eject members whose local byte count has used up their remaining
+    /// bandwidth. Members back from a failure cooldown are checked too.
+    fn check_local_usage(&self) {
+        let now = Instant::now();
+        for member in self.members() {
+            let exhausted = {
+                let state = member.state.lock().unwrap();
+                let used = member.bytes.load(Ordering::Relaxed) - state.baseline;
+                state.is_available(now) && state.remaining_bytes.is_some_and(|rem| used >= rem)
+            };
+            if exhausted {
+                self.eject_exhausted(&member, "local byte count reached the order's remaining bandwidth");
+            }
+        }
+    }
+
+    /// Refresh every member from the backend.
+    async fn poll(&self, client: &ProxyBaseClient) {
+        for member in self.members() {
+            let status = client
+                .check_order_status(&member.api_key, &member.order_id)
+                .await
+                .and_then(|v| OrderStatus::from_value(&v));
+            match status {
+                Ok(status) if status.is_exhausted() || !status.is_active() => {
+                    self.eject_exhausted(&member, &format!("order is {}", status.status));
+                }
+                Ok(status) => {
+                    let mut state = member.state.lock().unwrap();
+                    state.remaining_bytes = status.remaining_bytes;
+                    state.baseline = member.bytes.load(Ordering::Relaxed);
+                    if state.ejected.as_ref().is_some_and(|e| e.until.is_none()) {
+                        log::info!("Order {} is active again; returning it to the pool", member.order_id);
+                        state.ejected = None;
+                    }
+                }
+                Err(e) => log::warn!("Pool could not poll order {}: {}", member.order_id, e),
+            }
+        }
+    }
+
+    /// Start the background health monitor once.
+    pub fn ensure_monitor(self: &Arc<Self>, client: ProxyBaseClient, interval: Duration) {
+        if self.monitor_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut last_poll = Instant::now();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let Some(pool) = pool.upgrade() else {
+                    return;
+                };
+                pool.check_local_usage();
+                if last_poll.elapsed() >= interval {
+                    last_poll = Instant::now();
+                    pool.poll(&client).await;
+                }
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let members = self.members();
+        json!({
+            "members": members.iter().map(|m| m.snapshot()).collect::<Vec<_>>(),
+            "healthy": members.iter().filter(|m| m.is_available(Instant::now())).count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, country: &str) -> PoolMember {
+        PoolMember::new(
+            OrderId::parse(id).unwrap(),
+            "pk_test",
+            ProxyCredentials {
+                host: "127.0.0.1".to_string(),
+                port: 1080,
+                username: "u".to_string(),
+                password: "p".to_string(),
+            },
+            Some(country.to_string()),
+            Some(1000),
+        )
+    }
+
+    fn ids(members: &[Arc<PoolMember>]) -> Vec<&str> {
+        members.iter().map(|m| m.order_id.as_str()).collect()
+    }
+
+    fn pool() -> ProxyPool {
+        let pool = ProxyPool::new(Notifier::default());
+        pool.add(member("a", "US"));
+        pool.add(member("b", "de"));
+        pool.add(member("c", "us"));
+        pool
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(Strategy::parse("sticky_host", None).unwrap(), Strategy::StickyHost);
+        assert_eq!(
+            Strategy::parse("country", Some("US")).unwrap(),
+            Strategy::Country("us".to_string())
+        );
+        assert!(Strategy::parse("country", None).is_err());
+        assert!(Strategy::parse("random", None).is_err());
+    }
+
+    #[test]
+    fn test_strategies() {
+        let pool = pool();
+
+        assert_eq!(ids(&pool.candidates(&Strategy::RoundRobin, "x", 0)), ["a", "b", "c"]);
+        assert_eq!(ids(&pool.candidates(&Strategy::RoundRobin, "x", 4)), ["b", "c", "a"]);
+
+        pool.members()[0].bytes().fetch_add(500, Ordering::Relaxed);
+        assert_eq!(ids(&pool.candidates(&Strategy::LeastUsed, "x", 0)), ["b", "c", "a"]);
+
+        let country = Strategy::Country("us".to_string());
+        assert_eq!(ids(&pool.candidates(&country, "x", 0)), ["c", "a"]);
+
+        let first = pool.candidates(&Strategy::StickyHost, "example.com", 0)[0].order_id.clone();
+        for turn in 0..5 {
+            assert_eq!(pool.candidates(&Strategy::StickyHost, "example.com", turn)[0].order_id, first);
+        }
+    }
+
+    #[test]
+    fn test_failures_eject_and_recover() {
+        let pool = pool();
+        let a = pool.members()[0].clone();
+
+        for _ in 0..MAX_FAILURES - 1 {
+            pool.report_failure(&a, "connection refused");
+        }
+        assert_eq!(pool.candidates(&Strategy::RoundRobin, "x", 0).len(), 3);
+
+        pool.report_failure(&a, "connection refused");
+        assert_eq!(ids(&pool.candidates(&Strategy::RoundRobin, "x", 0)), ["b", "c"]);
+        assert!(a.snapshot()["ejected_reason"].as_str().unwrap().contains("connection refused"));
+
+        // Once the cooldown has passed a success puts it back for good
+        a.state.lock().unwrap().ejected.as_mut().unwrap().until = Some(Instant::now());
+        assert_eq!(pool.candidates(&Strategy::RoundRobin, "x", 0).len(), 3);
+        assert!(a.snapshot()["healthy"].as_bool().unwrap());
+        assert_eq!(pool.snapshot()["healthy"], 3);
+        pool.report_success(&a);
+        assert!(a.snapshot()["healthy"].as_bool().unwrap());
+    }
+
+    #[test]
+    fn test_local_usage_ejects_exhausted_member() {
+        let pool = pool();
+        let b = pool.members()[1].clone();
+        b.bytes().fetch_add(1000, Ordering::Relaxed);
+
+        pool.check_local_usage();
+        assert_eq!(ids(&pool.candidates(&Strategy::LeastUsed, "x", 0)), ["a", "c"]);
+        // Exhaustion is not lifted by a successful connection
+        pool.report_success(&b);
+        assert!(!b.snapshot()["healthy"].as_bool().unwrap());
+
+        assert!(pool.remove(&b.order_id));
+        assert_eq!(pool.members().len(), 2);
+    }
+
+    #[test]
+    fn test_exhaustion_is_checked_after_a_failure_cooldown() {
+        let pool = pool();
+        let a = pool.members()[0].clone();
+        for _ in 0..MAX_FAILURES {
+            pool.report_failure(&a, "connection refused");
+        }
+        a.state.lock().unwrap().ejected.as_mut().unwrap().until = Some(Instant::now());
+        a.bytes().fetch_add(1000, Ordering::Relaxed);
+
+        pool.check_local_usage();
+        assert_eq!(ids(&pool.candidates(&Strategy::RoundRobin, "x", 0)), ["b", "c"]);
+        assert!(a.snapshot()["ejected_reason"].as_str().unwrap().contains("remaining bandwidth"));
+        assert!(a.snapshot()["retry_in_secs"].is_null());
+    }
+}
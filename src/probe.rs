@@ -0,0 +1,321 @@
+//! End-to-end connectivity checks for purchased proxies.
+//!
+//! A probe runs the SOCKS5 handshake step by step against the proxy so a
+//! failure can be pinned to a stage, then fetches an IP echo service through
+//! the proxy to learn the exit address.
+
+use crate::models::ProxyCredentials;
+use crate::socks5::{self, Socks5Error, Stage, METHOD_NO_AUTH, METHOD_USER_PASS};
+use serde::Serialize;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_ECHO_URL: &str = "https://api.ipify.org?format=json";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Longest per-stage timeout an agent may ask for.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Serialize)]
+pub struct StageResult {
+    pub stage: &'static str,
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeReport {
+    pub ok: bool,
+    pub proxy: String,
+    pub echo_url: String,
+    /// `accepted`, `rejected` or `not_reached`.
+    pub auth: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_stage: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub stages: Vec<StageResult>,
+}
+
+impl ProbeReport {
+    fn new(creds: &ProxyCredentials, echo_url: &str) -> Self {
+        Self {
+            ok: false,
+            proxy: creds.addr(),
+            echo_url: echo_url.to_string(),
+            auth: "not_reached",
+            connect_latency_ms: None,
+            exit_ip: None,
+            failed_stage: None,
+            error: None,
+            stages: Vec::new(),
+        }
+    }
+
+    fn pass(&mut self, stage: &'static str, started: Instant, detail: Option<String>) {
+        self.stages.push(StageResult {
+            stage,
+            ok: true,
+            latency_ms: started.elapsed().as_millis() as u64,
+            detail,
+        });
+    }
+
+    fn fail(mut self, stage: &'static str, started: Instant, error: String) -> Self {
+        self.stages.push(StageResult {
+            stage,
+            ok: false,
+            latency_ms: started.elapsed().as_millis() as u64,
+            detail: Some(error.clone()),
+        });
+        self.failed_stage = Some(stage);
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Probe `creds` end to end. Never fails; problems are reported in the result.
+pub async fn probe(creds: &ProxyCredentials, echo_url: &str, timeout: Duration) -> ProbeReport {
+    let mut report = ProbeReport::new(creds, echo_url);
+
+    let (echo_host, echo_port) = match echo_target(echo_url) {
+        Ok(target) => target,
+        Err(e) => return report.fail("echo_url", Instant::now(), e),
+    };
+
+    let started = Instant::now();
+    let mut stream = match with_timeout(timeout, Stage::Connect, async {
+        TcpStream::connect(creds.addr())
+            .await
+            .map_err(|e| Socks5Error { stage: Stage::Connect, message: e.to_string() })
+    })
+    .await
+    {
+        Ok(s) => s,
+        Err(e) => return report.fail("connect", started, e.message),
+    };
+    report.connect_latency_ms = Some(started.elapsed().as_millis() as u64);
+    report.pass("connect", started, None);
+
+    let started = Instant::now();
+    let method = match with_timeout(timeout, Stage::Greeting, socks5::greet(&mut stream, true)).await {
+        Ok(m) => m,
+        Err(e) => return report.fail("greeting", started, e.message),
+    };
+    report.pass("greeting", started, None);
+
+    let started = Instant::now();
+    match method {
+        METHOD_USER_PASS => {
+            let auth = socks5::authenticate(&mut stream, &creds.username, &creds.password);
+            if let Err(e) = with_timeout(timeout, Stage::Auth, auth).await {
+                report.auth = "rejected";
+                return report.fail("auth", started, e.message);
+            }
+            report.auth = "accepted";
+            report.pass("auth", started, None);
+        }
+        METHOD_NO_AUTH => {
+            report.auth = "accepted";
+            report.pass("auth", started, Some("proxy did not ask for credentials".to_string()));
+        }
+        other => {
+            return report.fail("auth", started, format!("unsupported auth method {:#04x}", other));
+        }
+    }
+
+    let started = Instant::now();
+    let tunnel = socks5::request_connect(&mut stream, &echo_host, echo_port);
+    if let Err(e) = with_timeout(timeout, Stage::Request, tunnel).await {
+        return report.fail("tunnel", started, e.message);
+    }
+    report.pass("tunnel", started, Some(format!("{}:{}", echo_host, echo_port)));
+    drop(stream);
+
+    let started = Instant::now();
+    match fetch_exit_ip(creds, echo_url, timeout).await {
+        Ok(ip) => {
+            report.pass("fetch", started, None);
+            report.exit_ip = Some(ip);
+            report.ok = true;
+            report
+        }
+        Err(e) => report.fail("fetch", started, e),
+    }
+}
+
+/// Fetch `echo_url` through the proxy and return the exit IP it reports.
+pub async fn fetch_exit_ip(
+    creds: &ProxyCredentials,
+    echo_url: &str,
+    timeout: Duration,
+) -> Result<String, String> {
+    let resp = proxy_client(creds, timeout)?
+        .get(echo_url)
+        .send()
+        .await
+        .map_err(|e| format!("request through proxy failed: {}", e))?;
+
+    let status = resp.status();
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| format!("reading echo response failed: {}", e))?;
+    if !status.is_success() {
+        return Err(format!("echo service returned {}", status));
+    }
+    parse_exit_ip(&body).ok_or_else(|| format!("echo service returned no IP address: {:.200}", body))
+}
+
+/// An HTTP client that sends every request through the proxy, resolving
+/// hostnames on the proxy side.
+pub fn proxy_client(creds: &ProxyCredentials, timeout: Duration) -> Result<reqwest::Client, String> {
+    let mut url = reqwest::Url::parse(&format!("socks5h://{}", creds.addr()))
+        .map_err(|e| format!("invalid proxy address {}: {}", creds.addr(), e))?;
+    url.set_username(&creds.username)
+        .and_then(|_| url.set_password(Some(&creds.password)))
+        .map_err(|_| "invalid proxy credentials".to_string())?;
+
+    let proxy = reqwest::Proxy::all(url.as_str()).map_err(|e| format!("invalid proxy: {}", e))?;
+    reqwest::Client::builder()
+        .proxy(proxy)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("failed to build proxy client: {}", e))
+}
+
+/// Extract an IP from an echo response: JSON `ip`/`origin`/`query` fields or
+/// a bare address.
+pub fn parse_exit_ip(body: &str) -> Option<String> {
+    let candidate = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(v) => ["ip", "origin", "query", "ip_addr"]
+            .iter()
+            .find_map(|k| v.get(*k).and_then(|x| x.as_str()).map(|s| s.to_string()))?,
+        Err(_) => body.trim().to_string(),
+    };
+    // httpbin reports "a, b" when forwarded; the first hop is the exit
+    let first = candidate.split(',').next()?.trim();
+    first.parse::<IpAddr>().ok().map(|ip| ip.to_string())
+}
+
+fn echo_target(echo_url: &str) -> Result<(String, u16), String> {
+    let url = reqwest::Url::parse(echo_url).map_err(|e| format!("invalid echo_url: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("echo_url must be http:// or https://".to_string());
+    }
+    let host = url.host_str().ok_or("echo_url has no host")?.to_string();
+    let port = url.port_or_known_default().ok_or("echo_url has no port")?;
+    Ok((host, port))
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    stage: Stage,
+    fut: impl Future<Output = Result<T, Socks5Error>>,
+) -> Result<T, Socks5Error> {
+    tokio::time::timeout(timeout, fut).await.unwrap_or_else(|_| {
+        Err(Socks5Error {
+            stage,
+            message: format!("timed out after {:?}", timeout),
+        })
+    })
+}
+
+#[cfg(test)]
+pub mod test_support {
+    //! A local HTTP server that reports a fixed "exit IP", used together with
+    //! the SOCKS5 stand-in.
+
+    use crate::http1;
+    use std::net::SocketAddr;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    pub async fn spawn_echo(ip: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    if http1::read_head(&mut reader).await.is_ok() {
+                        let body = format!("{{\"ip\":\"{}\"}}", ip);
+                        let _ = http1::write_response(
+                            reader.get_mut(),
+                            200,
+                            "OK",
+                            "application/json",
+                            body.as_bytes(),
+                        )
+                        .await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::test_server;
+
+    fn creds(addr: std::net::SocketAddr, password: &str) -> ProxyCredentials {
+        ProxyCredentials {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            username: "pb_user".to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_exit_ip() {
+        assert_eq!(parse_exit_ip(r#"{"ip":"203.0.113.7"}"#).as_deref(), Some("203.0.113.7"));
+        assert_eq!(parse_exit_ip(r#"{"origin":"203.0.113.7, 10.0.0.1"}"#).as_deref(), Some("203.0.113.7"));
+        assert_eq!(parse_exit_ip("2001:db8::1\n").as_deref(), Some("2001:db8::1"));
+        assert_eq!(parse_exit_ip("<html>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe_success() {
+        let proxy = test_server::spawn("pb_user", "s3cr/t:@").await;
+        let echo = test_support::spawn_echo("203.0.113.7").await;
+        let url = format!("http://{}/", echo);
+
+        let report = probe(&creds(proxy, "s3cr/t:@"), &url, Duration::from_secs(5)).await;
+        assert!(report.ok, "{:?}", report);
+        assert_eq!(report.auth, "accepted");
+        assert_eq!(report.exit_ip.as_deref(), Some("203.0.113.7"));
+        let stages: Vec<_> = report.stages.iter().map(|s| s.stage).collect();
+        assert_eq!(stages, vec!["connect", "greeting", "auth", "tunnel", "fetch"]);
+    }
+
+    #[tokio::test]
+    async fn test_probe_bad_password() {
+        let proxy = test_server::spawn("pb_user", "right").await;
+        let echo = test_support::spawn_echo("203.0.113.7").await;
+
+        let report = probe(&creds(proxy, "wrong"), &format!("http://{}/", echo), Duration::from_secs(5)).await;
+        assert!(!report.ok);
+        assert_eq!(report.auth, "rejected");
+        assert_eq!(report.failed_stage, Some("auth"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_connect_failure() {
+        // Grab a free port and close it again
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let report = probe(&creds(addr, "x"), "http://127.0.0.1/", Duration::from_secs(5)).await;
+        assert_eq!(report.failed_stage, Some("connect"));
+        assert_eq!(report.auth, "not_reached");
+    }
+}
@@ -0,0 +1,189 @@
+//! Checking that a rotation actually changed the exit IP.
+//!
+//! The exit IP is read through the proxy before rotating and again after
+//! each rotation; if it did not change, rotation is retried. The new IP's
+//! country is then checked against the package's country with the offline
+//! GeoIP database, when one is configured.
+
+use crate::geoip::GeoIp;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Pause between a rotation and reading the new exit IP, giving the
+/// upstream time to drop the old session.
+pub const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RotationCheck {
+    /// Exit IP before rotating; `None` if it could not be read.
+    pub old_ip: Option<String>,
+    pub new_ip: Option<String>,
+    /// `None` when either IP is unknown.
+    pub changed: Option<bool>,
+    /// Rotations performed, including retries.
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub country: Option<String>,
+    pub expected_country: Option<String>,
+    /// `None` when either country is unknown.
+    pub country_match: Option<bool>,
+    pub geoip_database: Option<String>,
+}
+
+/// Rotate until the exit IP reported by `exit_ip` differs from `old_ip`, at
+/// most `max_attempts` times. Returns the last rotation response together
+/// with the check; a failing rotation call is an error.
+pub async fn rotate_until_changed<T, R, RF, E, EF>(
+    old_ip: Option<String>,
+    mut rotate: R,
+    mut exit_ip: E,
+    max_attempts: u32,
+    settle: Duration,
+) -> Result<(T, RotationCheck), String>
+where
+    R: FnMut() -> RF,
+    RF: Future<Output = Result<T, String>>,
+    E: FnMut() -> EF,
+    EF: Future<Output = Result<String, String>>,
+{
+    let mut check = RotationCheck {
+        old_ip,
+        new_ip: None,
+        changed: None,
+        attempts: 0,
+        error: None,
+        country: None,
+        expected_country: None,
+        country_match: None,
+        geoip_database: None,
+    };
+
+    loop {
+        let response = rotate().await?;
+        check.attempts += 1;
+        tokio::time::sleep(settle).await;
+
+        match exit_ip().await {
+            Ok(ip) => {
+                check.changed = check.old_ip.as_ref().map(|old| *old != ip);
+                check.new_ip = Some(ip);
+                check.error = None;
+            }
+            Err(e) => {
+                check.new_ip = None;
+                check.changed = None;
+                check.error = Some(format!("could not read the new exit IP: {}", e));
+            }
+        }
+
+        // Without a known old IP there is nothing to compare against
+        if check.changed != Some(false) || check.attempts >= max_attempts.max(1) {
+            return Ok((response, check));
+        }
+        log::info!(
+            "Exit IP unchanged after rotation attempt {}, retrying",
+            check.attempts
+        );
+    }
+}
+
+impl RotationCheck {
+    /// Fill in the country of the new exit IP and compare it with the
+    /// expected (package) country.
+    pub fn locate(&mut self, geoip: Option<&GeoIp>, expected_country: Option<&str>) {
+        self.expected_country = expected_country.map(|c| c.to_lowercase());
+        let Some(geoip) = geoip else {
+            return;
+        };
+        self.geoip_database = Some(geoip.database_type().to_string());
+
+        let ip = self.new_ip.as_deref().and_then(|ip| ip.parse().ok());
+        if let Some(ip) = ip {
+            match geoip.country(ip) {
+                Ok(country) => self.country = country,
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        self.country_match = match (&self.country, &self.expected_country) {
+            (Some(actual), Some(expected)) => Some(actual == expected),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_retries_until_ip_changes() {
+        let rotations = Cell::new(0);
+        let ips = ["198.51.100.1", "198.51.100.1", "198.51.100.2"];
+
+        let (response, check) = rotate_until_changed(
+            Some("198.51.100.1".to_string()),
+            || async {
+                rotations.set(rotations.get() + 1);
+                Ok::<_, String>(rotations.get())
+            },
+            || async { Ok(ips[rotations.get()].to_string()) },
+            5,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response, 2);
+        assert_eq!(check.attempts, 2);
+        assert_eq!(check.changed, Some(true));
+        assert_eq!(check.new_ip.as_deref(), Some("198.51.100.2"));
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let (_, check) = rotate_until_changed(
+            Some("198.51.100.1".to_string()),
+            || async { Ok::<_, String>(()) },
+            || async { Ok("198.51.100.1".to_string()) },
+            3,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        assert_eq!(check.attempts, 3);
+        assert_eq!(check.changed, Some(false));
+
+        let err = rotate_until_changed(
+            None,
+            || async { Err::<(), _>("API error (409)".to_string()) },
+            || async { Ok("198.51.100.1".to_string()) },
+            3,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.contains("409"));
+    }
+
+    #[test]
+    fn test_locate_without_database() {
+        let mut check = RotationCheck {
+            old_ip: None,
+            new_ip: Some("198.51.100.2".to_string()),
+            changed: None,
+            attempts: 1,
+            error: None,
+            country: None,
+            expected_country: None,
+            country_match: None,
+            geoip_database: None,
+        };
+        check.locate(None, Some("US"));
+        assert_eq!(check.expected_country.as_deref(), Some("us"));
+        assert_eq!(check.country_match, None);
+    }
+}
@@ -0,0 +1,502 @@
+//! Scheduled proxy rotation.
+//!
+//! A schedule rotates one order's proxy in a background task, every
+//! `interval` (with jitter) and/or after every `every_connections`
+//! connections made through the order's local gateways. Rotations are capped
+//! per rolling hour to respect upstream limits, and every rotation, manual
+//! or scheduled, is recorded in the per-order history.
+
+use crate::jsonl;
+use crate::order_id::OrderId;
+use crate::util::{random_unit, unix_now};
+use crate::ProxyBaseClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub const DEFAULT_MAX_PER_HOUR: u32 = 12;
+pub const DEFAULT_JITTER_PCT: u32 = 10;
+pub const MAX_JITTER_PCT: u32 = 50;
+pub const MIN_INTERVAL: Duration = Duration::from_secs(30);
+/// Failed rotations in a row after which a schedule cancels itself.
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+/// Records kept in the rotation history.
+pub const MAX_HISTORY: usize = 10_000;
+
+const TICK: Duration = Duration::from_secs(1);
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Rotation settings from the environment.
+#[derive(Debug, Clone)]
+pub struct RotationConfig {
+    /// Orders to rotate from startup, with their intervals.
+    pub schedules: Vec<(String, Duration)>,
+    pub max_per_hour: u32,
+    pub jitter_pct: u32,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            schedules: Vec::new(),
+            max_per_hour: DEFAULT_MAX_PER_HOUR,
+            jitter_pct: DEFAULT_JITTER_PCT,
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// History
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RotationRecord {
+    pub order_id: String,
+    /// Unix timestamp (seconds) of the rotation.
+    pub at: u64,
+    /// `manual`, `interval` or `connections`.
+    pub trigger: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_id: Option<String>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RotationRecord {
+    pub fn new(order_id: &OrderId, trigger: &str, schedule_id: Option<&str>, result: &Result<Value, String>) -> Self {
+        Self {
+            order_id: order_id.to_string(),
+            at: unix_now(),
+            trigger: trigger.to_string(),
+            schedule_id: schedule_id.map(|s| s.to_string()),
+            ok: result.is_ok(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+}
+
+pub struct RotationHistory {
+    records: jsonl::Journal<RotationRecord>,
+}
+
+impl RotationHistory {
+    pub fn in_memory() -> Self {
+        Self {
+            records: jsonl::Journal::in_memory(MAX_HISTORY),
+        }
+    }
+
+    /// Open (or create) the history file at `path`.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        Self {
+            records: jsonl::Journal::open(path, MAX_HISTORY),
+        }
+    }
+
+    pub fn record(&self, record: RotationRecord) {
+        self.records.push(record);
+    }
+
+    /// The most recent `limit` rotations of an order, oldest first.
+    pub fn query(&self, order_id: &str, limit: usize) -> Vec<RotationRecord> {
+        let records = self.records.records();
+        let mut matching: Vec<_> = records
+            .iter()
+            .rev()
+            .filter(|r| r.order_id == order_id)
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Schedules
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleSpec {
+    pub interval: Option<Duration>,
+    pub every_connections: Option<u64>,
+    pub jitter_pct: u32,
+    pub max_per_hour: u32,
+}
+
+impl ScheduleSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval.is_none() && self.every_connections.is_none() {
+            return Err("Provide interval_secs, every_connections, or both".to_string());
+        }
+        if self.interval.is_some_and(|i| i < MIN_INTERVAL) {
+            return Err(format!("interval_secs must be at least {}", MIN_INTERVAL.as_secs()));
+        }
+        if self.every_connections == Some(0) {
+            return Err("every_connections must be at least 1".to_string());
+        }
+        if self.jitter_pct > MAX_JITTER_PCT {
+            return Err(format!("jitter_pct must be at most {}", MAX_JITTER_PCT));
+        }
+        if self.max_per_hour == 0 {
+            return Err("max_per_hour must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// The interval with ±`jitter_pct` applied, if the schedule has one.
+    pub fn next_delay(&self) -> Option<Duration> {
+        let interval = self.interval?;
+        let spread = self.jitter_pct as f64 / 100.0;
+        let factor = 1.0 + spread * (2.0 * random_unit() - 1.0);
+        Some(interval.mul_f64(factor))
+    }
+}
+
+#[derive(Debug, Default)]
+struct ScheduleState {
+    next_at: Option<Instant>,
+    /// Rotations admitted within the last hour.
+    recent: VecDeque<Instant>,
+    /// Set while the hourly cap is reached.
+    throttled_until: Option<Instant>,
+    connections_baseline: u64,
+    rotations: u64,
+    throttled: u64,
+    failures: u32,
+    last_rotation_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+pub struct RotationSchedule {
+    pub id: String,
+    pub order_id: OrderId,
+    pub spec: ScheduleSpec,
+    api_key: String,
+    created_at: u64,
+    cancel: watch::Sender<bool>,
+    cancel_reason: Mutex<Option<String>>,
+    state: Mutex<ScheduleState>,
+}
+
+impl RotationSchedule {
+    pub fn is_active(&self) -> bool {
+        !*self.cancel.borrow()
+    }
+
+    pub fn cancel(&self, reason: &str) {
+        let mut cancel_reason = self.cancel_reason.lock().unwrap();
+        if cancel_reason.is_none() {
+            log::info!("Rotation schedule {} for {} cancelled: {}", self.id, self.order_id, reason);
+            *cancel_reason = Some(reason.to_string());
+        }
+        self.cancel.send_replace(true);
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let in_secs = |at: Option<Instant>| at.map(|t| t.saturating_duration_since(now).as_secs());
+        json!({
+            "schedule_id": self.id,
+            "order_id": self.order_id.as_str(),
+            "active": self.is_active(),
+            "cancel_reason": *self.cancel_reason.lock().unwrap(),
+            "interval_secs": self.spec.interval.map(|i| i.as_secs()),
+            "every_connections": self.spec.every_connections,
+            "jitter_pct": self.spec.jitter_pct,
+            "max_per_hour": self.spec.max_per_hour,
+            "created_at": self.created_at,
+            "next_rotation_in_secs": in_secs(state.next_at),
+            "throttled_for_secs": in_secs(state.throttled_until),
+            "rotations": state.rotations,
+            "rotations_last_hour": state.recent.iter().filter(|t| now.duration_since(**t) < HOUR).count(),
+            "throttled": state.throttled,
+            "consecutive_failures": state.failures,
+            "last_rotation_at": state.last_rotation_at,
+            "last_error": state.last_error
+        })
+    }
+
+    /// The trigger that is due now, if any.
+    fn due(&self, now: Instant, connections: u64) -> Option<&'static str> {
+        let mut state = self.state.lock().unwrap();
+        if connections < state.connections_baseline {
+            // A gateway went away; count from here
+            state.connections_baseline = connections;
+        }
+        if state.throttled_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        if state.next_at.is_some_and(|at| now >= at) {
+            return Some("interval");
+        }
+        let made = connections - state.connections_baseline;
+        if self.spec.every_connections.is_some_and(|n| made >= n) {
+            return Some("connections");
+        }
+        None
+    }
+
+    /// Take a slot under the hourly cap, or note the throttle.
+    fn admit(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if admit(&mut state.recent, now, self.spec.max_per_hour) {
+            state.throttled_until = None;
+            true
+        } else {
+            state.throttled += 1;
+            state.throttled_until = state.recent.front().map(|oldest| *oldest + HOUR);
+            false
+        }
+    }
+
+    /// Returns whether the schedule should give up.
+    fn finish(&self, now: Instant, connections: u64, result: &Result<Value, String>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.next_at = self.spec.next_delay().map(|d| now + d);
+        state.connections_baseline = connections;
+        match result {
+            Ok(_) => {
+                state.rotations += 1;
+                state.failures = 0;
+                state.last_rotation_at = Some(unix_now());
+                state.last_error = None;
+                false
+            }
+            Err(e) => {
+                state.failures += 1;
+                state.last_error = Some(e.clone());
+                state.failures >= MAX_CONSECUTIVE_FAILURES
+            }
+        }
+    }
+}
+
+/// Drop entries older than an hour and take a slot if fewer than `max`
+/// remain.
+fn admit(recent: &mut VecDeque<Instant>, now: Instant, max: u32) -> bool {
+    while recent.front().is_some_and(|t| now.duration_since(*t) >= HOUR) {
+        recent.pop_front();
+    }
+    if recent.len() >= max as usize {
+        return false;
+    }
+    recent.push_back(now);
+    true
+}
+
+/// Connections made so far through local gateways for an order.
+pub type ConnectionCounter = Arc<dyn Fn(&str) -> u64 + Send + Sync>;
+
+pub struct Scheduler {
+    schedules: Mutex<Vec<Arc<RotationSchedule>>>,
+    next_id: AtomicU64,
+    history: Arc<RotationHistory>,
+}
+
+impl Scheduler {
+    pub fn new(history: RotationHistory) -> Self {
+        Self {
+            schedules: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            history: Arc::new(history),
+        }
+    }
+
+    pub fn history(&self) -> &RotationHistory {
+        &self.history
+    }
+
+    /// Start a schedule, replacing any existing schedule for the order.
+    pub fn start(
+        &self,
+        client: ProxyBaseClient,
+        api_key: &str,
+        order_id: OrderId,
+        spec: ScheduleSpec,
+        connections: ConnectionCounter,
+    ) -> Result<Arc<RotationSchedule>, String> {
+        spec.validate()?;
+        self.cancel(|s| s.order_id == order_id, "replaced by a new schedule");
+
+        let now = Instant::now();
+        let schedule = Arc::new(RotationSchedule {
+            id: format!("rot_{}", self.next_id.fetch_add(1, Ordering::Relaxed)),
+            api_key: api_key.to_string(),
+            created_at: unix_now(),
+            cancel: watch::channel(false).0,
+            cancel_reason: Mutex::new(None),
+            state: Mutex::new(ScheduleState {
+                next_at: spec.next_delay().map(|d| now + d),
+                connections_baseline: connections(order_id.as_str()),
+                ..ScheduleState::default()
+            }),
+            order_id,
+            spec,
+        });
+        self.schedules.lock().unwrap().push(schedule.clone());
+        tokio::spawn(run(schedule.clone(), client, self.history.clone(), connections));
+        Ok(schedule)
+    }
+
+    pub fn list(&self) -> Vec<Arc<RotationSchedule>> {
+        self.schedules.lock().unwrap().clone()
+    }
+
+    /// Cancel and remove every schedule matching `pred`.
+    pub fn cancel(&self, pred: impl Fn(&RotationSchedule) -> bool, reason: &str) -> Vec<Arc<RotationSchedule>> {
+        let mut schedules = self.schedules.lock().unwrap();
+        let (removed, kept): (Vec<_>, Vec<_>) = schedules.drain(..).partition(|s| pred(s));
+        *schedules = kept;
+        for schedule in &removed {
+            schedule.cancel(reason);
+        }
+        removed
+    }
+}
+
+async fn run(
+    schedule: Arc<RotationSchedule>,
+    client: ProxyBaseClient,
+    history: Arc<RotationHistory>,
+    connections: ConnectionCounter,
+) {
+    let mut cancelled = schedule.cancel.subscribe();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(TICK) => {}
+            _ = cancelled.wait_for(|c| *c) => return,
+        }
+
+        let now = Instant::now();
+        let count = connections(schedule.order_id.as_str());
+        let Some(trigger) = schedule.due(now, count) else {
+            continue;
+        };
+        if !schedule.admit(now) {
+            log::warn!(
+                "Rotation of {} skipped: {} rotations per hour reached",
+                schedule.order_id, schedule.spec.max_per_hour
+            );
+            continue;
+        }
+
+        let result = client.rotate_proxy(&schedule.api_key, &schedule.order_id).await;
+        history.record(RotationRecord::new(&schedule.order_id, trigger, Some(&schedule.id), &result));
+        if let Err(e) = &result {
+            log::warn!("Scheduled rotation of {} failed: {}", schedule.order_id, e);
+        }
+        if schedule.finish(Instant::now(), count, &result) {
+            schedule.cancel(&format!("{} consecutive rotation failures", MAX_CONSECUTIVE_FAILURES));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> ScheduleSpec {
+        ScheduleSpec {
+            interval: Some(Duration::from_secs(600)),
+            every_connections: None,
+            jitter_pct: 10,
+            max_per_hour: 2,
+        }
+    }
+
+    #[test]
+    fn test_spec_validation_and_jitter() {
+        assert!(spec().validate().is_ok());
+        let none = ScheduleSpec { interval: None, ..spec() };
+        assert!(none.validate().is_err());
+        let short = ScheduleSpec { interval: Some(Duration::from_secs(5)), ..spec() };
+        assert!(short.validate().is_err());
+        let jittery = ScheduleSpec { jitter_pct: 90, ..spec() };
+        assert!(jittery.validate().is_err());
+
+        for _ in 0..100 {
+            let delay = spec().next_delay().unwrap().as_secs_f64();
+            assert!((540.0..=660.0).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn test_hourly_cap() {
+        let mut recent = VecDeque::new();
+        let start = Instant::now();
+        assert!(admit(&mut recent, start, 2));
+        assert!(admit(&mut recent, start + Duration::from_secs(60), 2));
+        assert!(!admit(&mut recent, start + Duration::from_secs(120), 2));
+        // The first slot frees up an hour after it was taken
+        assert!(admit(&mut recent, start + HOUR, 2));
+        assert_eq!(recent.len(), 2);
+    }
+
+    #[test]
+    fn test_history_query_and_reopen() {
+        let path = std::env::temp_dir()
+            .join(format!("proxybase-rotations-test-{}", std::process::id()))
+            .join("rotations.jsonl");
+        let order = OrderId::parse("kQx7p3Wn").unwrap();
+        let other = OrderId::parse("other").unwrap();
+
+        let history = RotationHistory::open(&path);
+        history.record(RotationRecord::new(&order, "manual", None, &Ok(json!({}))));
+        history.record(RotationRecord::new(&other, "interval", Some("rot_1"), &Ok(json!({}))));
+        history.record(RotationRecord::new(&order, "interval", Some("rot_2"), &Err("API error".to_string())));
+        drop(history);
+
+        let history = RotationHistory::open(&path);
+        let records = history.query("kQx7p3Wn", 10);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].trigger, "manual");
+        assert!(!records[1].ok);
+        assert_eq!(history.query("kQx7p3Wn", 1)[0].schedule_id.as_deref(), Some("rot_2"));
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connection_trigger_rotates_and_records() {
+        let scheduler = Scheduler::new(RotationHistory::in_memory());
+        let counter = Arc::new(AtomicU64::new(0));
+        let count = counter.clone();
+        let connections: ConnectionCounter = Arc::new(move |_| count.load(Ordering::Relaxed));
+
+        let spec = ScheduleSpec {
+            interval: None,
+            every_connections: Some(3),
+            ..spec()
+        };
+        // Nothing listens on the discard port, so the rotation fails fast
+        let client = ProxyBaseClient::new("http://127.0.0.1:9");
+        let order = OrderId::parse("kQx7p3Wn").unwrap();
+        let schedule = scheduler.start(client, "pk_test", order, spec, connections).unwrap();
+
+        counter.store(3, Ordering::Relaxed);
+        for _ in 0..50 {
+            if schedule.snapshot()["consecutive_failures"] == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let records = scheduler.history().query("kQx7p3Wn", 10);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].trigger, "connections");
+        assert!(!records[0].ok);
+
+        let cancelled = scheduler.cancel(|s| s.id == schedule.id, "test");
+        assert_eq!(cancelled.len(), 1);
+        assert!(!schedule.is_active());
+        assert!(scheduler.list().is_empty());
+    }
+}
@@ -0,0 +1,512 @@
+//! SOCKS5 (RFC 1928) with username/password authentication (RFC 1929).
+//!
+//! The client steps are exposed individually so callers can time and report
+//! each stage; [`connect`] runs them all. The server side only implements
+//! what the local gateway needs: no-auth negotiation and CONNECT.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const VERSION: u8 = 0x05;
+/// Version of the RFC 1929 sub-negotiation.
+pub const AUTH_VERSION: u8 = 0x01;
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USER_PASS: u8 = 0x02;
+pub const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+pub const CMD_CONNECT: u8 = 0x01;
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Handshake stage at which a failure occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Greeting,
+    Auth,
+    Request,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Connect => "connect",
+            Stage::Greeting => "greeting",
+            Stage::Auth => "auth",
+            Stage::Request => "request",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Socks5Error {
+    pub stage: Stage,
+    pub message: String,
+}
+
+impl fmt::Display for Socks5Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SOCKS5 {} failed: {}", self.stage.as_str(), self.message)
+    }
+}
+
+fn fail(stage: Stage, message: impl Into<String>) -> Socks5Error {
+    Socks5Error {
+        stage,
+        message: message.into(),
+    }
+}
+
+/// Send the method negotiation and return the method chosen by the server.
+pub async fn greet<S>(stream: &mut S, with_auth: bool) -> Result<u8, Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let offer: &[u8] = if with_auth {
+        &[VERSION, 1, METHOD_USER_PASS]
+    } else {
+        &[VERSION, 1, METHOD_NO_AUTH]
+    };
+    let io = |e: std::io::Error| fail(Stage::Greeting, e.to_string());
+    stream.write_all(offer).await.map_err(io)?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.map_err(io)?;
+    if reply[0] != VERSION {
+        return Err(fail(
+            Stage::Greeting,
+            format!("server is not speaking SOCKS5 (version byte {:#04x})", reply[0]),
+        ));
+    }
+    if reply[1] == METHOD_NONE_ACCEPTABLE {
+        return Err(fail(Stage::Greeting, "server accepted none of the offered auth methods"));
+    }
+    Ok(reply[1])
+}
+
+/// RFC 1929 username/password sub-negotiation.
+pub async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> Result<(), Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if username.len() > 255 || password.len() > 255 {
+        return Err(fail(Stage::Auth, "username and password must be at most 255 bytes"));
+    }
+    let mut msg = vec![AUTH_VERSION, username.len() as u8];
+    msg.extend_from_slice(username.as_bytes());
+    msg.push(password.len() as u8);
+    msg.extend_from_slice(password.as_bytes());
+
+    let io = |e: std::io::Error| fail(Stage::Auth, e.to_string());
+    stream.write_all(&msg).await.map_err(io)?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.map_err(io)?;
+    if reply[0] != AUTH_VERSION {
+        return Err(fail(Stage::Auth, format!("unexpected auth reply version {:#04x}", reply[0])));
+    }
+    if reply[1] != 0x00 {
+        return Err(fail(Stage::Auth, "username/password rejected by the proxy"));
+    }
+    Ok(())
+}
+
+/// Issue a CONNECT to `host:port`; the host is sent as a domain name unless
+/// it is an IP literal, so resolution happens on the proxy side.
+pub async fn request_connect<S>(stream: &mut S, host: &str, port: u16) -> Result<(), Socks5Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut msg = vec![VERSION, CMD_CONNECT, 0x00];
+    msg.extend(encode_addr(host).map_err(|e| fail(Stage::Request, e))?);
+    msg.extend_from_slice(&port.to_be_bytes());
+
+    let io = |e: std::io::Error| fail(Stage::Request, e.to_string());
+    stream.write_all(&msg).await.map_err(io)?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await.map_err(io)?;
+    if head[0] != VERSION {
+        return Err(fail(Stage::Request, format!("unexpected reply version {:#04x}", head[0])));
+    }
+    if head[1] != 0x00 {
+        return Err(fail(Stage::Request, reply_message(head[1])));
+    }
+    // Skip the bound address
+    let addr_len = match head[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await.map_err(io)?;
+            len[0] as usize
+        }
+        other => return Err(fail(Stage::Request, format!("unknown address type {:#04x}", other))),
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut rest).await.map_err(io)?;
+    Ok(())
+}
+
+/// Connect to `proxy` and open a tunnel to `host:port`, giving up once the
+/// whole handshake has taken longer than `timeout`.
+pub async fn connect(
+    proxy: &str,
+    credentials: Option<(&str, &str)>,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, Socks5Error> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut stream = within(deadline, timeout, Stage::Connect, async {
+        TcpStream::connect(proxy)
+            .await
+            .map_err(|e| fail(Stage::Connect, format!("{}: {}", proxy, e)))
+    })
+    .await?;
+    match (within(deadline, timeout, Stage::Greeting, greet(&mut stream, credentials.is_some())).await?, credentials) {
+        (METHOD_USER_PASS, Some((user, pass))) => {
+            within(deadline, timeout, Stage::Auth, authenticate(&mut stream, user, pass)).await?
+        }
+        (METHOD_NO_AUTH, _) => {}
+        (method, _) => {
+            return Err(fail(
+                Stage::Greeting,
+                format!("server chose unsupported auth method {:#04x}", method),
+            ))
+        }
+    }
+    within(deadline, timeout, Stage::Request, request_connect(&mut stream, host, port)).await?;
+    Ok(stream)
+}
+
+/// Run one handshake step, failing it at `stage` once `deadline` passes.
+async fn within<T>(
+    deadline: tokio::time::Instant,
+    timeout: Duration,
+    stage: Stage,
+    fut: impl std::future::Future<Output = Result<T, Socks5Error>>,
+) -> Result<T, Socks5Error> {
+    tokio::time::timeout_at(deadline, fut)
+        .await
+        .unwrap_or_else(|_| Err(fail(stage, format!("timed out after {:?}", timeout))))
+}
+
+/// Server side: negotiate "no authentication" with a client.
+pub async fn accept_no_auth<S>(stream: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "client does not offer no-auth",
+        ));
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await
+}
+
+/// Server side: read a request and return its destination. Anything other
+/// than CONNECT is answered with an error reply.
+pub async fn read_connect_request<S>(stream: &mut S) -> io::Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        stream.write_all(&encode_reply(REPLY_GENERAL_FAILURE, None)).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected request version 0x{:02x}", head[0]),
+        ));
+    }
+    if head[1] != CMD_CONNECT {
+        stream.write_all(&encode_reply(REPLY_COMMAND_NOT_SUPPORTED, None)).await?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "only CONNECT is supported"));
+    }
+
+    let host = match head[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "destination is not UTF-8"))?
+        }
+        _ => {
+            stream.write_all(&encode_reply(REPLY_ADDRESS_NOT_SUPPORTED, None)).await?;
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unknown address type"));
+        }
+    };
+
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+/// A reply with the given code and bound address.
+pub fn encode_reply(code: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut msg = vec![VERSION, code, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&ip.octets());
+        }
+    }
+    msg.extend_from_slice(&bound.port().to_be_bytes());
+    msg
+}
+
+/// Encode a destination as ATYP + address bytes.
+pub fn encode_addr(host: &str) -> Result<Vec<u8>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok(match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[ATYP_IPV4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[ATYP_IPV6][..], &ip.octets()].concat(),
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(format!("invalid destination host '{}'", host));
+            }
+            [&[ATYP_DOMAIN, host.len() as u8][..], host.as_bytes()].concat()
+        }
+    })
+}
+
+/// Human-readable text for a SOCKS5 reply code.
+pub fn reply_message(code: u8) -> String {
+    match code {
+        0x01 => "general SOCKS server failure".to_string(),
+        0x02 => "connection not allowed by ruleset".to_string(),
+        0x03 => "network unreachable".to_string(),
+        0x04 => "host unreachable".to_string(),
+        0x05 => "connection refused by destination".to_string(),
+        0x06 => "TTL expired".to_string(),
+        0x07 => "command not supported".to_string(),
+        0x08 => "address type not supported".to_string(),
+        other => format!("unknown reply code {:#04x}", other),
+    }
+}
+
+#[cfg(test)]
+pub mod test_server {
+    //! Minimal authenticating SOCKS5 server standing in for the ProxyBase
+    //! proxy in tests. Only CONNECT is supported.
+
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Start a stand-in proxy on an ephemeral port and return its address.
+    pub async fn spawn(username: &'static str, password: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, username, password));
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut stream: TcpStream, username: &str, password: &str) -> std::io::Result<()> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await?;
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_USER_PASS) {
+            return stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await;
+        }
+        stream.write_all(&[VERSION, METHOD_USER_PASS]).await?;
+
+        let mut ver_ulen = [0u8; 2];
+        stream.read_exact(&mut ver_ulen).await?;
+        let mut user = vec![0u8; ver_ulen[1] as usize];
+        stream.read_exact(&mut user).await?;
+        let mut plen = [0u8; 1];
+        stream.read_exact(&mut plen).await?;
+        let mut pass = vec![0u8; plen[0] as usize];
+        stream.read_exact(&mut pass).await?;
+        if user != username.as_bytes() || pass != password.as_bytes() {
+            return stream.write_all(&[0x01, 0x01]).await;
+        }
+        stream.write_all(&[0x01, 0x00]).await?;
+
+        let (host, port) = read_connect_request(&mut stream).await?;
+        let mut upstream = match TcpStream::connect((host.as_str(), port)).await {
+            Ok(s) => s,
+            Err(_) => return stream.write_all(&encode_reply(0x05, None)).await,
+        };
+        stream.write_all(&encode_reply(REPLY_SUCCEEDED, upstream.local_addr().ok())).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A server that answers every read with `replies`, in turn, then
+    /// stays silent.
+    async fn scripted_server(replies: &'static [&'static [u8]]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 512];
+                    for reply in replies {
+                        if s.read(&mut buf).await.unwrap_or(0) == 0 || s.write_all(reply).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = s.read(&mut buf).await;
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = s.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_encode_addr() {
+        assert_eq!(encode_addr("1.2.3.4").unwrap(), vec![ATYP_IPV4, 1, 2, 3, 4]);
+        assert_eq!(encode_addr("[::1]").unwrap()[0], ATYP_IPV6);
+        assert_eq!(encode_addr("ab.c").unwrap(), vec![ATYP_DOMAIN, 4, b'a', b'b', b'.', b'c']);
+        assert!(encode_addr("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_stand_in() {
+        let proxy = test_server::spawn("user", "p@ss").await;
+        let target = echo_server().await;
+
+        let mut stream = connect(
+            &proxy.to_string(),
+            Some(("user", "p@ss")),
+            &target.ip().to_string(),
+            target.port(),
+            TIMEOUT,
+        )
+        .await
+        .unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_failure_stages() {
+        let proxy = test_server::spawn("user", "right").await;
+        let target = echo_server().await;
+
+        let err = connect(&proxy.to_string(), Some(("user", "wrong")), "127.0.0.1", target.port(), TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(err.stage, Stage::Auth);
+
+        let err = connect(&proxy.to_string(), None, "127.0.0.1", target.port(), TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(err.stage, Stage::Greeting);
+
+        let err = connect("127.0.0.1:1", None, "127.0.0.1", target.port(), TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(err.stage, Stage::Connect);
+
+        // Nothing listens on port 1
+        let err = connect(&proxy.to_string(), Some(("user", "right")), "127.0.0.1", 1, TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(err.stage, Stage::Request);
+        assert!(err.message.contains("refused"));
+    }
+
+    #[tokio::test]
+    async fn test_reply_versions_are_checked() {
+        let creds = Some(("user", "pass"));
+        // An auth reply must be sub-negotiation version 1
+        let proxy = scripted_server(&[&[VERSION, METHOD_USER_PASS], &[0x05, 0x00]]).await;
+        let err = connect(&proxy.to_string(), creds, "example.com", 80, TIMEOUT).await.unwrap_err();
+        assert_eq!((err.stage, err.message.as_str()), (Stage::Auth, "unexpected auth reply version 0x05"));
+
+        // A CONNECT reply must be SOCKS5
+        let proxy = scripted_server(&[&[VERSION, METHOD_USER_PASS], &[AUTH_VERSION, 0x00], &[0x04, 0x00, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]]).await;
+        let err = connect(&proxy.to_string(), creds, "example.com", 80, TIMEOUT).await.unwrap_err();
+        assert_eq!((err.stage, err.message.as_str()), (Stage::Request, "unexpected reply version 0x04"));
+    }
+
+    #[tokio::test]
+    async fn test_silent_proxy_times_out() {
+        let proxy = scripted_server(&[&[VERSION, METHOD_USER_PASS]]).await;
+        let err = connect(&proxy.to_string(), Some(("user", "pass")), "example.com", 80, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert_eq!(err.stage, Stage::Auth);
+        assert_eq!(err.message, "timed out after 200ms");
+    }
+
+    #[tokio::test]
+    async fn test_request_version_is_checked() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x04, CMD_CONNECT, 0x00, ATYP_IPV4, 1, 2, 3, 4, 0, 80]).await.unwrap();
+        let err = read_connect_request(&mut server).await.unwrap_err();
+        assert_eq!(err.to_string(), "unexpected request version 0x04");
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [VERSION, REPLY_GENERAL_FAILURE]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Request tracing: every JSON-RPC request gets a correlation id, sent to
+//! the backend as `X-Request-Id` and logged with each backend call and
+//! with a summary of the request.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Log target for request summaries and backend calls.
+pub const TARGET: &str = "proxybase_mcp::trace";
+
+tokio::task_local! {
+    static CURRENT: Arc<Request>;
+}
+
+/// A fresh correlation id, `req_` and 16 hex digits.
+pub fn new_id() -> String {
+    format!("req_{:016x}", crate::util::random_u64())
+}
+
+/// The id of the request being handled by this task, if any. Background
+/// tasks (schedules, top-up monitors) run outside any request.
+pub fn current_id() -> Option<String> {
+    CURRENT.try_with(|request| request.id.clone()).ok()
+}
+
+/// Record a backend call against the current request, if any.
+pub fn record(call: BackendCall) {
+    let _ = CURRENT.try_with(|request| request.calls.lock().unwrap().push(call));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendCall {
+    /// Method and path, e.g. `GET /v1/packages`.
+    pub endpoint: String,
+    /// HTTP status, or `None` when no response arrived.
+    pub status: Option<u16>,
+    pub retries: u32,
+    pub duration: Duration,
+}
+
+impl BackendCall {
+    /// `GET /v1/packages 200`, with the retries if there were any.
+    fn summary(&self) -> String {
+        let status = self.status.map_or("failed".to_string(), |s| s.to_string());
+        match self.retries {
+            0 => format!("{} {}", self.endpoint, status),
+            n => format!("{} {} after {} retries", self.endpoint, status, n),
+        }
+    }
+}
+
+/// One JSON-RPC request being handled.
+#[derive(Debug)]
+pub struct Request {
+    pub id: String,
+    started: Instant,
+    calls: Mutex<Vec<BackendCall>>,
+}
+
+impl Request {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { id: new_id(), started: Instant::now(), calls: Mutex::new(Vec::new()) })
+    }
+
+    /// Run `fut` as part of this request.
+    pub async fn scope<F: std::future::Future>(self: &Arc<Self>, fut: F) -> F::Output {
+        CURRENT.scope(self.clone(), fut).await
+    }
+
+    pub fn calls(&self) -> Vec<BackendCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Log the request with its backend calls. The status is that of the
+    /// last backend call.
+    pub fn finish(&self, method: &str, tool: Option<&str>, ok: bool) {
+        let calls = self.calls();
+        let backend: Vec<String> = calls.iter().map(BackendCall::summary).collect();
+        let label = match tool {
+            Some(tool) => format!("{} {}", method, tool),
+            None => method.to_string(),
+        };
+        log::info!(
+            target: TARGET,
+            request_id = self.id.as_str(),
+            method = method,
+            tool = tool.unwrap_or(""),
+            ok = ok,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            backend = backend.join(", ").as_str(),
+            status = calls.last().and_then(|c| c.status).unwrap_or(0),
+            retries = calls.iter().map(|c| c.retries).sum::<u32>();
+            "{} {}", label, if ok { "ok" } else { "failed" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_calls_are_recorded_in_scope_only() {
+        let request = Request::new();
+        assert!(request.id.starts_with("req_") && request.id.len() == 20);
+
+        let call = BackendCall {
+            endpoint: "GET /v1/packages".to_string(),
+            status: Some(200),
+            retries: 2,
+            duration: Duration::from_millis(40),
+        };
+        let id = request
+            .scope(async {
+                record(call.clone());
+                current_id()
+            })
+            .await;
+        assert_eq!(id.as_deref(), Some(request.id.as_str()));
+
+        // Outside the scope nothing is attached
+        record(call.clone());
+        assert_eq!(current_id(), None);
+        assert_eq!(request.calls(), vec![call.clone()]);
+        assert_eq!(call.summary(), "GET /v1/packages 200 after 2 retries");
+    }
+}
@@ -0,0 +1,542 @@
+//! Bandwidth usage history, forecasts and spend per order.
+//!
+//! Every order status fetched through the client is sampled, and orders seen
+//! once are re-polled in the background, so a time series builds up without
+//! agents having to poll. Invoices from `create_order` and `topup_order` are
+//! kept in a local ledger. Both are JSON Lines files in the data directory.
+
+use crate::jsonl::Journal;
+use crate::models::OrderStatus;
+use crate::order_id::OrderId;
+use crate::util::unix_now;
+use crate::ProxyBaseClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(300);
+/// Samples closer together than this are dropped unless the order changed.
+pub const MIN_SAMPLE_GAP: u64 = 60;
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 3600);
+pub const MAX_SAMPLES: usize = 100_000;
+pub const MAX_LEDGER: usize = 10_000;
+
+/// Points in the downsampled `series` of a report.
+const MAX_SERIES_POINTS: usize = 48;
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageSample {
+    pub order_id: String,
+    /// Unix timestamp (seconds) of the sample.
+    pub at: u64,
+    pub status: String,
+    pub used_bytes: u64,
+    #[serde(default)]
+    pub bandwidth_bytes: Option<u64>,
+    #[serde(default)]
+    pub remaining_bytes: Option<u64>,
+}
+
+impl UsageSample {
+    /// `None` when the status carries no byte counts (e.g. unpaid orders).
+    pub fn from_status(order_id: &str, status: &OrderStatus, at: u64) -> Option<Self> {
+        let used = status.used_bytes.or_else(|| {
+            let total = status.bandwidth_bytes?;
+            Some(total.saturating_sub(status.remaining_bytes?))
+        })?;
+        Some(Self {
+            order_id: order_id.to_string(),
+            at,
+            status: status.status.clone(),
+            used_bytes: used,
+            bandwidth_bytes: status.bandwidth_bytes,
+            remaining_bytes: status
+                .remaining_bytes
+                .or_else(|| status.bandwidth_bytes.map(|t| t.saturating_sub(used))),
+        })
+    }
+}
+
+/// An invoice created through this server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub order_id: String,
+    pub at: u64,
+    /// `order` or `topup`.
+    pub kind: String,
+    #[serde(default)]
+    pub package_id: Option<String>,
+    #[serde(default)]
+    pub price_usd: Option<f64>,
+    #[serde(default)]
+    pub pay_currency: Option<String>,
+    #[serde(default)]
+    pub payment_id: Option<String>,
+}
+
+impl LedgerEntry {
+    /// Build an entry from a `create_order` / `topup_order` response. The
+    /// order id falls back to `order_id` when the response lacks one.
+    pub fn from_invoice(kind: &str, order_id: Option<&str>, package_id: &str, invoice: &Value) -> Option<Self> {
+        let field = |k: &str| match invoice.get(k) {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        };
+        Some(Self {
+            order_id: field("order_id").or(order_id.map(|s| s.to_string()))?,
+            at: unix_now(),
+            kind: kind.to_string(),
+            package_id: field("package_id").or(Some(package_id.to_string())),
+            price_usd: invoice.get("price_usd").and_then(|v| v.as_f64()),
+            pay_currency: field("pay_currency"),
+            payment_id: field("payment_id"),
+        })
+    }
+}
+
+pub struct UsageTracker {
+    samples: Journal<UsageSample>,
+    ledger: Journal<LedgerEntry>,
+    /// Orders to re-poll, with the API key they were seen with. Kept in
+    /// memory only, so keys never reach the disk.
+    tracked: Mutex<HashMap<String, String>>,
+    sampler_started: AtomicBool,
+}
+
+impl UsageTracker {
+    pub fn in_memory() -> Self {
+        Self {
+            samples: Journal::in_memory(MAX_SAMPLES),
+            ledger: Journal::in_memory(MAX_LEDGER),
+            tracked: Mutex::new(HashMap::new()),
+            sampler_started: AtomicBool::new(false),
+        }
+    }
+
+    /// Open (or create) `usage.jsonl` and `ledger.jsonl` in `dir`.
+    pub fn open(dir: &Path) -> Self {
+        Self {
+            samples: Journal::open(dir.join("usage.jsonl"), MAX_SAMPLES),
+            ledger: Journal::open(dir.join("ledger.jsonl"), MAX_LEDGER),
+            ..Self::in_memory()
+        }
+    }
+
+    /// Track an order and store a sample of its status.
+    pub fn observe(&self, api_key: &str, order_id: &OrderId, status: &Value) {
+        self.tracked
+            .lock()
+            .unwrap()
+            .insert(order_id.to_string(), api_key.to_string());
+
+        let Ok(status) = OrderStatus::from_value(status) else {
+            return;
+        };
+        let Some(sample) = UsageSample::from_status(order_id.as_str(), &status, unix_now()) else {
+            return;
+        };
+
+        // Frequent polling adds nothing unless the order itself changed
+        let redundant = self
+            .samples
+            .records()
+            .iter()
+            .rev()
+            .find(|s| s.order_id == sample.order_id)
+            .is_some_and(|last| {
+                sample.at.saturating_sub(last.at) < MIN_SAMPLE_GAP
+                    && last.status == sample.status
+                    && last.bandwidth_bytes == sample.bandwidth_bytes
+            });
+        if !redundant {
+            self.samples.push(sample);
+        }
+    }
+
+    pub fn record_invoice(&self, entry: LedgerEntry) {
+        self.ledger.push(entry);
+    }
+
+    /// Samples of an order, oldest first.
+    pub fn samples(&self, order_id: &str) -> Vec<UsageSample> {
+        self.samples
+            .records()
+            .iter()
+            .filter(|s| s.order_id == order_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn ledger(&self, order_id: &str) -> Vec<LedgerEntry> {
+        self.ledger
+            .records()
+            .iter()
+            .filter(|e| e.order_id == order_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Orders with samples or invoices on record, in the order they were
+    /// first seen. The backend cannot list an account's orders, so this is
+    /// what `orders list` shows.
+    pub fn order_ids(&self) -> Vec<String> {
+        let mut seen: Vec<(u64, String)> = self
+            .ledger
+            .records()
+            .iter()
+            .map(|e| (e.at, e.order_id.clone()))
+            .chain(self.samples.records().iter().map(|s| (s.at, s.order_id.clone())))
+            .collect();
+        seen.sort();
+        let mut ids: Vec<String> = Vec::new();
+        for (_, id) in seen {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    /// Start polling tracked orders every `interval`, once. Samples are
+    /// stored by the client itself; orders the backend no longer knows or
+    /// lets this key read are dropped, while rate limits and server errors
+    /// are retried on the next round.
+    pub fn ensure_sampler(self: &Arc<Self>, client: ProxyBaseClient, interval: Duration) {
+        if interval.is_zero() || self.sampler_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let tracker = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(tracker) = tracker.upgrade() else {
+                    return;
+                };
+                let tracked: Vec<(String, String)> = tracker
+                    .tracked
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+
+                for (order_id, api_key) in tracked {
+                    let Ok(id) = OrderId::parse(&order_id) else {
+                        continue;
+                    };
+                    if let Err(e) = client.check_order_status(&api_key, &id).await {
+                        log::warn!("Usage sampling of {} failed: {}", order_id, e);
+                        if matches!(crate::api_error_status(&e), Some(401 | 403 | 404 | 410)) {
+                            tracker.tracked.lock().unwrap().remove(&order_id);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Catalog facts about a package: its size in bytes and price in USD.
+pub type PackageInfo = (Option<u64>, Option<f64>);
+
+/// Consumption rate, projected exhaustion and spend of an order.
+///
+/// The rate covers samples within `window` before `now`. `package` looks up
+/// packages in the catalog; `order_package` is the package the order was
+/// created with, used to estimate the purchase price of orders not created
+/// through this server.
+pub fn report(
+    order_id: &str,
+    samples: &[UsageSample],
+    ledger: &[LedgerEntry],
+    now: u64,
+    window: Duration,
+    order_package: Option<&str>,
+    package: impl Fn(&str) -> PackageInfo,
+) -> Value {
+    let Some(latest) = samples.last() else {
+        return json!({
+            "order_id": order_id,
+            "samples": 0,
+            "note": "No usage recorded yet; the order has no bandwidth figures until it is paid"
+        });
+    };
+
+    let since = now.saturating_sub(window.as_secs());
+    let recent: Vec<&UsageSample> = samples.iter().filter(|s| s.at >= since).collect();
+    let rate = consumption_rate(&recent);
+
+    let usage_percentage = latest
+        .bandwidth_bytes
+        .filter(|t| *t > 0)
+        .map(|t| latest.used_bytes as f64 * 100.0 / t as f64);
+
+    let forecast = match (rate, latest.remaining_bytes) {
+        (_, Some(0)) => json!({"exhausts_in_secs": 0, "exhausts_at": latest.at}),
+        (Some((per_sec, _)), Some(remaining)) if per_sec > 0.0 => {
+            let secs = (remaining as f64 / per_sec).round() as u64;
+            json!({"exhausts_in_secs": secs, "exhausts_at": latest.at + secs})
+        }
+        _ => Value::Null,
+    };
+
+    let mut result = json!({
+        "order_id": order_id,
+        "status": latest.status,
+        "sampled_at": latest.at,
+        "used_bytes": latest.used_bytes,
+        "bandwidth_bytes": latest.bandwidth_bytes,
+        "remaining_bytes": latest.remaining_bytes,
+        "usage_percentage": usage_percentage,
+        "window_secs": window.as_secs(),
+        "samples": recent.len(),
+        "rate": rate.map(|(per_sec, over)| json!({
+            "bytes_per_hour": (per_sec * 3600.0).round() as u64,
+            "gb_per_day": per_sec * 86400.0 / GIB,
+            "measured_over_secs": over
+        })),
+        "forecast": forecast,
+        "cost": cost(latest, ledger, order_package, &package),
+        "series": series(&recent)
+    });
+    if rate.is_none() {
+        result["note"] = json!("Not enough samples in the window to measure a rate yet");
+    }
+    result
+}
+
+/// Bytes per second over the samples, counting only increases so a counter
+/// reset never shows up as negative usage. Also returns the time covered.
+fn consumption_rate(samples: &[&UsageSample]) -> Option<(f64, u64)> {
+    let (first, last) = (samples.first()?, samples.last()?);
+    let elapsed = last.at.saturating_sub(first.at);
+    if elapsed < MIN_SAMPLE_GAP {
+        return None;
+    }
+    let consumed: u64 = samples
+        .windows(2)
+        .map(|w| w[1].used_bytes.saturating_sub(w[0].used_bytes))
+        .sum();
+    Some((consumed as f64 / elapsed as f64, elapsed))
+}
+
+/// Spend on the order. Invoices are taken in order and counted as paid while
+/// their packages fit in the order's current bandwidth; the rest are pending.
+fn cost(
+    latest: &UsageSample,
+    ledger: &[LedgerEntry],
+    order_package: Option<&str>,
+    package: &impl Fn(&str) -> PackageInfo,
+) -> Value {
+    let mut entries: Vec<LedgerEntry> = ledger.to_vec();
+    entries.sort_by_key(|e| e.at);
+
+    let mut estimated = false;
+    if !entries.iter().any(|e| e.kind == "order") {
+        if let Some(package_id) = order_package {
+            entries.insert(0, LedgerEntry {
+                order_id: latest.order_id.clone(),
+                at: 0,
+                kind: "order".to_string(),
+                package_id: Some(package_id.to_string()),
+                price_usd: package(package_id).1,
+                pay_currency: None,
+                payment_id: None,
+            });
+            estimated = true;
+        }
+    }
+
+    let bandwidth = latest.bandwidth_bytes.unwrap_or(0);
+    let (mut credited, mut spent, mut pending) = (0u64, 0.0, 0.0);
+    let mut priced = !entries.is_empty();
+    for entry in &entries {
+        let (bytes, catalog_price) = entry.package_id.as_deref().map(package).unwrap_or((None, None));
+        let Some(price) = entry.price_usd.or(catalog_price) else {
+            priced = false;
+            continue;
+        };
+        // Packages of unknown size are assumed to be paid
+        let fits = bytes.is_none_or(|b| credited + b <= bandwidth + bandwidth / 100);
+        if fits {
+            credited += bytes.unwrap_or(0);
+            spent += price;
+        } else {
+            pending += price;
+        }
+    }
+
+    if entries.is_empty() {
+        return Value::Null;
+    }
+    let per_gb = |bytes: u64| (bytes > 0 && spent > 0.0).then(|| spent / (bytes as f64 / GIB));
+    json!({
+        "spent_usd": spent,
+        "pending_usd": pending,
+        "complete": priced,
+        "estimated": estimated,
+        "cost_per_gb_used": per_gb(latest.used_bytes),
+        "cost_per_gb_bought": latest.bandwidth_bytes.and_then(per_gb),
+        "invoices": entries
+    })
+}
+
+/// At most `MAX_SERIES_POINTS` evenly spaced samples, always including the
+/// newest.
+fn series(samples: &[&UsageSample]) -> Vec<Value> {
+    let step = samples.len().div_ceil(MAX_SERIES_POINTS).max(1);
+    let mut picked: Vec<&UsageSample> = samples.iter().rev().step_by(step).copied().collect();
+    picked.reverse();
+    picked
+        .iter()
+        .map(|s| json!({"at": s.at, "used_bytes": s.used_bytes}))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1 << 30;
+
+    fn sample(at: u64, used: u64) -> UsageSample {
+        UsageSample {
+            order_id: "kQx7p3Wn".to_string(),
+            at,
+            status: "proxy_active".to_string(),
+            used_bytes: used,
+            bandwidth_bytes: Some(2 * GB),
+            remaining_bytes: Some(2 * GB - used),
+        }
+    }
+
+    fn entry(kind: &str, at: u64, package_id: &str, price: Option<f64>) -> LedgerEntry {
+        LedgerEntry {
+            order_id: "kQx7p3Wn".to_string(),
+            at,
+            kind: kind.to_string(),
+            package_id: Some(package_id.to_string()),
+            price_usd: price,
+            pay_currency: None,
+            payment_id: None,
+        }
+    }
+
+    fn catalog(id: &str) -> PackageInfo {
+        match id {
+            "us_residential_1gb" => (Some(GB), Some(10.0)),
+            _ => (None, None),
+        }
+    }
+
+    #[test]
+    fn test_sample_from_status() {
+        let status = OrderStatus::from_value(&json!({
+            "status": "proxy_active",
+            "bandwidth_bytes": 1000,
+            "remaining_bytes": 400
+        }))
+        .unwrap();
+        let sample = UsageSample::from_status("kQx7p3Wn", &status, 5).unwrap();
+        assert_eq!(sample.used_bytes, 600);
+
+        let pending = OrderStatus::from_value(&json!({"status": "payment_pending"})).unwrap();
+        assert!(UsageSample::from_status("kQx7p3Wn", &pending, 5).is_none());
+    }
+
+    #[test]
+    fn test_rate_and_forecast() {
+        // 100 MiB per hour, 1 GiB used of 2
+        let mib = 1 << 20;
+        let samples: Vec<_> = (0..=10).map(|h| sample(h * 3600, GB - (10 - h) * 100 * mib)).collect();
+        let now = 10 * 3600;
+
+        let r = report("kQx7p3Wn", &samples, &[], now, DEFAULT_WINDOW, None, catalog);
+        assert_eq!(r["rate"]["bytes_per_hour"], 100 * mib);
+        assert_eq!(r["usage_percentage"], 50.0);
+        // 1024 MiB left at 100 MiB/h
+        assert_eq!(r["forecast"]["exhausts_in_secs"], 36864);
+        assert_eq!(r["cost"], Value::Null);
+
+        // A short window only sees the newest sample
+        let r = report("kQx7p3Wn", &samples, &[], now, Duration::from_secs(60), None, catalog);
+        assert_eq!(r["rate"], Value::Null);
+        assert_eq!(r["forecast"], Value::Null);
+        assert!(r["note"].as_str().unwrap().contains("Not enough samples"));
+    }
+
+    #[test]
+    fn test_cost_counts_only_credited_invoices() {
+        let mut last = sample(3600, GB / 2);
+        last.bandwidth_bytes = Some(GB);
+        last.remaining_bytes = Some(GB / 2);
+        let samples = vec![sample(0, 0), last];
+
+        // Ordered and topped up, but the top-up is not paid yet
+        let ledger = vec![
+            entry("order", 10, "us_residential_1gb", Some(10.0)),
+            entry("topup", 20, "us_residential_1gb", None),
+        ];
+        let r = report("kQx7p3Wn", &samples, &ledger, 3600, DEFAULT_WINDOW, None, catalog);
+        assert_eq!(r["cost"]["spent_usd"], 10.0);
+        assert_eq!(r["cost"]["pending_usd"], 10.0);
+        assert_eq!(r["cost"]["cost_per_gb_used"], 20.0);
+        assert_eq!(r["cost"]["cost_per_gb_bought"], 10.0);
+        assert_eq!(r["cost"]["estimated"], false);
+
+        // Orders created elsewhere are priced from the catalog
+        let r = report("kQx7p3Wn", &samples, &[], 3600, DEFAULT_WINDOW, Some("us_residential_1gb"), catalog);
+        assert_eq!(r["cost"]["spent_usd"], 10.0);
+        assert_eq!(r["cost"]["estimated"], true);
+    }
+
+    #[test]
+    fn test_observe_drops_redundant_samples() {
+        let tracker = UsageTracker::in_memory();
+        let order = OrderId::parse("kQx7p3Wn").unwrap();
+        let status = json!({"status": "proxy_active", "bandwidth_bytes": 1000, "used_bytes": 10});
+        tracker.observe("pk_test", &order, &status);
+        tracker.observe("pk_test", &order, &json!({"status": "proxy_active", "bandwidth_bytes": 1000, "used_bytes": 20}));
+        assert_eq!(tracker.samples("kQx7p3Wn").len(), 1);
+
+        // A top-up changes the bandwidth and is always recorded
+        tracker.observe("pk_test", &order, &json!({"status": "proxy_active", "bandwidth_bytes": 2000, "used_bytes": 20}));
+        assert_eq!(tracker.samples("kQx7p3Wn").len(), 2);
+        assert!(tracker.tracked.lock().unwrap().contains_key("kQx7p3Wn"));
+    }
+
+    #[tokio::test]
+    async fn test_sampler_keeps_rate_limited_orders() {
+        let base = crate::http1::test_support::spawn_backend(|_, target, _| match target.contains("Zp4rT8Lm") {
+            true => (404, json!({"error": "order not found"})),
+            false => (429, json!({"error": "slow down"})),
+        })
+        .await;
+        let tracker = Arc::new(UsageTracker::in_memory());
+        let active = json!({"status": "proxy_active", "bandwidth_bytes": 1000, "used_bytes": 10});
+        for id in ["kQx7p3Wn", "Zp4rT8Lm"] {
+            tracker.observe("pk_test", &OrderId::parse(id).unwrap(), &active);
+        }
+
+        tracker.ensure_sampler(ProxyBaseClient::new(&base), Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let tracked = tracker.tracked.lock().unwrap();
+        assert!(tracked.contains_key("kQx7p3Wn"));
+        assert!(!tracked.contains_key("Zp4rT8Lm"));
+    }
+
+    #[test]
+    fn test_series_is_downsampled() {
+        let samples: Vec<_> = (0..200).map(|i| sample(i * 60, i)).collect();
+        let refs: Vec<&UsageSample> = samples.iter().collect();
+        let points = series(&refs);
+        assert!(points.len() <= MAX_SERIES_POINTS);
+        assert_eq!(points.last().unwrap()["used_bytes"], 199);
+    }
+}
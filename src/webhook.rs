@@ -0,0 +1,344 @@
+//! Embedded receiver for order status callbacks.
+//!
+//! When enabled, the server listens for the backend's webhook POSTs (payment
+//! confirmed, bandwidth 80%/95%, exhausted), verifies the HMAC signature if a
+//! secret is configured, records each event and forwards it to the MCP client
+//! as a log notification.
+
+use crate::http1;
+use crate::inbox::{EventInbox, OrderEvent};
+use crate::notify::Notifier;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+/// Path the backend is expected to POST to.
+pub const WEBHOOK_PATH: &str = "/webhook";
+/// Header carrying `sha256=<hex HMAC of the raw body>`.
+pub const SIGNATURE_HEADER: &str = "X-ProxyBase-Signature";
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Time a connection gets to send its request and read the reply.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections handled at once; further ones wait to be accepted.
+const MAX_CONNECTIONS: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct WebhookConfig {
+    /// Address for the embedded listener; disabled when `None`.
+    pub listen: Option<SocketAddr>,
+    /// Externally reachable URL that routes to the listener, used to fill in
+    /// `callback_url` on new orders.
+    pub public_url: Option<String>,
+    /// Shared secret for HMAC-SHA256 signature verification.
+    pub secret: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Refuse to listen beyond loopback without a secret, where anyone who
+    /// can reach the port could inject order events.
+    pub fn check(&self) -> Result<(), String> {
+        match self.listen {
+            Some(addr) if self.secret.is_none() && !addr.ip().is_loopback() => Err(format!(
+                "Webhook listener on {} needs a secret (PROXYBASE_WEBHOOK_SECRET); without one it may only listen on a loopback address",
+                addr
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the listener is configured and allowed to start.
+    pub fn enabled(&self) -> bool {
+        self.listen.is_some() && self.check().is_ok()
+    }
+
+    /// The `callback_url` to attach to new orders, if any.
+    pub fn callback_url(&self) -> Option<String> {
+        if !self.enabled() {
+            return None;
+        }
+        let base = self.public_url.as_deref()?.trim_end_matches('/');
+        if base.ends_with(WEBHOOK_PATH) {
+            Some(base.to_string())
+        } else {
+            Some(format!("{}{}", base, WEBHOOK_PATH))
+        }
+    }
+}
+
+pub struct WebhookReceiver {
+    secret: Option<String>,
+    inbox: Arc<EventInbox>,
+    notifier: Notifier,
+}
+
+impl WebhookReceiver {
+    pub fn new(secret: Option<String>, inbox: Arc<EventInbox>, notifier: Notifier) -> Self {
+        Self {
+            secret,
+            inbox,
+            notifier,
+        }
+    }
+
+    /// Accept connections until the listener fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        if self.secret.is_none() {
+            log::warn!("Webhook listener has no secret configured; callbacks are not authenticated");
+        }
+        let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            let Ok(slot) = slots.clone().acquire_owned().await else {
+                return;
+            };
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let receiver = self.clone();
+                    tokio::spawn(async move {
+                        let result = tokio::time::timeout(READ_TIMEOUT, receiver.handle_connection(stream)).await;
+                        match result {
+                            Ok(Err(e)) => log::debug!("Webhook connection from {} failed: {}", peer, e),
+                            Err(_) => log::debug!("Webhook connection from {} timed out", peer),
+                            Ok(Ok(())) => {}
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(e) => {
+                    log::error!("Webhook listener stopped: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle_connection(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let head = http1::read_head(&mut reader).await?;
+
+        let path = head.target.split('?').next().unwrap_or_default();
+        if path != WEBHOOK_PATH {
+            return respond(&mut reader, 404, "Not Found", json!({"error": "not found"})).await;
+        }
+        if head.method != "POST" {
+            return respond(&mut reader, 405, "Method Not Allowed", json!({"error": "use POST"})).await;
+        }
+
+        let body = match http1::read_body(&mut reader, &head, MAX_BODY_BYTES).await {
+            Ok(body) => body,
+            Err(e) => {
+                return respond(&mut reader, 400, "Bad Request", json!({"error": e.to_string()})).await;
+            }
+        };
+
+        let (status, reason, reply) = self.accept(head.header(SIGNATURE_HEADER), &body);
+        respond(&mut reader, status, reason, reply).await
+    }
+
+    /// Verify and record one callback body, returning the HTTP reply.
+    fn accept(&self, signature: Option<&str>, body: &[u8]) -> (u16, &'static str, Value) {
+        if let Some(secret) = &self.secret {
+            if !signature.is_some_and(|sig| verify_signature(secret, body, sig)) {
+                log::warn!("Rejected webhook with missing or invalid signature");
+                return (401, "Unauthorized", json!({"error": "invalid signature"}));
+            }
+        }
+
+        let event = match serde_json::from_slice(body)
+            .map_err(|e| e.to_string())
+            .and_then(OrderEvent::from_payload)
+        {
+            Ok(event) => event,
+            Err(e) => return (400, "Bad Request", json!({"error": e})),
+        };
+        // The signature covers only the body, so a captured callback could be
+        // replayed; its id is what lets the inbox drop the copy
+        if self.secret.is_some() && !event.supplied_id {
+            log::warn!("Rejected signed webhook without an event id");
+            return (400, "Bad Request", json!({"error": "signed callbacks must carry an event_id or id"}));
+        }
+
+        // Acknowledge redeliveries without recording or forwarding them again
+        let Some(event) = self.inbox.record(event) else {
+            log::debug!("Ignoring duplicate webhook event");
+            return (200, "OK", json!({"ok": true, "duplicate": true}));
+        };
+
+        log::info!(
+            "Webhook event {} for order {}",
+            event.event,
+            event.order_id.as_deref().unwrap_or("?")
+        );
+        self.notifier.log(
+            "info",
+            "proxybase.webhook",
+            serde_json::to_value(&event).unwrap_or_default(),
+        );
+
+        (200, "OK", json!({"ok": true}))
+    }
+}
+
+async fn respond(
+    reader: &mut BufReader<TcpStream>,
+    status: u16,
+    reason: &str,
+    body: Value,
+) -> std::io::Result<()> {
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    http1::write_response(reader.get_mut(), status, reason, "application/json", &body).await
+}
+
+/// Check a `sha256=<hex>` (or bare hex) HMAC-SHA256 signature of `body`.
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let hex = signature.trim();
+    let hex = hex.strip_prefix("sha256=").unwrap_or(hex);
+    let Some(expected) = decode_hex(hex) else {
+        return false;
+    };
+
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("sha256={}", hex)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = br#"{"event":"payment_confirmed"}"#;
+        let sig = sign("s3cret", body);
+        assert!(verify_signature("s3cret", body, &sig));
+        assert!(verify_signature("s3cret", body, sig.trim_start_matches("sha256=")));
+        assert!(!verify_signature("other", body, &sig));
+        assert!(!verify_signature("s3cret", b"tampered", &sig));
+        assert!(!verify_signature("s3cret", body, "sha256=zz"));
+    }
+
+    #[test]
+    fn test_callback_url() {
+        let mut cfg = WebhookConfig {
+            listen: None,
+            public_url: Some("https://hooks.example.com/".to_string()),
+            secret: None,
+        };
+        assert_eq!(cfg.callback_url(), None);
+
+        cfg.listen = Some("127.0.0.1:8787".parse().unwrap());
+        assert_eq!(cfg.callback_url().as_deref(), Some("https://hooks.example.com/webhook"));
+    }
+
+    #[test]
+    fn test_unsigned_listener_stays_on_loopback() {
+        let mut cfg = WebhookConfig {
+            listen: Some("127.0.0.1:8787".parse().unwrap()),
+            ..WebhookConfig::default()
+        };
+        assert!(cfg.check().is_ok());
+        cfg.listen = Some("[::1]:8787".parse().unwrap());
+        assert!(cfg.check().is_ok());
+
+        cfg.listen = Some("0.0.0.0:8787".parse().unwrap());
+        assert!(cfg.check().unwrap_err().contains("needs a secret"));
+        cfg.secret = Some("k".to_string());
+        assert!(cfg.check().is_ok());
+    }
+
+    #[test]
+    fn test_accept_records_and_notifies() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let inbox = Arc::new(EventInbox::in_memory());
+        let receiver = WebhookReceiver::new(Some("k".to_string()), inbox.clone(), Notifier::new(tx));
+
+        let body = br#"{"event_id":"evt_1","event":"proxy_active","order_id":"abc"}"#;
+        assert_eq!(receiver.accept(None, body).0, 401);
+        assert_eq!(receiver.accept(Some(&sign("k", body)), body).0, 200);
+
+        assert_eq!(inbox.query(Some("abc"), None, 10).len(), 1);
+        let note: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(note["method"], "notifications/message");
+        assert_eq!(note["params"]["data"]["event"], "proxy_active");
+
+        // Redelivery is acknowledged but neither stored nor forwarded again
+        let (status, _, reply) = receiver.accept(Some(&sign("k", body)), body);
+        assert_eq!(status, 200);
+        assert_eq!(reply["duplicate"], true);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_signed_callbacks_need_an_event_id() {
+        let inbox = Arc::new(EventInbox::in_memory());
+        let receiver = WebhookReceiver::new(Some("k".to_string()), inbox.clone(), Notifier::default());
+
+        // Without an id a replayed copy could not be told from a new event
+        let body = br#"{"event":"bandwidth_80","order_id":"abc"}"#;
+        let (status, _, reply) = receiver.accept(Some(&sign("k", body)), body);
+        assert_eq!(status, 400);
+        assert!(reply["error"].as_str().unwrap().contains("event_id"));
+        assert!(inbox.query(None, None, 10).is_empty());
+
+        // Unsigned loopback listeners still take events without ids
+        let receiver = WebhookReceiver::new(None, inbox.clone(), Notifier::default());
+        assert_eq!(receiver.accept(None, body).0, 200);
+        assert_eq!(receiver.accept(None, body).0, 200);
+        assert_eq!(inbox.query(None, None, 10).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_serve_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbox = Arc::new(EventInbox::in_memory());
+        let receiver = Arc::new(WebhookReceiver::new(None, inbox.clone(), Notifier::default()));
+        tokio::spawn(receiver.serve(listener));
+
+        let body = r#"{"event":"bandwidth_exhausted","order_id":"o1"}"#;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "POST /webhook HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        assert_eq!(inbox.query(Some("o1"), None, 10)[0].event, "bandwidth_exhausted");
+    }
+}